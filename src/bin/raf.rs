@@ -24,10 +24,29 @@ use telegram_raf::persistence::db::connection;
 use telegram_raf::persistence::types::*;
 
 use telegram_raf::telegram::commands::*;
+use telegram_raf::telegram::fairness;
 use telegram_raf::telegram::handlers;
+use telegram_raf::telegram::scheduler;
 
 #[tokio::main]
 async fn main() {
+    // The verification of a draw runs offline: no bot is started
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 1 && args[1] == "verify" {
+        match fairness::verify(&args[2..]) {
+            Ok(winners) => {
+                for (position, winner) in winners.iter().enumerate() {
+                    println!("#{} {winner}", position + 1);
+                }
+            }
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     SimpleLogger::new()
         .with_level(LevelFilter::Info)
         .init()
@@ -36,10 +55,13 @@ async fn main() {
     let pool = connection();
     let token = env::var("TOKEN").expect("Provide the token via TOKEN env var");
     let bot_name = env::var("BOT_NAME").expect("Provide the bot name via BOT_NAME env var");
+    // The bot operator is optional: it's excluded from the contests with this eligibility rule
+    let operator = env::var("OPERATOR")
+        .ok()
+        .and_then(|id| id.parse::<i64>().ok());
 
     // Check for the --broadcast flag
     let mut broadcast = false;
-    let args: Vec<String> = env::args().collect();
     if args.len() > 1 && args[1] == "--broadcast" {
         broadcast = true;
     }
@@ -52,7 +74,7 @@ async fn main() {
     } else {
         client_builder = client_builder
             .set_framework(create_framework!(
-                &bot_name, help, start, register, contest, list, rank
+                &bot_name, help, start, register, contest, list, rank, results
             ))
             .set_allowed_updates(vec![UpdateType::CallbackQuery, UpdateType::Message])
             .add_handler_func(handlers::message)
@@ -65,6 +87,7 @@ async fn main() {
         let mut data = client.data.write();
         data.insert::<DBKey>(pool);
         data.insert::<NameKey>(bot_name);
+        data.insert::<OperatorKey>(operator);
     }
 
     if broadcast {
@@ -79,6 +102,11 @@ async fn main() {
             }
         }
     } else {
+        // The periodic jobs share the API connection and the data of the client
+        tokio::spawn(scheduler::run(Context::new(
+            client.api_client.clone(),
+            client.data.clone(),
+        )));
         loop {
             let ret = client.start().await;
             match ret {
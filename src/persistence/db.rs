@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
 
 /// Database schema definition. Transaction executed every time a new connection
 /// pool is requested (usually, once at the application startup).
@@ -20,10 +21,104 @@ use r2d2_sqlite::SqliteConnectionManager;
 /// `being_managed_channels`, as the name suggests, is the channel that the owner (
 /// hence `channels.registered_by` == owner) is managing.
 ///
-/// NOTE: `being_contacted_users` and `being_managed_channels` are tables required because
-/// there are moments in the flow, where the user should send "complex" messages, but these
-/// "complex" messages are outside the FSM created by the `callback_handler`
-/// (FSM created naturally because all the callbacks invokes the same method).
+/// NOTE: `being_managed_channels` is a table required because there are moments in the
+/// flow, where the user should send "complex" messages, but these "complex" messages are
+/// outside the FSM created by the `callback_handler` (FSM created naturally because all the
+/// callbacks invokes the same method).
+///
+/// `invitation_events` is the history of the status changes of every invitation: invitations
+/// are never deleted, hence the history is the audit trail to use in case of disputes.
+///
+/// `being_managed_contests` is the equivalent of `being_managed_channels` for the actions on a
+/// contest that require the owner to write something (e.g. the reason of a disqualification).
+/// `action` identifies what the owner is answering to, and `arg` its optional argument.
+///
+/// `audit_log` contains every action executed by the owners on the contests participants and
+/// invitations.
+///
+/// `adjustments` is the ledger of the manual credit adjustments: points added to (or removed
+/// from) a participant by the owner, e.g. when the attribution of an invitation failed.
+///
+/// `contest_settings` contains the optional features of every contest, as key-value pairs.
+/// A missing key means the default value.
+///
+/// `attributions` records the decisions of the attribution policy: when several participants
+/// invite the same user in a contest, every invitation involved gets a row with the credit it
+/// has been given. `caused_by` is the invitation that triggered the decision.
+///
+/// `allowlists` contains the users allowed to participate in the contests with an allowlist:
+/// every `entry` is a user ID or a lowercase username (without @), since the allowed users
+/// might not be known to `RaF` yet.
+///
+/// `challenges` are the human-verification challenges sent to the invitees: `solved` is NULL
+/// while waiting for the answer.
+///
+/// `contest_participants` are the users that got a referral link of a contest. When the contest
+/// has a maximum number of participants, the users beyond the limit are `waitlisted` and get
+/// their link once a slot is free, in order of arrival.
+///
+/// `reward_codes` is the stock of codes (gift cards, coupons, ...) uploaded by the owners. Every
+/// contest has several stocks, identified by `pool` (e.g. `milestone:5`): a code is given once,
+/// to the user in `assigned_to`.
+///
+/// `milestones` are the rewards given to the participants that reach a `threshold` of invites,
+/// and `milestone_achievements` the milestones reached by every participant, with the code sent
+/// (if any). Achievements are never removed, even if the milestones change.
+///
+/// `prize_deliveries` tracks the prize codes sent privately to the winners of every tier (the
+/// final position) when a contest finishes, and whether the winner claimed them.
+///
+/// `fulfillments` is the prize-fulfillment record of every winner, from the notification to the
+/// delivery (or the dispute). `reminded_at` is the last time the owner has been reminded of a
+/// prize still pending.
+///
+/// `relay_sessions` are the chats between the owners and the winners, relayed by the bot. The
+/// messages of the winner always reach the owner while the session is `open`, the messages of
+/// the owner reach the winner of the session with the `owner_focus`: an owner chats with one
+/// winner at a time.
+///
+/// `invitee_rewards` contains the welcome message sent to the invitees of a contest when their
+/// invitation qualifies, together with a code from the `invitee` stock of the contest (if any).
+/// `invitee_reward_deliveries` records what every invitee got: an invitee is rewarded once per
+/// channel, whatever the contest.
+///
+/// `point_rules` contains the points model of a contest, when the owner replaces the raw count
+/// of the invitations with it: the points per invite, the bonus for the invitees still members
+/// when the contest finishes, the bonus for the invitees active in the group, and the penalty
+/// for the invitees that left. `invitation_activity` records the invitations whose invitee
+/// wrote in the group while the contest was running.
+///
+/// `draw_seeds` contains the secret seed of the random draws of every contest, generated when
+/// the contest starts, and its commitment (the SHA-256 of the seed) published in the
+/// announcement. The seed is revealed with the results, only if its commitment has been
+/// `published`.
+///
+/// `giveaway_entries` contains the members that pressed Participate on the announcement of a
+/// giveaway.
+///
+/// `activity_messages` contains the messages counted in the activity contests: the digest of
+/// the normalized text makes every message of a user count once.
+///
+/// `contest_channels` contains the channels linked to a contest, besides the channel that owns
+/// it: the invitations to any of them count.
+///
+/// `sponsors` contains the partner chats of a contest: the invitees must join them too, for
+/// their invitation to count.
+///
+/// `contest_templates` are the recurring contests: every instance copies the rules of
+/// `contest`, with its own name, prize and duration. A removed template is not `active`
+/// anymore, but its instances are still finished by the scheduler: `template_instances`
+/// links every instance to its template.
+///
+/// `leaderboards` contains the live leaderboard message of a contest in every channel: it's
+/// edited in place when its chart, stored in `text`, changes, and replaced with the results at
+/// the end.
+///
+/// `results` is the snapshot of the ranking taken when a contest finishes. It's written
+/// once, and it's the official outcome of the contest: it doesn't change even if the
+/// invitations change afterwards. `points` is NULL when the contest ranks by the raw count
+/// of the invitations, `indirect` is the credit of the multi-level referrals, and `adjustment`
+/// the sum of the manual adjustments.
 const SCHEMA: &str = "BEGIN;
 CREATE TABLE IF NOT EXISTS users (
    id   INTEGER PRIMARY KEY NOT NULL,
@@ -69,21 +164,361 @@ CREATE TABLE IF NOT EXISTS being_managed_channels(
   chan INTEGER NOT NULL,
   FOREIGN KEY(chan) REFERENCES channels(id)
 );
-CREATE TABLE IF NOT EXISTS being_contacted_users(
+CREATE TABLE IF NOT EXISTS results(
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  contest INTEGER NOT NULL,
+  rank INTEGER NOT NULL,
+  user INTEGER NOT NULL,
+  invites INTEGER NOT NULL,
+  points REAL NULL,
+  indirect REAL NOT NULL DEFAULT 0,
+  adjustment INTEGER NOT NULL DEFAULT 0,
+  prize TEXT NULL,
+  last_invite TIMESTAMP NOT NULL,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  FOREIGN KEY(user) REFERENCES users(id),
+  UNIQUE(contest, rank),
+  UNIQUE(contest, user)
+);
+CREATE TABLE IF NOT EXISTS invitation_events(
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  invitation INTEGER NOT NULL,
+  status TEXT NOT NULL,
+  reason TEXT NULL,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY(invitation) REFERENCES invitations(id)
+);
+CREATE TABLE IF NOT EXISTS being_managed_contests(
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  owner INTEGER NOT NULL,
+  contest INTEGER NOT NULL,
+  action TEXT NOT NULL,
+  arg TEXT NULL,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  done BOOL NOT NULL DEFAULT FALSE,
+  FOREIGN KEY(owner) REFERENCES users(id),
+  FOREIGN KEY(contest) REFERENCES contests(id)
+);
+CREATE TABLE IF NOT EXISTS disqualified_participants(
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  contest INTEGER NOT NULL,
+  user INTEGER NOT NULL,
+  reason TEXT NOT NULL,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  FOREIGN KEY(user) REFERENCES users(id),
+  UNIQUE(contest, user)
+);
+CREATE TABLE IF NOT EXISTS audit_log(
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  contest INTEGER NOT NULL,
+  actor INTEGER NOT NULL,
+  action TEXT NOT NULL,
+  target INTEGER NULL,
+  reason TEXT NULL,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  FOREIGN KEY(actor) REFERENCES users(id)
+);
+CREATE TABLE IF NOT EXISTS adjustments(
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  contest INTEGER NOT NULL,
+  user INTEGER NOT NULL,
+  points INTEGER NOT NULL,
+  reason TEXT NOT NULL,
+  actor INTEGER NOT NULL,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  FOREIGN KEY(user) REFERENCES users(id),
+  FOREIGN KEY(actor) REFERENCES users(id),
+  CHECK (points <> 0)
+);
+CREATE TABLE IF NOT EXISTS contest_settings(
+  contest INTEGER NOT NULL,
+  key TEXT NOT NULL,
+  value TEXT NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  PRIMARY KEY(contest, key)
+);
+CREATE TABLE IF NOT EXISTS attributions(
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  contest INTEGER NOT NULL,
+  invitee INTEGER NOT NULL,
+  invitation INTEGER NOT NULL,
+  caused_by INTEGER NOT NULL,
+  policy TEXT NOT NULL,
+  credit REAL NOT NULL,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  FOREIGN KEY(invitee) REFERENCES users(id),
+  FOREIGN KEY(invitation) REFERENCES invitations(id),
+  FOREIGN KEY(caused_by) REFERENCES invitations(id)
+);
+CREATE TABLE IF NOT EXISTS allowlists(
+  contest INTEGER NOT NULL,
+  entry TEXT NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  PRIMARY KEY(contest, entry)
+);
+CREATE TABLE IF NOT EXISTS challenges(
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  invitation INTEGER NOT NULL,
+  user INTEGER NOT NULL,
+  kind TEXT NOT NULL,
+  answer TEXT NOT NULL,
+  expires_at TIMESTAMP NOT NULL,
+  solved BOOL NULL,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY(invitation) REFERENCES invitations(id),
+  FOREIGN KEY(user) REFERENCES users(id)
+);
+CREATE TABLE IF NOT EXISTS contest_participants(
+  contest INTEGER NOT NULL,
+  user INTEGER NOT NULL,
+  status TEXT NOT NULL DEFAULT 'active',
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  FOREIGN KEY(user) REFERENCES users(id),
+  PRIMARY KEY(contest, user),
+  CHECK (status IN ('active', 'waitlisted'))
+);
+CREATE TABLE IF NOT EXISTS reward_codes(
   id INTEGER PRIMARY KEY AUTOINCREMENT,
+  contest INTEGER NOT NULL,
+  pool TEXT NOT NULL,
+  code TEXT NOT NULL,
+  assigned_to INTEGER NULL,
+  assigned_at TIMESTAMP NULL,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  FOREIGN KEY(assigned_to) REFERENCES users(id),
+  UNIQUE(contest, pool, code)
+);
+CREATE TABLE IF NOT EXISTS milestones(
+  contest INTEGER NOT NULL,
+  threshold INTEGER NOT NULL,
+  reward TEXT NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  PRIMARY KEY(contest, threshold),
+  CHECK (threshold > 0)
+);
+CREATE TABLE IF NOT EXISTS milestone_achievements(
+  contest INTEGER NOT NULL,
+  threshold INTEGER NOT NULL,
+  user INTEGER NOT NULL,
+  reward TEXT NOT NULL,
+  code TEXT NULL,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  FOREIGN KEY(user) REFERENCES users(id),
+  PRIMARY KEY(contest, threshold, user)
+);
+CREATE TABLE IF NOT EXISTS prize_deliveries(
+  contest INTEGER NOT NULL,
+  user INTEGER NOT NULL,
+  rank INTEGER NOT NULL,
+  code TEXT NULL,
+  status TEXT NOT NULL,
+  claimed_at TIMESTAMP NULL,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  FOREIGN KEY(user) REFERENCES users(id),
+  PRIMARY KEY(contest, user),
+  CHECK (status IN ('sent', 'failed', 'out_of_stock'))
+);
+CREATE TABLE IF NOT EXISTS fulfillments(
+  contest INTEGER NOT NULL,
   user INTEGER NOT NULL,
+  rank INTEGER NOT NULL,
+  prize TEXT NOT NULL,
+  status TEXT NOT NULL,
+  updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  reminded_at TIMESTAMP NULL,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  FOREIGN KEY(user) REFERENCES users(id),
+  PRIMARY KEY(contest, user),
+  CHECK (status IN ('notified', 'claimed', 'shipped', 'delivered', 'disputed'))
+);
+CREATE TABLE IF NOT EXISTS relay_sessions(
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  contest INTEGER NOT NULL,
   owner INTEGER NOT NULL,
+  winner INTEGER NOT NULL,
+  open BOOL NOT NULL DEFAULT TRUE,
+  owner_focus BOOL NOT NULL DEFAULT FALSE,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  closed_at TIMESTAMP NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  FOREIGN KEY(owner) REFERENCES users(id),
+  FOREIGN KEY(winner) REFERENCES users(id),
+  UNIQUE(contest, winner)
+);
+CREATE TABLE IF NOT EXISTS invitee_rewards(
+  contest INTEGER NOT NULL PRIMARY KEY,
+  message TEXT NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id)
+);
+CREATE TABLE IF NOT EXISTS invitee_reward_deliveries(
+  chan INTEGER NOT NULL,
+  invitee INTEGER NOT NULL,
+  contest INTEGER NOT NULL,
+  invitation INTEGER NOT NULL,
+  code TEXT NULL,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY(chan) REFERENCES channels(id),
+  FOREIGN KEY(invitee) REFERENCES users(id),
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  FOREIGN KEY(invitation) REFERENCES invitations(id),
+  PRIMARY KEY(chan, invitee)
+);
+CREATE TABLE IF NOT EXISTS point_rules(
+  contest INTEGER NOT NULL PRIMARY KEY,
+  base INTEGER NOT NULL,
+  member_bonus INTEGER NOT NULL,
+  active_bonus INTEGER NOT NULL,
+  leave_penalty INTEGER NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id)
+);
+CREATE TABLE IF NOT EXISTS invitation_activity(
+  invitation INTEGER NOT NULL PRIMARY KEY,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY(invitation) REFERENCES invitations(id)
+);
+CREATE TABLE IF NOT EXISTS draw_seeds(
+  contest INTEGER NOT NULL PRIMARY KEY,
+  seed TEXT NOT NULL,
+  commitment TEXT NOT NULL,
+  published BOOL NOT NULL DEFAULT FALSE,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  revealed_at TIMESTAMP NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id)
+);
+CREATE TABLE IF NOT EXISTS giveaway_entries(
   contest INTEGER NOT NULL,
-  contacted BOOL NOT NULL DEFAULT FALSE,
+  user INTEGER NOT NULL,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  FOREIGN KEY(user) REFERENCES users(id),
+  PRIMARY KEY(contest, user)
+);
+CREATE TABLE IF NOT EXISTS activity_messages(
+  contest INTEGER NOT NULL,
+  user INTEGER NOT NULL,
+  digest TEXT NOT NULL,
+  date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
   FOREIGN KEY(user) REFERENCES users(id),
-  FOREIGN KEY(owner) REFERENCES users(id)
+  UNIQUE(contest, user, digest)
+);
+CREATE TABLE IF NOT EXISTS contest_channels(
+  contest INTEGER NOT NULL,
+  chan INTEGER NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  FOREIGN KEY(chan) REFERENCES channels(id),
+  PRIMARY KEY(contest, chan)
+);
+CREATE TABLE IF NOT EXISTS sponsors(
+  contest INTEGER NOT NULL,
+  chat INTEGER NOT NULL,
+  name TEXT NOT NULL,
+  link TEXT NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  PRIMARY KEY(contest, chat)
+);
+CREATE TABLE IF NOT EXISTS contest_templates(
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  contest INTEGER NOT NULL,
+  name TEXT NOT NULL,
+  prize TEXT NOT NULL,
+  duration INTEGER NOT NULL,
+  schedule TEXT NOT NULL,
+  next_run TIMESTAMP NOT NULL,
+  runs INTEGER NOT NULL DEFAULT 0,
+  active BOOL NOT NULL DEFAULT TRUE,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  CHECK (duration > 0)
+);
+CREATE TABLE IF NOT EXISTS template_instances(
+  contest INTEGER NOT NULL PRIMARY KEY,
+  template INTEGER NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  FOREIGN KEY(template) REFERENCES contest_templates(id)
+);
+CREATE TABLE IF NOT EXISTS leaderboards(
+  contest INTEGER NOT NULL,
+  chan INTEGER NOT NULL,
+  message INTEGER NOT NULL,
+  text TEXT NOT NULL,
+  updated_at TIMESTAMP NOT NULL,
+  FOREIGN KEY(contest) REFERENCES contests(id),
+  FOREIGN KEY(chan) REFERENCES channels(id),
+  PRIMARY KEY(contest, chan)
 );
 COMMIT;";
 
+/// Changes to the tables already present in `SCHEMA`. `SQLite` can't add a column only if it
+/// doesn't exist, hence every migration is executed only once: the number of migrations
+/// applied is stored in the `user_version` pragma.
+///
+/// NOTE: migrations are append only. Never change or remove a migration already released.
+const MIGRATIONS: &[&str] = &[
+    // Invitation lifecycle: invitations are never deleted, their status changes. An invitation
+    // split among several participants is worth a fraction of credit, and every contest has
+    // its own invitations: accepting an invitation for a new contest of the channel doesn't
+    // move the invitation of the previous contest.
+    "CREATE TABLE invitations_lifecycle(
+       id   INTEGER PRIMARY KEY AUTOINCREMENT,
+       date TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+       source INTEGER NOT NULL,
+       dest INTEGER NOT NULL,
+       chan INTEGER NOT NULL,
+       contest INTEGER NOT NULL,
+       status TEXT NOT NULL DEFAULT 'joined',
+       reason TEXT NULL,
+       updated_at TIMESTAMP NULL,
+       credit REAL NOT NULL DEFAULT 1,
+       FOREIGN KEY(source) REFERENCES users(id),
+       FOREIGN KEY(dest) REFERENCES users(id),
+       FOREIGN KEY(chan) REFERENCES channels(id),
+       FOREIGN KEY(contest) REFERENCES contests(id),
+       CHECK (source <> dest),
+       UNIQUE(source, dest, chan, contest)
+    );
+    INSERT INTO invitations_lifecycle(id, date, source, dest, chan, contest)
+        SELECT id, date, source, dest, chan, contest FROM invitations;
+    DROP TABLE invitations;
+    ALTER TABLE invitations_lifecycle RENAME TO invitations;",
+    // The relay sessions replaced the single message to the winners
+    "DROP TABLE IF EXISTS being_contacted_users;",
+];
+
+/// Creates the `SCHEMA` in the database of `conn`, and applies the pending `MIGRATIONS` with
+/// the foreign keys disabled, since a migration can rebuild a referenced table. The foreign
+/// keys are enabled at the end.
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the schema or a migration can't be applied.
+pub fn init(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(SCHEMA)?;
+
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    conn.execute_batch("PRAGMA foreign_keys=0;")?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        conn.execute_batch(&format!(
+            "BEGIN;\n{migration}\nPRAGMA user_version = {};\nCOMMIT;",
+            i + 1
+        ))?;
+    }
+    conn.execute_batch("PRAGMA foreign_keys=1;")
+}
+
 /// Creates a connection pool to the `SQLite` database, whose name is always
 /// "raf.db" and it's always in the current working directory of the application.
 ///
-/// Foreign keys are enabled in the `SQLite` instance.
+/// Foreign keys are enabled in the `SQLite` instance, and the database is initialized with
+/// `init`.
 ///
 /// # Panics
 /// Panics if the connection with the db fails.
@@ -94,7 +529,7 @@ pub fn connection() -> r2d2::Pool<SqliteConnectionManager> {
     let pool = r2d2::Pool::builder().max_size(15).build(manager).unwrap();
     {
         let conn = pool.get().unwrap();
-        conn.execute_batch(SCHEMA).unwrap();
+        init(&conn).unwrap();
     }
 
     pool
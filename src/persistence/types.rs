@@ -15,6 +15,7 @@
 use chrono::DateTime;
 use chrono::Utc;
 use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use typemap::Key;
 
 /// A User is a human using the bot
@@ -53,8 +54,291 @@ pub struct BeingManagedChannel {
     pub chan: i64,
 }
 
-/// An invitation sent from source, to dest, for the chan.
+/// The lifecycle of an invitation. Invitations are never deleted: every change of status
+/// is stored, together with the reason, in the `invitation_events` table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InvitationStatus {
+    /// The invitee accepted the invitation, but hasn't joined the channel yet
+    Pending,
+    /// The invitee joined the channel
+    Joined,
+    /// The invitee was still a member of the channel when the contest finished
+    Qualified,
+    /// The invitee left the channel
+    Left,
+    /// The invitation has been invalidated by the channel owner
+    Disqualified,
+    /// The invitation has been manually credited by the channel owner
+    Adjusted,
+    /// The invitation has not been credited: the invitee failed (or didn't answer in time)
+    /// the human-verification challenge, or the attribution policy gave the credit to another
+    /// participant
+    Rejected,
+}
+
+impl InvitationStatus {
+    /// All the statuses, in lifecycle order
+    pub const ALL: [InvitationStatus; 7] = [
+        InvitationStatus::Pending,
+        InvitationStatus::Joined,
+        InvitationStatus::Qualified,
+        InvitationStatus::Left,
+        InvitationStatus::Disqualified,
+        InvitationStatus::Adjusted,
+        InvitationStatus::Rejected,
+    ];
+
+    /// SQL condition matching the invitations that count in the ranking
+    pub const COUNTED: &'static str = "status IN ('joined', 'qualified', 'adjusted')";
+
+    /// True if the invitations with this status count in the ranking
+    #[must_use]
+    pub fn is_counted(self) -> bool {
+        matches!(
+            self,
+            InvitationStatus::Joined | InvitationStatus::Qualified | InvitationStatus::Adjusted
+        )
+    }
+
+    /// The name of the status, as stored in the database
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            InvitationStatus::Pending => "pending",
+            InvitationStatus::Joined => "joined",
+            InvitationStatus::Qualified => "qualified",
+            InvitationStatus::Left => "left",
+            InvitationStatus::Disqualified => "disqualified",
+            InvitationStatus::Adjusted => "adjusted",
+            InvitationStatus::Rejected => "rejected",
+        }
+    }
+}
+
+impl std::fmt::Display for InvitationStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl ToSql for InvitationStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+impl FromSql for InvitationStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let value = value.as_str()?;
+        InvitationStatus::ALL
+            .iter()
+            .copied()
+            .find(|status| status.as_str() == value)
+            .ok_or(FromSqlError::InvalidType)
+    }
+}
+
+/// A decision of the attribution policy, taken when several participants invited the same
+/// user in a contest.
+#[derive(Debug)]
+pub struct Attribution {
+    /// The invitation the decision refers to
+    pub invite: Invite,
+    /// The credit given to the invitation: 0 if it lost, 1 if it won, a fraction if split
+    pub credit: f64,
+}
+
+/// A human-verification challenge sent to an invitee before the invitation is credited.
+#[derive(Debug, Clone)]
+pub struct Challenge {
+    /// Challenge unique ID, locally generated
+    pub id: i64,
+    /// The invitation waiting for the challenge to be solved
+    pub invitation: i64,
+    /// The invitee that must solve the challenge
+    pub user: i64,
+    /// The kind of challenge (arithmetic, emoji, timed)
+    pub kind: String,
+    /// The expected answer
+    pub answer: String,
+    /// After this date the challenge can't be solved anymore
+    pub expires_at: DateTime<Utc>,
+    /// None while waiting for the answer, then true if solved and false if failed or expired
+    pub solved: Option<bool>,
+}
+
+/// A reward given to the participants that reach a number of invites in a contest.
+#[derive(Debug, Clone)]
+pub struct Milestone {
+    /// The contest the milestone belongs to
+    pub contest: i64,
+    /// The score to reach
+    pub threshold: u32,
+    /// The description of the reward
+    pub reward: String,
+}
+
+/// A milestone reached by a participant.
+#[derive(Debug, Clone)]
+pub struct Achievement {
+    /// The milestone reached
+    pub milestone: Milestone,
+    /// The participant
+    pub user: i64,
+    /// The reward code sent to the participant, if the stock wasn't empty
+    pub code: Option<String>,
+    /// Whenever the milestone has been reached
+    pub date: DateTime<Utc>,
+}
+
+/// The delivery of a prize code to a winner.
+#[derive(Debug, Clone)]
+pub struct PrizeDelivery {
+    /// The contest won
+    pub contest: i64,
+    /// The winner
+    pub user: i64,
+    /// The final position of the winner, hence the prize tier
+    pub rank: i64,
+    /// The code sent, `None` if the stock of the tier was empty
+    pub code: Option<String>,
+    /// The outcome of the delivery: `sent`, `failed` (the winner can't be contacted) or
+    /// `out_of_stock`
+    pub status: String,
+    /// Whenever the winner confirmed to have received the code
+    pub claimed_at: Option<DateTime<Utc>>,
+    /// Whenever the delivery has been attempted
+    pub date: DateTime<Utc>,
+}
+
+/// The fulfillment of the prize of a winner: the winner is notified, claims the prize, the
+/// owner ships it and the winner confirms it has been delivered, or opens a dispute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FulfillmentStatus {
+    /// The winner has been informed of the prize
+    Notified,
+    /// The winner claimed the prize
+    Claimed,
+    /// The owner sent the prize (prize codes are shipped as soon as they are sent)
+    Shipped,
+    /// The winner received the prize
+    Delivered,
+    /// The winner reported a problem with the prize
+    Disputed,
+}
+
+impl FulfillmentStatus {
+    /// All the statuses, in lifecycle order
+    pub const ALL: [FulfillmentStatus; 5] = [
+        FulfillmentStatus::Notified,
+        FulfillmentStatus::Claimed,
+        FulfillmentStatus::Shipped,
+        FulfillmentStatus::Delivered,
+        FulfillmentStatus::Disputed,
+    ];
+
+    /// The name of the status, as stored in the database
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FulfillmentStatus::Notified => "notified",
+            FulfillmentStatus::Claimed => "claimed",
+            FulfillmentStatus::Shipped => "shipped",
+            FulfillmentStatus::Delivered => "delivered",
+            FulfillmentStatus::Disputed => "disputed",
+        }
+    }
+
+    /// Parses the name of a status
+    #[must_use]
+    pub fn parse(value: &str) -> Option<FulfillmentStatus> {
+        FulfillmentStatus::ALL
+            .iter()
+            .copied()
+            .find(|status| status.as_str() == value)
+    }
+}
+
+impl std::fmt::Display for FulfillmentStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl ToSql for FulfillmentStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+impl FromSql for FulfillmentStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        FulfillmentStatus::parse(value.as_str()?).ok_or(FromSqlError::InvalidType)
+    }
+}
+
+/// The prize fulfillment record of a winner.
+#[derive(Debug, Clone)]
+pub struct Fulfillment {
+    /// The contest won
+    pub contest: i64,
+    /// The winner
+    pub user: i64,
+    /// The final position of the winner
+    pub rank: i64,
+    /// The prize, as described to the winner
+    pub prize: String,
+    /// The current status
+    pub status: FulfillmentStatus,
+    /// Whenever the status changed the last time
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A two-way chat between the owner of a contest and a winner, relayed by the bot.
+#[derive(Debug, Clone)]
+pub struct RelaySession {
+    /// Session unique ID, locally generated
+    pub id: i64,
+    /// The contest won
+    pub contest: i64,
+    /// The owner of the contest
+    pub owner: i64,
+    /// The winner
+    pub winner: i64,
+}
+
+/// A contest action waiting for the owner to write something.
 #[derive(Debug)]
+pub struct BeingManagedContest {
+    /// Unique ID, locally generated
+    pub id: i64,
+    /// The owner that must answer
+    pub owner: i64,
+    /// The contest being managed
+    pub contest: i64,
+    /// The action the owner is answering to
+    pub action: String,
+    /// The (optional) argument of the action, e.g. the user to disqualify
+    pub arg: Option<String>,
+}
+
+/// An entry of the audit log: an action executed by `actor` on a contest.
+#[derive(Debug)]
+pub struct AuditEntry {
+    /// When the action has been executed
+    pub date: DateTime<Utc>,
+    /// The user that executed the action
+    pub actor: User,
+    /// The action executed (e.g. "disqualify", "revoke")
+    pub action: String,
+    /// The target of the action: a user ID or an invitation ID, depending on the action
+    pub target: Option<i64>,
+    /// The reason of the action, if any
+    pub reason: Option<String>,
+}
+
+/// An invitation sent from source, to dest, for the chan.
+#[derive(Debug, Clone)]
 pub struct Invite {
     /// Invitation unique ID, locally generated
     pub id: i64,
@@ -66,6 +350,119 @@ pub struct Invite {
     pub dest: i64,
     /// The channel dest user is being invited into
     pub chan: i64,
+    /// The contest the invitation belongs to
+    pub contest: i64,
+    /// The current status of the invitation
+    pub status: InvitationStatus,
+    /// The reason of the last status change, if any
+    pub reason: Option<String>,
+    /// Whenever the status changed the last time
+    pub updated_at: Option<DateTime<Utc>>,
+    /// The credit the invitation is worth: less than 1 when split among several participants
+    pub credit: f64,
+}
+
+/// The number of invitations per status sent by a participant in a contest.
+#[derive(Debug, Clone)]
+pub struct StatusBreakdown {
+    /// The participant (source of the invitations)
+    pub user: User,
+    /// Number of invitations per status, in the same order of `InvitationStatus::ALL`
+    pub counts: [i64; InvitationStatus::ALL.len()],
+}
+
+/// The invitations credited to a participant at a level of the referral graph.
+#[derive(Debug, Clone)]
+pub struct ReferralLevel {
+    /// The level: 1 for the direct invitations, 2 for the invitations of the invitees, ...
+    pub level: i64,
+    /// Credited invitations at this level (a split invitation counts as a fraction)
+    pub invites: f64,
+    /// The points earned: the invites, weighted by the level
+    pub points: f64,
+}
+
+/// A partner chat of a contest: the invitees must join it too, for their invitation to count.
+#[derive(Debug, Clone)]
+pub struct Sponsor {
+    /// Chat unique ID, Telegram generated
+    pub chat: i64,
+    /// Chat name
+    pub name: String,
+    /// The invitation link of the chat
+    pub link: String,
+}
+
+/// A recurring contest: the scheduler creates, starts and finishes an instance of it every
+/// time its schedule comes.
+#[derive(Debug, Clone)]
+pub struct Template {
+    /// Unique identifier
+    pub id: i64,
+    /// The contest whose rules (settings, milestones, partners, ...) every instance copies
+    pub contest: i64,
+    /// The name of the instances, with the placeholders `{n}`, `{date}`, `{week}`, `{month}`
+    pub name: String,
+    /// The prize of the instances
+    pub prize: String,
+    /// How long every instance lasts, in seconds
+    pub duration: i64,
+    /// The recurrence: `weekly`, `monthly`, or a cron expression
+    pub schedule: String,
+    /// When the next instance is created
+    pub next_run: DateTime<Utc>,
+    /// The number of instances created
+    pub runs: i64,
+}
+
+/// The points model of a contest: how many points every invitation is worth.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PointsFormula {
+    /// Points per credited invitation
+    pub base: i64,
+    /// Bonus per invitee still a member of the channel when the contest finishes
+    pub member_bonus: i64,
+    /// Bonus per invitee that wrote in the group while the contest was running
+    pub active_bonus: i64,
+    /// Penalty per invitee that left the channel
+    pub leave_penalty: i64,
+}
+
+impl Default for PointsFormula {
+    /// The raw count of the invitations: one point per credited invitation
+    fn default() -> Self {
+        PointsFormula {
+            base: 1,
+            member_bonus: 0,
+            active_bonus: 0,
+            leave_penalty: 0,
+        }
+    }
+}
+
+/// The invitations of a participant that earn (or lose) points, per rule of the points model.
+/// A split invitation counts as a fraction.
+#[derive(Debug, Clone, Default)]
+pub struct PointsBreakdown {
+    /// Credited invitations
+    pub invites: f64,
+    /// Invitees still members when the contest finished
+    pub members: f64,
+    /// Invitees active in the group
+    pub active: f64,
+    /// Invitees that left the channel
+    pub left: f64,
+}
+
+/// A participant of a contest flagged by the fraud heuristics.
+#[derive(Debug, Clone)]
+pub struct Suspect {
+    /// The participant (source of the invitations)
+    pub user: User,
+    /// Suspiciousness score, from 0 (nothing suspicious) to 100
+    pub score: u32,
+    /// Human readable description of every heuristic that matched
+    pub reasons: Vec<String>,
 }
 
 /// A referral based strategy contest
@@ -93,19 +490,83 @@ pub struct Contest {
 pub struct RankContest {
     /// A user rank (position)
     pub rank: i64,
+    /// Credited invitations counted for the user
+    pub invites: f64,
+    /// Points earned by the invitations of the user
+    pub points: f64,
+    /// Weighted credit of the invitations of the invitees of the user
+    pub indirect: f64,
+    /// Sum of the manual adjustments of the user
+    pub adjustment: i64,
     /// The contest associated
     pub c: Contest,
 }
 
+/// A manual credit adjustment: points added to (or removed from) a participant by the owner.
+#[derive(Debug)]
+pub struct Adjustment {
+    /// Adjustment unique ID, locally generated
+    pub id: i64,
+    /// The contest the adjustment belongs to
+    pub contest: i64,
+    /// The participant whose credit changed
+    pub user: i64,
+    /// Points added (positive) or removed (negative)
+    pub points: i64,
+    /// Why the credit changed
+    pub reason: String,
+    /// The user that changed the credit
+    pub actor: i64,
+    /// When the credit changed
+    pub date: DateTime<Utc>,
+}
+
 /// Rank is like a row in a ranking table.
 #[derive(Debug, Clone)]
 pub struct Rank {
     /// The position in the chart
     pub rank: i64,
-    /// Number of invitations sent by this user
-    pub invites: i64,
+    /// Credited invitations sent by this user (a split invitation counts as a fraction)
+    pub invites: f64,
+    /// Points earned by the invitations, according to the points model of the contest. Without
+    /// a points model, they are the credited invitations.
+    pub points: f64,
+    /// Weighted credit of the invitations of the invitees (multi-level referrals)
+    pub indirect: f64,
+    /// Sum of the manual adjustments
+    pub adjustment: i64,
+    /// The score of the user: `points + indirect + adjustment`
+    pub score: f64,
     /// The user that is in `rank` position because it sent `invites` invitations
     pub user: User,
+    /// Date of the last invitation counted. It's the tie-break: with the same number of
+    /// invites, the user who reached it first is ranked higher.
+    pub last_invite: DateTime<Utc>,
+}
+
+/// A row of the frozen results of a finished contest.
+#[derive(Debug, Clone)]
+pub struct ContestResult {
+    /// The contest this result belongs to
+    pub contest: i64,
+    /// The final position in the chart
+    pub rank: i64,
+    /// Credited invitations counted when the contest finished
+    pub invites: f64,
+    /// Points earned by the invitations when the contest finished
+    pub points: f64,
+    /// Weighted credit of the invitations of the invitees, when the contest finished
+    pub indirect: f64,
+    /// Sum of the manual adjustments when the contest finished
+    pub adjustment: i64,
+    /// The final score: `points + indirect + adjustment`
+    pub score: f64,
+    /// The prize won with this position, if any
+    pub prize: Option<String>,
+    /// Date of the last invitation counted, used as tie-break
+    pub last_invite: DateTime<Utc>,
+    /// The user in `rank` position
+    pub user: User,
 }
 
 /// Unique type for a `typemap::Key` used to fetch from the Telexide context
@@ -121,3 +582,10 @@ pub struct NameKey;
 impl Key for NameKey {
     type Value = String;
 }
+
+/// Unique type for a `typemap::Key` used to fetch from the Telexide context
+/// the (optional) Telegram ID of the bot operator, without accessing in this way to the `env`.
+pub struct OperatorKey;
+impl Key for OperatorKey {
+    type Value = Option<i64>;
+}
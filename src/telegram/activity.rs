@@ -0,0 +1,201 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use rusqlite::params;
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::fmt::Write;
+use telexide_fork::{model::User, prelude::*};
+
+use crate::persistence::types::{Contest, DBKey, Rank};
+use crate::telegram::messages::escape_markdown;
+use crate::telegram::{channels, eligibility, fairness, networks, settings, users};
+
+/// Returns true if the `contest` is an activity contest: the members writing more qualifying
+/// messages in the group win, without referrals.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+#[must_use]
+pub fn enabled(ctx: &Context, contest: i64) -> bool {
+    settings::get(ctx, contest, settings::MODE) == "activity"
+}
+
+/// Returns the digest of the `text` used to spot the duplicates: the case and the spacing
+/// don't make a message different.
+fn digest(text: &str) -> String {
+    let normalized = text
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    fairness::hex(&Sha256::digest(normalized.as_bytes()))
+}
+
+/// Counts the message `text` written by the `user` in the group `chat`, for every running
+/// activity contest involving the group. A message counts only if the user satisfies the
+/// eligibility rules of the contest, if it's long enough, if it's not a command, if the user
+/// didn't write it before, and if the cooldown since the last counted message of the user is
+/// over. Stickers and media without caption have no text, and
+/// never reach this function.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chat` - The group ID
+/// * `user` - The user that wrote the message
+/// * `text` - The text of the message
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the insertion fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn record(ctx: &Context, chat: i64, user: &User, text: &str) -> rusqlite::Result<()> {
+    if user.is_bot || text.trim_start().starts_with('/') {
+        return Ok(());
+    }
+    let Some(group) = channels::get(ctx, chat) else {
+        return Ok(());
+    };
+    let running = networks::contests(ctx, chat)
+        .into_iter()
+        .filter(|c| c.started_at.is_some() && !c.stopped && enabled(ctx, c.id))
+        .collect::<Vec<_>>();
+    if running.is_empty() {
+        return Ok(());
+    }
+    let length = i64::try_from(text.trim().chars().count()).unwrap_or(i64::MAX);
+    let digest = digest(text);
+    for c in running {
+        if length < settings::number(ctx, c.id, settings::ACTIVITY_MIN_LENGTH) {
+            continue;
+        }
+        if eligibility::check_participant(ctx, &group, &c, user)
+            .await
+            .is_err()
+        {
+            continue;
+        }
+        let cooldown = settings::number(ctx, c.id, settings::ACTIVITY_COOLDOWN);
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.execute(
+            "INSERT OR IGNORE INTO users(id, first_name, last_name, username) VALUES(?, ?, ?, ?)",
+            params![user.id, user.first_name, user.last_name, user.username],
+        )?;
+        // The duplicates violate the unique constraint, and are ignored
+        conn.execute(
+            "INSERT OR IGNORE INTO activity_messages(contest, user, digest) \
+            SELECT ?1, ?2, ?3 WHERE NOT EXISTS (\
+                SELECT 1 FROM activity_messages WHERE contest = ?1 AND user = ?2 \
+                AND date > datetime('now', ?4))",
+            params![c.id, user.id, digest, format!("-{cooldown} seconds")],
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns the leaderboard of the activity contest `c`: the members sorted by counted
+/// messages. In case of equal messages, the member who reached the count first wins.
+/// The disqualified members are not ranked.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The activity contest
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+#[must_use]
+pub fn ranking(ctx: &Context, c: &Contest) -> Vec<Rank> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let mut stmt = conn
+        .prepare(
+            "SELECT ROW_NUMBER() OVER (ORDER BY COUNT(*) DESC, MAX(date) ASC, user ASC) AS r,
+            COUNT(*), user, MAX(date)
+            FROM activity_messages WHERE contest = ?1
+            AND user NOT IN (SELECT user FROM disqualified_participants WHERE contest = ?1)
+            GROUP BY user ORDER BY r",
+        )
+        .unwrap();
+    let rank = stmt
+        .query_map(params![c.id], |row| {
+            let messages: f64 = row.get(1)?;
+            Ok(Rank {
+                rank: row.get(0)?,
+                invites: 0.0,
+                points: messages,
+                indirect: 0.0,
+                adjustment: 0,
+                score: messages,
+                user: users::get(ctx, row.get(2)?).unwrap(),
+                last_invite: row.get(3)?,
+            })
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    rank
+}
+
+/// Returns the description of the activity contest `c`, shown in the announcement, or `None`
+/// if the contest is not an activity contest.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+#[must_use]
+pub fn rule(ctx: &Context, c: &Contest) -> Option<String> {
+    if !enabled(ctx, c.id) {
+        return None;
+    }
+    let mut rule = format!(
+        "Every message of at least {} characters you write in the group is a point. Commands, \
+        stickers and repeated messages don't count",
+        settings::number(ctx, c.id, settings::ACTIVITY_MIN_LENGTH)
+    );
+    let cooldown = settings::number(ctx, c.id, settings::ACTIVITY_COOLDOWN);
+    if cooldown > 0 {
+        let _ = write!(
+            rule,
+            ", and only one message every {cooldown} seconds counts"
+        );
+    }
+    rule += ".";
+    Some(rule)
+}
+
+/// Returns the announcement of the activity contest `c`, already escaped for `MarkdownV2`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The activity contest
+#[must_use]
+pub fn announcement(ctx: &Context, c: &Contest) -> String {
+    let mut text = format!(
+        "\u{1f4ac}{name} contest \u{1f4ac}\nWho is the most active in the group wins a {prize}!\n\n\
+        At the end of the contest ({end}) the member with more counted messages wins.\n",
+        name = c.name,
+        prize = c.prize,
+        end = c.end,
+    );
+    if let Some(rule) = rule(ctx, c) {
+        let _ = writeln!(text, "\u{2022} {rule}");
+    }
+    text += "\nYou can check your rank with the /rank command";
+    escape_markdown(&text, None)
+}
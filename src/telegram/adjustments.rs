@@ -0,0 +1,158 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use log::error;
+use rusqlite::params;
+use telexide_fork::prelude::*;
+
+use crate::persistence::types::{Adjustment, Contest, DBKey};
+use crate::telegram::{audit, participants};
+
+/// Adds to the ledger of the contest `c` an adjustment of `points` for the participant `user`.
+/// The credit of a disqualified participant, or of a finished contest, can't change.
+/// The action is added to the audit log.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+/// * `user` - The participant whose credit changes
+/// * `points` - Points to add (positive) or to remove (negative)
+/// * `reason` - Why the credit changes
+/// * `actor` - The user changing the credit
+///
+/// # Errors
+/// Returns the reason of the failure, ready to be shown to the owner.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn add(
+    ctx: &Context,
+    c: &Contest,
+    user: i64,
+    points: i64,
+    reason: &str,
+    actor: i64,
+) -> Result<(), String> {
+    if c.stopped {
+        return Err("The contest is finished".to_string());
+    }
+    if participants::disqualification(ctx, c.id, user).is_some() {
+        return Err("The participant is disqualified".to_string());
+    }
+    let res = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.execute(
+            "INSERT INTO adjustments(contest, user, points, reason, actor) VALUES(?, ?, ?, ?, ?)",
+            params![c.id, user, points, reason, actor],
+        )
+    };
+    res.and_then(|_| {
+        audit::log(
+            ctx,
+            c.id,
+            actor,
+            &format!("adjust {points:+}"),
+            Some(user),
+            Some(reason),
+        )
+    })
+    .map_err(|err| {
+        error!("[adjust] {err}");
+        err.to_string()
+    })
+}
+
+/// Returns the adjustments of the participant `user` in the `contest`, the oldest first.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `user` - The participant ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+#[must_use]
+pub fn get(ctx: &Context, contest: i64, user: i64) -> Vec<Adjustment> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let mut stmt = conn
+        .prepare(
+            "SELECT id, points, reason, actor, date FROM adjustments \
+            WHERE contest = ? AND user = ? ORDER BY id ASC",
+        )
+        .unwrap();
+    let adjustments = stmt
+        .query_map(params![contest, user], |row| {
+            Ok(Adjustment {
+                id: row.get(0)?,
+                contest,
+                user,
+                points: row.get(1)?,
+                reason: row.get(2)?,
+                actor: row.get(3)?,
+                date: row.get(4)?,
+            })
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    adjustments
+}
+
+/// Parses the text written by the owner to adjust the credit of a participant:
+/// `<points> <reason>`, where points is a signed integer (e.g. `+2`, `-1`).
+///
+/// # Arguments
+/// * `text` - The text written by the owner
+///
+/// # Errors
+/// Returns a string describing why the text is not valid.
+pub fn parse(text: &str) -> Result<(i64, String), String> {
+    let text = text.trim();
+    let (points, reason) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let points = points
+        .parse::<i64>()
+        .map_err(|_| format!("\"{points}\" is not a valid number of points"))?;
+    if points == 0 {
+        return Err("The points can't be zero".to_string());
+    }
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err("The reason is mandatory".to_string());
+    }
+    Ok((points, reason.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_adjustment() {
+        assert_eq!(parse("+2 good job"), Ok((2, "good job".to_string())));
+        assert_eq!(parse(" -1   spam  "), Ok((-1, "spam".to_string())));
+        assert_eq!(parse("3\treason"), Ok((3, "reason".to_string())));
+    }
+
+    #[test]
+    fn parse_malformed_adjustment() {
+        assert!(parse("0 nothing").is_err());
+        assert!(parse("2").is_err());
+        assert!(parse("two reason").is_err());
+        assert!(parse("").is_err());
+    }
+}
@@ -0,0 +1,201 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use log::error;
+use rusqlite::{params, Connection, TransactionBehavior};
+use std::convert::TryFrom;
+use telexide_fork::{api::types::SendMessage, prelude::*};
+
+use crate::persistence::types::{Attribution, Contest, DBKey, InvitationStatus, Invite};
+use crate::telegram::{invitations, settings, users};
+
+/// Reason of the invitations rejected because their source already invited the same user in
+/// another channel of the contest.
+const DUPLICATE_REASON: &str = "duplicate: already invited in another channel of the contest";
+
+/// Returns the other counted invitations of the invitee of `invite` in the same contest, sent
+/// by the participants matching the SQL `sources` condition. The pending invitations never
+/// count: the invitee didn't join with them.
+///
+/// # Arguments
+/// * `conn` - The connection to use: usually, the transaction of the caller
+/// * `invite` - The invitation just joined
+/// * `sources` - SQL condition on the source of the invitations
+fn others(conn: &Connection, invite: &Invite, sources: &str) -> Vec<Invite> {
+    invitations::get_all_tx(
+        conn,
+        invite.contest,
+        &format!(
+            "dest = {} AND id <> {} AND {sources} AND {}",
+            invite.dest,
+            invite.id,
+            InvitationStatus::COUNTED
+        ),
+    )
+}
+
+/// Marks the `invite` as `Joined` and applies the attribution policy of the contest, if other
+/// participants already brought the same user in the same contest:
+///
+/// - first: the credit stays to the participant who brought the user in first, the new
+///   invitation is rejected;
+/// - last: the credit goes to the new invitation, the previous ones are rejected;
+/// - split: the credit is split equally among all the invitations.
+///
+/// Only the counted invitations take part in the attribution: it's applied once per
+/// invitation, when the invitee joins. Every decision is recorded in the `attributions` table,
+/// and the invitations that lose the credit (or part of it) are returned. An invitee counts
+/// once per participant: if the source of `invite` already brought the same user in another
+/// channel of the contest, `invite` is rejected whatever the policy.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `invite` - The pending invitation whose invitee just joined
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if an update fails. In this case nothing changes.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn apply(ctx: &Context, invite: &Invite) -> rusqlite::Result<Vec<Attribution>> {
+    let policy = settings::get(ctx, invite.contest, settings::ATTRIBUTION);
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let mut conn = map.get().unwrap();
+    // Immediate: the concurrent joins of the same invitee see each other
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    invitations::set_status_tx(&tx, invite.id, InvitationStatus::Joined, None)?;
+    let mut invite = invite.clone();
+    invite.status = InvitationStatus::Joined;
+
+    if !others(&tx, &invite, &format!("source = {}", invite.source)).is_empty() {
+        invitations::set_status_tx(
+            &tx,
+            invite.id,
+            InvitationStatus::Rejected,
+            Some(DUPLICATE_REASON),
+        )?;
+        tx.commit()?;
+        invite.status = InvitationStatus::Rejected;
+        invite.reason = Some(DUPLICATE_REASON.to_string());
+        return Ok(vec![Attribution {
+            invite,
+            credit: 0.0,
+        }]);
+    }
+    let mut all = others(&tx, &invite, &format!("source <> {}", invite.source));
+    if all.is_empty() {
+        tx.commit()?;
+        return Ok(vec![]);
+    }
+    all.push(invite.clone());
+
+    let split = 1.0 / f64::from(u32::try_from(all.len()).unwrap_or(u32::MAX));
+    let decisions = all
+        .into_iter()
+        .map(|other| {
+            let winner = match policy.as_str() {
+                "last" => other.id == invite.id,
+                "split" => true,
+                _ => other.id != invite.id,
+            };
+            let credit = match (policy.as_str(), winner) {
+                ("split", _) => split,
+                (_, true) => 1.0,
+                (_, false) => 0.0,
+            };
+            Attribution {
+                invite: other,
+                credit,
+            }
+        })
+        .collect::<Vec<Attribution>>();
+
+    for decision in &decisions {
+        tx.execute(
+            "INSERT INTO attributions(contest, invitee, invitation, caused_by, policy, credit) \
+            VALUES(?, ?, ?, ?, ?, ?)",
+            params![
+                invite.contest,
+                invite.dest,
+                decision.invite.id,
+                invite.id,
+                policy,
+                decision.credit
+            ],
+        )?;
+        tx.execute(
+            "UPDATE invitations SET credit = ? WHERE id = ?",
+            params![decision.credit, decision.invite.id],
+        )?;
+        if decision.credit <= 0.0 {
+            invitations::set_status_tx(
+                &tx,
+                decision.invite.id,
+                InvitationStatus::Rejected,
+                Some(&format!(
+                    "attribution policy ({policy}): credited to another participant"
+                )),
+            )?;
+        }
+    }
+    tx.commit()?;
+    Ok(decisions
+        .into_iter()
+        .filter(|decision| decision.credit < 1.0)
+        .collect())
+}
+
+/// Informs the participants whose invitations lost the credit (or part of it) because of
+/// the attribution policy of the contest `c`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+/// * `decisions` - The decisions returned by `apply`
+pub async fn notify(ctx: &Context, c: &Contest, decisions: &[Attribution]) {
+    for decision in decisions {
+        let invitee = users::get(ctx, decision.invite.dest).map_or_else(
+            || "The user you invited".to_string(),
+            |u| users::display_name(&u),
+        );
+        let duplicate = decision.invite.reason.as_deref() == Some(DUPLICATE_REASON);
+        let text = if duplicate {
+            format!(
+                "You already invited {invitee} in another channel of the contest {}: this \
+                invitation doesn't count twice.",
+                c.name
+            )
+        } else if decision.credit <= 0.0 {
+            format!(
+                "{invitee} has been invited by another participant too. Because of the rules \
+                of the contest {}, this invitation doesn't count for you.",
+                c.name
+            )
+        } else {
+            format!(
+                "{invitee} has been invited by other participants too. Because of the rules \
+                of the contest {}, the credit is split: this invitation is worth {:.2} for you.",
+                c.name, decision.credit
+            )
+        };
+        let res = ctx
+            .api
+            .send_message(SendMessage::new(decision.invite.source, &text))
+            .await;
+        if let Err(err) = res {
+            error!("[attribution notify] {err}");
+        }
+    }
+}
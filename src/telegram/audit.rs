@@ -0,0 +1,109 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use rusqlite::{params, Connection};
+use telexide_fork::prelude::*;
+
+use crate::persistence::types::{AuditEntry, DBKey, User};
+
+/// Adds to the audit log of the `contest` the `action` executed by `actor`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `actor` - The user that executed the action
+/// * `action` - The action executed
+/// * `target` - The target (user or invitation ID) of the action, if any
+/// * `reason` - The reason of the action, if any
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the insertion fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn log(
+    ctx: &Context,
+    contest: i64,
+    actor: i64,
+    action: &str,
+    target: Option<i64>,
+    reason: Option<&str>,
+) -> rusqlite::Result<()> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    log_tx(&conn, contest, actor, action, target, reason)
+}
+
+/// Same as `log`, on the connection `conn`: usually, the transaction of the caller.
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the insertion fails.
+pub(crate) fn log_tx(
+    conn: &Connection,
+    contest: i64,
+    actor: i64,
+    action: &str,
+    target: Option<i64>,
+    reason: Option<&str>,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO audit_log(contest, actor, action, target, reason) VALUES(?, ?, ?, ?, ?)",
+        params![contest, actor, action, target, reason],
+    )?;
+    Ok(())
+}
+
+/// Returns the last `limit` entries of the audit log of the `contest`, the most recent first.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `limit` - Maximum number of entries to return
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+#[must_use]
+pub fn get(ctx: &Context, contest: i64, limit: i64) -> Vec<AuditEntry> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let mut stmt = conn
+        .prepare(
+            "SELECT audit_log.date, audit_log.action, audit_log.target, audit_log.reason, \
+            users.id, users.first_name, users.last_name, users.username \
+            FROM audit_log INNER JOIN users ON audit_log.actor = users.id \
+            WHERE audit_log.contest = ? ORDER BY audit_log.id DESC LIMIT ?",
+        )
+        .unwrap();
+    let entries = stmt
+        .query_map(params![contest, limit], |row| {
+            Ok(AuditEntry {
+                date: row.get(0)?,
+                action: row.get(1)?,
+                target: row.get(2)?,
+                reason: row.get(3)?,
+                actor: User {
+                    id: row.get(4)?,
+                    first_name: row.get(5)?,
+                    last_name: row.get(6)?,
+                    username: row.get(7)?,
+                },
+            })
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    entries
+}
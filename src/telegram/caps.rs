@@ -0,0 +1,122 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::Utc;
+use std::convert::TryFrom;
+use telexide_fork::prelude::*;
+
+use crate::persistence::types::{Contest, InvitationStatus};
+use crate::telegram::{invitations, participants, settings};
+
+/// Checks the caps of the contest `c` for the `invitation` just accepted, sent by the
+/// `source`. Returns the key of the first cap reached and its description.
+///
+/// The `source` is registered as participant if it got its link before the contest had a
+/// maximum number of participants: the invitations of a waitlisted participant never count.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+/// * `source` - The participant that sent the invitation
+/// * `invitation` - The ID of the invitation just created
+///
+/// # Errors
+/// Returns the key of the cap reached and its description.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn check(
+    ctx: &Context,
+    c: &Contest,
+    source: i64,
+    invitation: i64,
+) -> Result<(), (&'static str, String)> {
+    if settings::number(ctx, c.id, settings::MAX_PARTICIPANTS) > 0 {
+        match participants::register(ctx, c.id, source) {
+            Ok(None) => {}
+            Ok(Some(position)) => {
+                return Err((
+                    settings::MAX_PARTICIPANTS,
+                    format!("the participant is #{position} in the waitlist"),
+                ))
+            }
+            Err(err) => return Err((settings::MAX_PARTICIPANTS, err.to_string())),
+        }
+    }
+
+    let others: Vec<_> = invitations::sent_by(ctx, c.id, source)
+        .into_iter()
+        .filter(|invite| invite.id != invitation)
+        .collect();
+
+    let max = settings::number(ctx, c.id, settings::MAX_INVITES);
+    if max > 0 {
+        // A pending invitation can stay pending forever: only the counted ones take a slot
+        let counting = others
+            .iter()
+            .filter(|invite| invite.status.is_counted())
+            .count();
+        if i64::try_from(counting).unwrap_or(i64::MAX) >= max {
+            return Err((
+                settings::MAX_INVITES,
+                format!("the participant reached the limit of {max} invites"),
+            ));
+        }
+    }
+
+    let max = settings::number(ctx, c.id, settings::MAX_DAILY_INVITES);
+    if max > 0 {
+        let today = Utc::now().date_naive();
+        let sent_today = others
+            .iter()
+            .filter(|invite| {
+                invite.date.date_naive() == today && invite.status != InvitationStatus::Rejected
+            })
+            .count();
+        if i64::try_from(sent_today).unwrap_or(i64::MAX) >= max {
+            return Err((
+                settings::MAX_DAILY_INVITES,
+                format!("the participant reached the limit of {max} invites per day"),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the caps of the contest `c`, as rules ready to be shown to the users.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+#[must_use]
+pub fn rules(ctx: &Context, c: &Contest) -> Vec<String> {
+    let mut rules = vec![];
+    let max = settings::number(ctx, c.id, settings::MAX_INVITES);
+    if max > 0 {
+        rules.push(format!("Every participant can invite up to {max} friends."));
+    }
+    let max = settings::number(ctx, c.id, settings::MAX_DAILY_INVITES);
+    if max > 0 {
+        rules.push(format!(
+            "Every participant can invite up to {max} friends per day (UTC)."
+        ));
+    }
+    let max = settings::number(ctx, c.id, settings::MAX_PARTICIPANTS);
+    if max > 0 {
+        rules.push(format!(
+            "The contest is limited to {max} participants: the others are put on a waitlist."
+        ));
+    }
+    rules
+}
@@ -0,0 +1,362 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::{Duration, Utc};
+use log::error;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rusqlite::params;
+use telexide_fork::{
+    api::types::SendMessage,
+    model::{InlineKeyboardButton, InlineKeyboardMarkup, ReplyMarkup},
+    prelude::*,
+};
+
+use crate::persistence::types::{Challenge, DBKey, InvitationStatus};
+use crate::telegram::invitations;
+use crate::telegram::messages::callback_button;
+
+/// The emojis used in the emoji grid, with the name used in the question.
+const EMOJIS: [(&str, &str); 16] = [
+    ("\u{1f34e}", "apple"),
+    ("\u{1f34c}", "banana"),
+    ("\u{1f352}", "cherries"),
+    ("\u{1f347}", "grapes"),
+    ("\u{1f955}", "carrot"),
+    ("\u{1f355}", "pizza"),
+    ("\u{1f436}", "dog"),
+    ("\u{1f431}", "cat"),
+    ("\u{1f41f}", "fish"),
+    ("\u{1f697}", "car"),
+    ("\u{1f6b2}", "bicycle"),
+    ("\u{2708}\u{fe0f}", "airplane"),
+    ("\u{26bd}", "football"),
+    ("\u{1f3b8}", "guitar"),
+    ("\u{2602}\u{fe0f}", "umbrella"),
+    ("\u{1f511}", "key"),
+];
+
+/// Seconds the invitee has to solve a challenge, per kind.
+///
+/// # Arguments
+/// * `kind` - The kind of challenge
+fn window(kind: &str) -> i64 {
+    match kind {
+        "timed" => 15,
+        "emoji" => 60,
+        _ => 120,
+    }
+}
+
+/// Returns the question, the expected answer and the (optional) buttons of a new challenge
+/// of the specified `kind`.
+///
+/// # Arguments
+/// * `kind` - The kind of challenge: arithmetic, emoji or timed
+fn generate(kind: &str) -> (String, String, Vec<(String, String)>) {
+    let mut rng = rand::thread_rng();
+    match kind {
+        "emoji" => {
+            let grid = EMOJIS.choose_multiple(&mut rng, 9).collect::<Vec<_>>();
+            let target = rng.gen_range(0..grid.len());
+            let buttons = grid
+                .iter()
+                .enumerate()
+                .map(|(i, (emoji, _))| ((*emoji).to_string(), i.to_string()))
+                .collect();
+            (
+                format!("Tap the {} to verify you are human.", grid[target].1),
+                target.to_string(),
+                buttons,
+            )
+        }
+        "timed" => {
+            let (a, b) = (rng.gen_range(2..10), rng.gen_range(2..10));
+            let result: i64 = a * b;
+            let mut options = [result, result + 1, result - 1, result + a];
+            options.shuffle(&mut rng);
+            let buttons = options
+                .iter()
+                .map(|option| (option.to_string(), option.to_string()))
+                .collect();
+            (
+                format!(
+                    "How much is {a} \u{d7} {b}? You have {} seconds.",
+                    window(kind)
+                ),
+                result.to_string(),
+                buttons,
+            )
+        }
+        _ => {
+            let (a, b): (i64, i64) = (rng.gen_range(1..50), rng.gen_range(1..50));
+            (
+                format!(
+                    "How much is {a} + {b}? Write the result within {} minutes.",
+                    window(kind) / 60
+                ),
+                (a + b).to_string(),
+                vec![],
+            )
+        }
+    }
+}
+
+/// Creates a challenge of the specified `kind` for the `invitation` and sends it to the
+/// invitee `user` in the private chat. Returns the challenge if it has been sent.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `kind` - The kind of challenge: arithmetic, emoji or timed
+/// * `invitation` - The pending invitation
+/// * `user` - The invitee
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn send(ctx: &Context, kind: &str, invitation: i64, user: i64) -> Option<Challenge> {
+    let (question, answer, buttons) = generate(kind);
+    let expires_at = Utc::now() + Duration::seconds(window(kind));
+    let id = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.query_row(
+            "INSERT INTO challenges(invitation, user, kind, answer, expires_at) \
+            VALUES(?, ?, ?, ?, ?) RETURNING id",
+            params![invitation, user, kind, answer, expires_at],
+            |row| row.get(0),
+        )
+    };
+    if let Err(err) = id {
+        error!("[create challenge] {err}");
+        return None;
+    }
+    let id: i64 = id.unwrap();
+
+    let mut reply = SendMessage::new(user, &format!("\u{1f916} {question}"));
+    if !buttons.is_empty() {
+        let buttons = buttons
+            .iter()
+            .map(|(text, value)| callback_button(text, &format!("challenge {id} {value}")))
+            .collect::<Vec<InlineKeyboardButton>>();
+        reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+            inline_keyboard: buttons.chunks(3).map(<[_]>::to_vec).collect(),
+        }));
+    }
+    if let Err(err) = ctx.api.send_message(reply).await {
+        error!("[send challenge] {err}");
+    }
+    Some(Challenge {
+        id,
+        invitation,
+        user,
+        kind: kind.to_string(),
+        answer,
+        expires_at,
+        solved: None,
+    })
+}
+
+/// Returns the challenge with ID `id`, if exists.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `id` - The challenge ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+#[must_use]
+pub fn get(ctx: &Context, id: i64) -> Option<Challenge> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.query_row(
+        "SELECT invitation, user, kind, answer, expires_at, solved FROM challenges WHERE id = ?",
+        params![id],
+        |row| {
+            Ok(Challenge {
+                id,
+                invitation: row.get(0)?,
+                user: row.get(1)?,
+                kind: row.get(2)?,
+                answer: row.get(3)?,
+                expires_at: row.get(4)?,
+                solved: row.get(5)?,
+            })
+        },
+    )
+    .ok()
+}
+
+/// Returns the last challenge of the `user` that has to be answered by writing a message,
+/// if it's still waiting for the answer.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `user` - The invitee
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+#[must_use]
+pub fn waiting_text(ctx: &Context, user: i64) -> Option<Challenge> {
+    let id = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.query_row(
+            "SELECT id FROM challenges WHERE user = ? AND kind = 'arithmetic' \
+            AND solved IS NULL ORDER BY id DESC LIMIT 1",
+            params![user],
+            |row| row.get(0),
+        )
+        .ok()
+    };
+    id.and_then(|id| get(ctx, id))
+}
+
+/// Closes the `challenge` and, if not `solved`, rejects its invitation with the `reason`.
+/// Returns true if the challenge was waiting for the answer, false if it was already closed.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `challenge` - The challenge to close
+/// * `solved` - Whether the challenge has been solved
+/// * `reason` - The reason of the rejection, used only if not `solved`
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+fn close(
+    ctx: &Context,
+    challenge: &Challenge,
+    solved: bool,
+    reason: &str,
+) -> rusqlite::Result<bool> {
+    let changed = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.execute(
+            "UPDATE challenges SET solved = ? WHERE id = ? AND solved IS NULL",
+            params![solved, challenge.id],
+        )?
+    };
+    if changed == 0 {
+        return Ok(false);
+    }
+    if !solved {
+        invitations::set_status(
+            ctx,
+            challenge.invitation,
+            InvitationStatus::Rejected,
+            Some(reason),
+        )?;
+    }
+    Ok(true)
+}
+
+/// Possible outcomes of an answer to a challenge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The answer is correct: the invitation can proceed
+    Solved,
+    /// The answer is wrong: the invitation has been rejected
+    Failed,
+    /// The answer arrived too late: the invitation has been rejected
+    Expired,
+    /// The challenge was already closed: nothing changed
+    Closed,
+}
+
+/// Checks the `answer` to the `challenge` and closes it. Failed and expired challenges reject
+/// the invitation. Only the first answer is considered.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `challenge` - The challenge being answered
+/// * `answer` - The answer of the invitee
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+pub fn check(ctx: &Context, challenge: &Challenge, answer: &str) -> rusqlite::Result<Outcome> {
+    let (outcome, solved, reason) = if Utc::now() > challenge.expires_at {
+        (Outcome::Expired, false, "challenge expired")
+    } else if answer.trim() == challenge.answer {
+        (Outcome::Solved, true, "")
+    } else {
+        (Outcome::Failed, false, "challenge failed")
+    };
+    if close(ctx, challenge, solved, reason)? {
+        Ok(outcome)
+    } else {
+        Ok(Outcome::Closed)
+    }
+}
+
+/// Closes the `challenge`, if still waiting for the answer, rejecting its invitation.
+/// Returns true if the challenge expired now, false if it was already closed.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `challenge` - The challenge
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+pub fn expire(ctx: &Context, challenge: &Challenge) -> rusqlite::Result<bool> {
+    close(ctx, challenge, false, "challenge expired")
+}
+
+/// Closes the challenges still waiting for an answer after their window, rejecting their
+/// invitations, and tells the invitees. The answers arriving too late are already rejected by
+/// `check`: this catches the invitees that never answered. Invoked periodically by the
+/// scheduler.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn sweep(ctx: &Context) {
+    let stale: Vec<i64> = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT id FROM challenges WHERE solved IS NULL AND expires_at < ?")
+            .unwrap();
+        let ids = stmt
+            .query_map(params![Utc::now()], |row| row.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        ids
+    };
+    for challenge in stale.into_iter().filter_map(|id| get(ctx, id)) {
+        match expire(ctx, &challenge) {
+            Ok(true) => {
+                let reply = SendMessage::new(
+                    challenge.user,
+                    "Time is up: the invitation has been rejected.",
+                );
+                if let Err(err) = ctx.api.send_message(reply).await {
+                    error!("[challenge expired] {err}");
+                }
+            }
+            Ok(false) => {}
+            Err(err) => error!("[expire challenge] {err}"),
+        }
+    }
+}
@@ -15,7 +15,9 @@
 use log::{error, info};
 use rusqlite::params;
 use telexide_fork::{
-    api::types::{CreateChatInviteLink, GetChat, GetChatAdministrators, SendMessage},
+    api::types::{
+        CreateChatInviteLink, GetChat, GetChatAdministrators, GetChatMember, SendMessage,
+    },
     model::{AdministratorMemberStatus, Chat, ChatMember},
     prelude::*,
 };
@@ -54,6 +56,34 @@ pub fn get_all(ctx: &Context, user_id: i64) -> Vec<Channel> {
     channels
 }
 
+/// Returns the channel with ID `id`, if registered.
+///
+/// # Arguments:
+/// * `ctx` - Telexide `Context`
+/// * `id` - The channel ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+#[must_use]
+pub fn get(ctx: &Context, id: i64) -> Option<Channel> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.query_row(
+        "SELECT link, name, registered_by FROM channels WHERE id = ?",
+        params![id],
+        |row| {
+            Ok(Channel {
+                id,
+                link: row.get(0)?,
+                name: row.get(1)?,
+                registered_by: row.get(2)?,
+            })
+        },
+    )
+    .ok()
+}
+
 /// Returns all the admins of the `chat_id`. In case of errors sends a message to the `user_id`
 /// and logs with `error!`.
 ///
@@ -97,6 +127,45 @@ pub async fn admins(ctx: &Context, chat_id: i64, user_id: i64) -> Vec<Administra
         .collect()
 }
 
+/// Returns true if the `member` status means that the user is inside the chat.
+///
+/// NOTE: getChatMember always returns a `ChatMember`, even if the user never joined the chat,
+/// hence the type of the `ChatMember` must be checked.
+///
+/// # Arguments
+/// * `member` - The `ChatMember` returned by getChatMember
+#[must_use]
+pub fn joined(member: &ChatMember) -> bool {
+    match member {
+        ChatMember::Administrator(_)
+        | ChatMember::Creator(_)
+        | ChatMember::Member(_)
+        | ChatMember::Restricted(_) => true,
+        ChatMember::Kicked(_) | ChatMember::Left(_) => false,
+    }
+}
+
+/// Returns true if `user_id` is a member of the chat `chat_id`. If the request to Telegram
+/// fails, the user is considered outside the chat.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chat_id` - The unique id of the group/chan under examination
+/// * `user_id` - The user to look for
+pub async fn is_member(ctx: &Context, chat_id: i64, user_id: i64) -> bool {
+    let member = ctx
+        .api
+        .get_chat_member(GetChatMember { chat_id, user_id })
+        .await;
+    match member {
+        Ok(member) => joined(&member),
+        Err(err) => {
+            error!("[is member] {err}");
+            false
+        }
+    }
+}
+
 /// Tries to register a chat identified by its `chat_id`. The chat can be
 /// - a channel
 /// - a group
@@ -0,0 +1,152 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use rusqlite::params;
+use telexide_fork::prelude::*;
+
+use crate::persistence::types::DBKey;
+use crate::telegram::audit;
+
+/// Adds to the stock `pool` of the `contest` the codes written by the owner, separated by
+/// spaces or new lines. The codes already in the stock are ignored. The action is added to the
+/// audit log.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `pool` - The stock the codes belong to
+/// * `text` - The text written by the owner
+/// * `actor` - The user adding the codes
+///
+/// # Returns
+/// The number of codes added.
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the insertion fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn add(
+    ctx: &Context,
+    contest: i64,
+    pool: &str,
+    text: &str,
+    actor: i64,
+) -> rusqlite::Result<usize> {
+    let mut added = 0;
+    {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        let mut stmt = conn
+            .prepare("INSERT OR IGNORE INTO reward_codes(contest, pool, code) VALUES(?, ?, ?)")?;
+        for code in text.split_whitespace() {
+            added += stmt.execute(params![contest, pool, code])?;
+        }
+    }
+    audit::log(
+        ctx,
+        contest,
+        actor,
+        &format!("codes {pool} +{added}"),
+        None,
+        None,
+    )?;
+    Ok(added)
+}
+
+/// Assigns to the `user` the first available code of the stock `pool` of the `contest`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `pool` - The stock to take the code from
+/// * `user` - The user receiving the code
+///
+/// # Returns
+/// The code, or `None` if the stock is empty.
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn take(
+    ctx: &Context,
+    contest: i64,
+    pool: &str,
+    user: i64,
+) -> rusqlite::Result<Option<String>> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let mut stmt = conn.prepare(
+        "UPDATE reward_codes SET assigned_to = ?, assigned_at = CURRENT_TIMESTAMP \
+        WHERE id = (SELECT id FROM reward_codes \
+        WHERE contest = ? AND pool = ? AND assigned_to IS NULL ORDER BY id ASC LIMIT 1) \
+        RETURNING code",
+    )?;
+    let mut rows = stmt.query(params![user, contest, pool])?;
+    rows.next()?.map(|row| row.get(0)).transpose()
+}
+
+/// Puts back in the stock `pool` of the `contest` the `code` assigned by `take`, e.g. because
+/// it couldn't be delivered.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `pool` - The stock the code was taken from
+/// * `code` - The code to put back
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn release(ctx: &Context, contest: i64, pool: &str, code: &str) -> rusqlite::Result<()> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.execute(
+        "UPDATE reward_codes SET assigned_to = NULL, assigned_at = NULL \
+        WHERE contest = ? AND pool = ? AND code = ?",
+        params![contest, pool, code],
+    )?;
+    Ok(())
+}
+
+/// Returns the number of available codes and the total number of codes of the stock `pool`
+/// of the `contest`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `pool` - The stock
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+#[must_use]
+pub fn stock(ctx: &Context, contest: i64, pool: &str) -> (i64, i64) {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.query_row(
+        "SELECT COUNT(*) - COUNT(assigned_to), COUNT(*) FROM reward_codes \
+        WHERE contest = ? AND pool = ?",
+        params![contest, pool],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .unwrap()
+}
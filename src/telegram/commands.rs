@@ -16,6 +16,7 @@ use data_encoding::BASE64URL;
 use log::{error, info};
 use rusqlite::params;
 use std::collections::HashMap;
+use std::fmt::Write;
 
 use telexide_fork::{
     api::types::SendMessage,
@@ -25,11 +26,11 @@ use telexide_fork::{
 };
 
 use crate::{
-    persistence::types::{Channel, DBKey, NameKey, RankContest},
+    persistence::types::{Channel, DBKey, InvitationStatus, RankContest},
     telegram::{
-        channels, contests,
+        activity, channels, contests, eligibility,
         messages::{display_main_commands, escape_markdown},
-        users,
+        milestones, participants, points, referrals, users,
     },
 };
 
@@ -45,35 +46,69 @@ use crate::{
 pub async fn rank(ctx: Context, message: Message) -> CommandResult {
     info!("rank command begin");
     let sender_id = message.from.clone().unwrap().id;
-    let rank_per_user_contest = {
+    let ids = {
         let guard = ctx.data.read();
         let map = guard.get::<DBKey>().expect("db");
         let conn = map.get().unwrap();
         let mut stmt = conn
-            .prepare(
-                "SELECT ROW_NUMBER() OVER (ORDER BY t.c, t.source DESC) AS r, t.contest
-                FROM (SELECT COUNT(*) AS c, contest, source FROM invitations GROUP BY contest, source) AS t
-                WHERE t.source = ?",
-            )
+            .prepare(&format!(
+                "SELECT contest FROM invitations WHERE source = ?1 AND {counted}
+                UNION
+                SELECT contest FROM adjustments WHERE user = ?1
+                UNION
+                SELECT contest FROM activity_messages WHERE user = ?1
+                UNION
+                SELECT contest FROM results WHERE user = ?1",
+                counted = InvitationStatus::COUNTED
+            ))
             .unwrap();
-
-        let mut iter = stmt
-            .query_map(params![sender_id], |row| {
-                Ok(RankContest {
-                    rank: row.get(0)?,
-                    c: contests::get(&ctx, row.get(1)?).unwrap(),
-                })
-            })
+        let ids = stmt
+            .query_map(params![sender_id], |row| row.get(0))
             .unwrap()
-            .peekable();
-        if iter.peek().is_some() && iter.peek().unwrap().is_ok() {
-            iter.map(std::result::Result::unwrap)
-                .collect::<Vec<RankContest>>()
-        } else {
-            vec![]
-        }
+            .map(std::result::Result::unwrap)
+            .collect::<Vec<i64>>();
+        ids
     };
 
+    // The rank of the finished contests comes from the frozen results, the others
+    // are computed from the invitations and the adjustments
+    let rank_per_user_contest = ids
+        .into_iter()
+        .filter_map(|id| contests::get(&ctx, id))
+        .filter_map(|c| {
+            let results = super::results::get(&ctx, c.id);
+            if results.is_empty() {
+                let rank = if activity::enabled(&ctx, c.id) {
+                    activity::ranking(&ctx, &c)
+                } else {
+                    contests::ranking(&ctx, &c)
+                };
+                rank.into_iter()
+                    .find(|row| row.user.id == sender_id)
+                    .map(|row| RankContest {
+                        rank: row.rank,
+                        invites: row.invites,
+                        points: row.points,
+                        indirect: row.indirect,
+                        adjustment: row.adjustment,
+                        c,
+                    })
+            } else {
+                results
+                    .into_iter()
+                    .find(|row| row.user.id == sender_id)
+                    .map(|row| RankContest {
+                        rank: row.rank,
+                        invites: row.invites,
+                        points: row.points,
+                        indirect: row.indirect,
+                        adjustment: row.adjustment,
+                        c,
+                    })
+            }
+        })
+        .collect::<Vec<RankContest>>();
+
     let text = if rank_per_user_contest.is_empty() {
         "You haven't participated in any contest yet!".to_string()
     } else {
@@ -89,7 +124,43 @@ pub async fn rank(ctx: Context, message: Message) -> CommandResult {
             } else {
                 m += &format!("#{rank}");
             }
+            let formula = points::formula(&ctx, c.id);
+            if activity::enabled(&ctx, c.id) {
+                let _ = write!(m, " - {} messages", rank_contest.points);
+            } else if formula.is_some() {
+                let _ = write!(m, " - {} points", rank_contest.points);
+            } else {
+                let _ = write!(m, " - {} invites", rank_contest.invites);
+            }
+            if rank_contest.indirect > 0.0 {
+                let _ = write!(m, " + {} from referrals", rank_contest.indirect);
+            }
+            if rank_contest.adjustment != 0 {
+                let _ = write!(m, " ({:+} adjusted)", rank_contest.adjustment);
+            }
             m += "\n";
+            if let Some(f) = formula {
+                let (lines, _) = points::explain(&f, &points::breakdown(&ctx, c.id, sender_id));
+                for line in lines {
+                    let _ = writeln!(m, "  {line}");
+                }
+            }
+            if rank_contest.indirect > 0.0 {
+                for level in referrals::breakdown(&ctx, &c, sender_id) {
+                    let _ = writeln!(
+                        m,
+                        "  Level {}: {} invites, {} points",
+                        level.level, level.invites, level.points
+                    );
+                }
+            }
+            for achievement in milestones::achieved(&ctx, c.id, sender_id) {
+                let _ = writeln!(
+                    m,
+                    "  \u{1f3c5} {} invites: {}",
+                    achievement.milestone.threshold, achievement.milestone.reward
+                );
+            }
         }
         m
     };
@@ -106,6 +177,54 @@ pub async fn rank(ctx: Context, message: Message) -> CommandResult {
     Ok(())
 }
 
+/// Results command. Shows to the user the official results of the finished contests he/she
+/// joined, or of the contests of his/her channels.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `message` - Received message with the command inside
+///
+/// # Panics
+/// Panics if the connection to the db fails, or if telegram servers return error.
+#[command(description = "Official results of the finished contests")]
+pub async fn results(ctx: Context, message: Message) -> CommandResult {
+    info!("results command begin");
+    let sender_id = message.from.clone().unwrap().id;
+    let finished = super::results::visible_to(&ctx, sender_id);
+
+    let text = if finished.is_empty() {
+        "There are no finished contests to show!".to_string()
+    } else {
+        let mut m = "Official results\n\n".to_string();
+        for c in finished {
+            let results = super::results::get(&ctx, c.id);
+            let _ = writeln!(m, "\u{1f3c6} Contest \"{}({})\"", c.name, c.end);
+            if results.is_empty() {
+                m += "No one partecipated.\n\n";
+                continue;
+            }
+            let top = results.iter().take(10).cloned().collect::<Vec<_>>();
+            m += &super::results::chart(&top, super::fairness::drawn(&ctx, c.id));
+            if let Some(own) = results.iter().find(|r| r.user.id == sender_id) {
+                let _ = writeln!(m, "Your position: #{} - {}", own.rank, own.score);
+            }
+            m += "\n";
+        }
+        m
+    };
+    let mut reply = SendMessage::new(sender_id, &escape_markdown(&text, None));
+    reply.set_parse_mode(&ParseMode::MarkdownV2);
+    let res = ctx.api.send_message(reply).await;
+    if res.is_err() {
+        let err = res.err().unwrap();
+        error!("[results] {err}");
+    }
+
+    display_main_commands(&ctx, sender_id).await;
+    info!("results command end");
+    Ok(())
+}
+
 /// Help command. Shows to the user the help menu with the complete command list.
 ///
 /// # Arguments
@@ -123,6 +242,7 @@ pub async fn help(ctx: Context, message: Message) -> CommandResult {
         /list - List your registered groups/channels\n\
         /contest - Start/Manage the referral contest\n\
         /rank - Your rank in the challenges you joined\n\
+        /results - Official results of the finished contests\n\
         /help - This menu",
         None,
     );
@@ -378,39 +498,47 @@ pub async fn start(ctx: Context, message: Message) -> CommandResult {
         } else if user.is_none() && channel.is_some() && c.is_some() {
             let chan = channel.unwrap();
             let c = c.unwrap();
-            let bot_name = {
-                let guard = ctx.data.read();
-                guard
-                    .get::<NameKey>()
-                    .expect("name")
-                    .clone()
-                    .replace('@', "")
-            };
-            let params = BASE64URL.encode(
-                format!(
-                    "chan={}&contest={}&source={}",
-                    chan.id,
-                    c.id,
-                    message.from.unwrap().id
-                )
-                .as_bytes(),
-            );
-            let invite_link = format!("https://t.me/{bot_name}?start={params}");
-
-            let text = &escape_markdown(
-                &format!(
-                    "Thank you for joining the {contest_name} contest!\n\
-            Here's the link to use for inviting your friends to join {chan_name}:\n\n\
-            \u{1f449}\u{1f3fb}{invite_link}",
-                    contest_name = c.name,
-                    chan_name = chan.name,
-                    invite_link = invite_link
-                ),
-                None,
-            );
-            let mut reply = SendMessage::new(sender_id, text);
-            reply.set_parse_mode(&ParseMode::MarkdownV2);
-            ctx.api.send_message(reply).await?;
+            if let Some(reason) = participants::disqualification(&ctx, c.id, sender_id) {
+                ctx.api
+                    .send_message(SendMessage::new(
+                        sender_id,
+                        &format!(
+                            "You have been disqualified from the {} contest.\n\nReason: {reason}",
+                            c.name
+                        ),
+                    ))
+                    .await?;
+                return Ok(());
+            }
+            let from = message.from.clone().unwrap();
+            if let Err(reason) = eligibility::check_participant(&ctx, &chan, &c, &from).await {
+                ctx.api
+                    .send_message(SendMessage::new(
+                        sender_id,
+                        &format!("You can't participate in the {} contest: {reason}", c.name),
+                    ))
+                    .await?;
+                return Ok(());
+            }
+            match participants::register(&ctx, c.id, sender_id) {
+                Ok(None) => participants::send_link(&ctx, &chan, &c, sender_id).await?,
+                Ok(Some(position)) => {
+                    ctx.api
+                        .send_message(SendMessage::new(
+                            sender_id,
+                            &format!(
+                                "The {} contest is full: you are #{position} in the waitlist. \
+                                You will receive your link as soon as a slot is free.",
+                                c.name
+                            ),
+                        ))
+                        .await?;
+                }
+                Err(err) => {
+                    error!("[register participant] {err}");
+                    return Err(CommandError(err.to_string()));
+                }
+            }
         }
     } else {
         // Case in which no parameter are present
@@ -13,15 +13,56 @@
 // limitations under the License.
 
 use chrono::{DateTime, Utc};
+use data_encoding::BASE64URL;
 use log::error;
-use rusqlite::params;
-use telexide_fork::{api::types::GetChatMember, prelude::*};
+use rusqlite::{params, Transaction};
+use std::convert::TryFrom;
+use std::fmt::Write;
+use telexide_fork::{
+    api::types::{PinChatMessage, SendMessage},
+    model::{InlineKeyboardMarkup, ParseMode, ReplyMarkup},
+    prelude::*,
+};
 
-use crate::persistence::types::{Contest, DBKey, Rank};
-use crate::telegram::users;
+use crate::persistence::types::{
+    Channel, Contest, ContestResult, DBKey, InvitationStatus, NameKey, Rank,
+};
+use crate::telegram::messages::escape_markdown;
+use crate::telegram::{
+    activity, caps, channels, fairness, fulfillment, giveaway, invitations, invitee_rewards,
+    leaderboard, networks, points, prizes, raffle, referrals, relay, results, sponsors, users,
+};
 
 use std::string::ToString;
 
+/// Tables of the rules and of the history of the management of a contest: their rows are
+/// deleted together with the contest.
+const OWNED: &[&str] = &[
+    "contest_settings",
+    "audit_log",
+    "being_managed_contests",
+    "allowlists",
+    "milestones",
+    "point_rules",
+    "invitee_rewards",
+    "contest_channels",
+    "sponsors",
+    "reward_codes",
+    "draw_seeds",
+    "leaderboards",
+    "template_instances",
+];
+
+/// Tables of the participation in a contest: a contest with participants can't be deleted,
+/// that would be unfair.
+const PARTICIPATION: &[&str] = &[
+    "invitations",
+    "contest_participants",
+    "giveaway_entries",
+    "activity_messages",
+    "results",
+];
+
 /// Returns the `Contest` with the specified `id`, if exists.
 ///
 /// # Arguments
@@ -51,8 +92,7 @@ pub fn get(ctx: &Context, id: i64) -> Option<Contest> {
             })
         })
         .unwrap();
-    let c = iter.next().unwrap();
-    if let Ok(c) = c {
+    if let Some(Ok(c)) = iter.next() {
         return Some(c);
     }
     None
@@ -95,8 +135,72 @@ pub fn get_all(ctx: &Context, chan: i64) -> Vec<Contest> {
     contests
 }
 
-/// Returns rank for the `contest`, already oredered by number of invites accepted in descending
-/// order.
+/// Deletes the contest with ID `id` of the channel `chan`, together with its rules and the
+/// history of its management.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `id` - The contest ID
+/// * `chan` - The channel of the contest
+///
+/// # Errors
+/// Returns the reason why the contest can't be deleted, ready to be shown to the owner: it has
+/// participants, it's the model of a recurring contest, or the deletion failed. In this case
+/// nothing is deleted.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn delete(ctx: &Context, id: i64, chan: i64) -> Result<(), String> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let mut conn = map.get().unwrap();
+    let tx = conn.transaction().map_err(|err| err.to_string())?;
+    delete_tx(&tx, id, chan)?;
+    tx.commit().map_err(|err| err.to_string())
+}
+
+/// Same as `delete`, as part of the transaction `tx` of the caller.
+///
+/// # Errors
+/// Returns the reason why the contest can't be deleted.
+pub(crate) fn delete_tx(tx: &Transaction, id: i64, chan: i64) -> Result<(), String> {
+    let exists = |query: &str| {
+        tx.query_row(&format!("SELECT EXISTS({query})"), params![id], |row| {
+            row.get::<_, bool>(0)
+        })
+        .map_err(|err| err.to_string())
+    };
+    // Only the contests of the channel
+    if !exists(&format!(
+        "SELECT 1 FROM contests WHERE id = ?1 AND chan = {chan}"
+    ))? {
+        return Err("the contest doesn't exist anymore".to_string());
+    }
+    for table in PARTICIPATION {
+        if exists(&format!("SELECT 1 FROM {table} WHERE contest = ?1"))? {
+            return Err("the contest has participants, deleting it would be unfair".to_string());
+        }
+    }
+    if exists("SELECT 1 FROM contest_templates WHERE contest = ?1")? {
+        return Err("it's the model of a recurring contest".to_string());
+    }
+    for table in OWNED {
+        tx.execute(
+            &format!("DELETE FROM {table} WHERE contest = ?"),
+            params![id],
+        )
+        .map_err(|err| err.to_string())?;
+    }
+    tx.execute("DELETE FROM contests WHERE id = ?", params![id])
+        .map_err(|err| err.to_string())?;
+    Ok(())
+}
+
+/// Returns rank for the `contest`, already oredered by score in descending order.
+/// The score of a participant is the points of its invitations (by default, the credit of its
+/// invitations with a status in `InvitationStatus::COUNTED`), plus the weighted credit of the
+/// invitations of its invitees (when the multi-level referrals are enabled), plus the sum of
+/// its manual adjustments. The adjustments of the disqualified participants don't count.
 ///
 /// # Arguments
 /// * `ctx` - Telexide context
@@ -106,24 +210,64 @@ pub fn get_all(ctx: &Context, chan: i64) -> Vec<Contest> {
 /// Panics if the connection to the DB fails, or if the returned data is corrupt.
 #[must_use]
 pub fn ranking(ctx: &Context, contest: &Contest) -> Vec<Rank> {
+    let (levels, weight) = referrals::config(ctx, contest.id);
+    let f = points::formula(ctx, contest.id).unwrap_or_default();
     let guard = ctx.data.read();
     let map = guard.get::<DBKey>().expect("db");
     let conn = map.get().unwrap();
-    // NOTE: the ordering ALSO via t.source is required to give a meaningful order (depending on
-    // the id, hence jsut to have them different) in case of equal rank
+    // NOTE: in case of equal score, the user who reached it first (hence with the
+    // oldest last invitation) wins. The ordering ALSO via t.source is required to give a
+    // meaningful order in the (unlikely) case of invitations received in the same second.
+    // Participants credited only by adjustments have no last invitation: the date of the
+    // last adjustment is used instead.
     let mut stmt = conn
-            .prepare(
-                "SELECT ROW_NUMBER() OVER (ORDER BY t.c, t.source DESC) AS r, t.c, t.source
-                FROM (SELECT COUNT(*) AS c, source FROM invitations WHERE contest = ? GROUP BY source) AS t",
-            )
-            .unwrap();
-    stmt.query_map(params![contest.id], |row| {
-        Ok(Rank {
-            rank: row.get(0)?,
-            invites: row.get(1)?,
-            user: users::get(ctx, row.get(2)?).unwrap(),
-        })
-    })
+        .prepare(&format!(
+            "{graph}
+            SELECT ROW_NUMBER() OVER (ORDER BY t.p + t.i + t.a DESC, t.last ASC, t.source ASC) AS r,
+            t.c, t.p, t.i, t.a, t.p + t.i + t.a, t.source, t.last
+            FROM (SELECT SUM(c) AS c, SUM(p) AS p, SUM(i) AS i, SUM(a) AS a, COALESCE(MAX(last), MAX(adjusted)) AS last, source FROM (
+                SELECT SUM(CASE WHEN level = 1 THEN credit ELSE 0.0 END) AS c, 0.0 AS p,
+                SUM(CASE WHEN level > 1 THEN credit ELSE 0.0 END) AS i, 0 AS a,
+                MAX(CASE WHEN level = 1 THEN date END) AS last, NULL AS adjusted, root AS source
+                FROM tree GROUP BY root
+                UNION ALL
+                SELECT 0.0 AS c, {points} AS p, 0.0 AS i, 0 AS a, NULL AS last, NULL AS adjusted, source
+                FROM invitations WHERE contest = ?1 GROUP BY source
+                UNION ALL
+                SELECT 0.0 AS c, 0.0 AS p, 0.0 AS i, SUM(points) AS a, NULL AS last, MAX(date) AS adjusted, user AS source
+                FROM adjustments WHERE contest = ?1
+                AND user NOT IN (SELECT user FROM disqualified_participants WHERE contest = ?1)
+                GROUP BY user
+            ) GROUP BY source) AS t
+            WHERE t.p + t.i + t.a > 0
+            ORDER BY r",
+            graph = referrals::graph(),
+            points = points::sql()
+        ))
+        .unwrap();
+    stmt.query_map(
+        params![
+            contest.id,
+            levels,
+            weight,
+            f.base,
+            f.member_bonus,
+            f.active_bonus,
+            f.leave_penalty
+        ],
+        |row| {
+            Ok(Rank {
+                rank: row.get(0)?,
+                invites: row.get(1)?,
+                points: row.get(2)?,
+                indirect: row.get(3)?,
+                adjustment: row.get(4)?,
+                score: row.get(5)?,
+                user: users::get(ctx, row.get(6)?).unwrap(),
+                last_invite: row.get(7)?,
+            })
+        },
+    )
     .unwrap()
     .map(std::result::Result::unwrap)
     .collect::<Vec<Rank>>()
@@ -236,7 +380,10 @@ pub fn count_users(ctx: &Context, contest: &Contest) -> i64 {
     let map = guard.get::<DBKey>().expect("db");
     let conn = map.get().unwrap();
     let mut stmt = conn
-        .prepare("SELECT COUNT(id) FROM invitations WHERE contest = ?")
+        .prepare(&format!(
+            "SELECT COUNT(id) FROM invitations WHERE contest = ? AND {}",
+            InvitationStatus::COUNTED
+        ))
         .unwrap();
     let vals = stmt
         .query_map(params![contest.id], |row| {
@@ -251,9 +398,13 @@ pub fn count_users(ctx: &Context, contest: &Contest) -> i64 {
     vals[0]
 }
 
-/// Function to call to verify that the joined users are still in the channel.
+/// Function to call to verify that the joined users are still in the channel, and in the
+/// partner chats of the contest.
+/// The counted invitations of the users still in the channel become `Qualified`, the others
+/// become `Left`: nothing is deleted, so the invitation history is preserved.
 /// NOTE: this function is async because it uses the async `ctx.api.get_chat_member`
-/// function to check if the user is still inside the channel referenced by the `contest`.
+/// function to check if the user is still inside the channel referenced by the invitation:
+/// in a multi-channel contest, every invitee is checked against the channel it joined.
 ///
 /// # Arguments
 /// * `ctx`: The Telexide context, used to get the db
@@ -262,45 +413,429 @@ pub fn count_users(ctx: &Context, contest: &Contest) -> i64 {
 /// # Panics
 /// Panics if the connection to the DB fails, or if the returned data is corrupt.
 pub async fn validate_users(ctx: &Context, contest: &Contest) {
-    struct InnerUser {
-        id: i64,
+    let invites = invitations::get_all(ctx, contest.id, InvitationStatus::COUNTED);
+    for invite in invites {
+        // The invitees must still be members of the partner chats too
+        let in_channel = channels::is_member(ctx, invite.chan, invite.dest).await
+            && sponsors::missing(ctx, contest.id, invite.dest)
+                .await
+                .is_empty();
+        let res = if in_channel {
+            let res = invitations::set_status(ctx, invite.id, InvitationStatus::Qualified, None);
+            if res.is_ok() {
+                invitee_rewards::deliver(ctx, contest, &invite).await;
+            }
+            res
+        } else {
+            invitations::set_status(
+                ctx,
+                invite.id,
+                InvitationStatus::Left,
+                Some("not a member when the contest finished"),
+            )
+        };
+        if res.is_err() {
+            error!("[users validation] {}", res.err().unwrap());
+        }
+    }
+}
+
+/// Sends the message `reply` and pins it on top of its chat. If the bot can't pin the
+/// message, the error is forwarded to the `owner`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `reply` - The message to send
+/// * `owner` - The user to inform if the pin fails
+///
+/// # Returns
+/// True if the message has been sent, even if it hasn't been pinned.
+async fn post_and_pin(ctx: &Context, reply: SendMessage, owner: i64) -> bool {
+    let chat_id = reply.chat_id;
+    match ctx.api.send_message(reply).await {
+        Err(err) => {
+            error!("[send message] {err}");
+            false
+        }
+        Ok(message) => {
+            let res = ctx
+                .api
+                .pin_chat_message(PinChatMessage {
+                    chat_id,
+                    message_id: message.message_id,
+                    disable_notification: false,
+                })
+                .await;
+            if let Err(err) = res {
+                error!("[pin message] {err}");
+                let reply = SendMessage::new(owner, &err.to_string());
+                if let Err(err) = ctx.api.send_message(reply).await {
+                    error!("[pin message2] {err}");
+                }
+            }
+            true
+        }
     }
-    let users = {
+}
+
+/// Builds the announcement of the referral contest `c`, that invites the users to start
+/// the bot from the deep link with the parameters `params`.
+///
+/// # Arguments
+/// * `c` - The contest
+/// * `raffle` - Whether the winners are drawn
+/// * `limits` - The rules of the contest, already escaped
+/// * `bot_name` - The username of the bot
+/// * `params` - The encoded parameters of the deep link
+fn announcement(c: &Contest, raffle: bool, limits: &str, bot_name: &str, params: &str) -> String {
+    let title = escape_markdown(
+        &format!(
+            "\u{1f525}{name} contest \u{1f525}\n{who} a {prize}!",
+            who = if raffle {
+                "Every friend you invite is a ticket to win"
+            } else {
+                "Who invites more friends wins"
+            },
+            prize = c.prize,
+            name = c.name
+        ),
+        None,
+    );
+    let steps = escape_markdown(
+        &format!(
+            "1. Start the contest bot using the link below\n\
+            2. The bot gives you a link\n\
+            3. Share the link with your friends!\n\n\
+            At the end of the contest ({end_date}) {who} will win a ",
+            end_date = c.end,
+            who = if raffle {
+                "the users drawn among the participants"
+            } else {
+                "the user that referred more friends"
+            }
+        ),
+        None,
+    );
+    let rules = format!(
+        "{steps} **{prize}**\n{limits}{disclaimer}",
+        prize = escape_markdown(&c.prize, None),
+        disclaimer = escape_markdown("You can check your rank with the /rank command", None),
+    );
+    let bot_link = escape_markdown(&format!("https://t.me/{bot_name}?start={params}"), None);
+    format!("{title}\n\n{rules}\n\n{bot_link}")
+}
+
+/// Starts the contest `contest_id` of the channel `chan`: the announcement is posted and pinned
+/// in every channel of the contest, and the `owner` is informed.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chan` - The channel of the contest
+/// * `contest_id` - The contest ID
+/// * `owner` - The owner of the contest
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn start(ctx: &Context, chan: &Channel, contest_id: i64, owner: i64) {
+    let c = {
+        let now: DateTime<Utc> = Utc::now();
         let guard = ctx.data.read();
         let map = guard.get::<DBKey>().expect("db");
         let conn = map.get().unwrap();
         let mut stmt = conn
-            .prepare("SELECT dest FROM invitations WHERE contest = ?")
+            .prepare("UPDATE contests SET started_at = ? WHERE id = ? RETURNING name, prize, end")
+            .unwrap();
+        let mut iter = stmt
+            .query_map(params![now, contest_id], |row| {
+                Ok(Contest {
+                    id: contest_id,
+                    name: row.get(0)?,
+                    prize: row.get(1)?,
+                    end: row.get(2)?,
+                    started_at: Some(now),
+                    stopped: false,
+                    chan: chan.id,
+                })
+            })
             .unwrap();
-        stmt.query_map(params![contest.id], |row| Ok(InnerUser { id: row.get(0)? }))
-            .unwrap()
-            .map(|user| user.unwrap().id)
-            .collect::<Vec<i64>>()
+        iter.next().unwrap()
     };
+    let text = match &c {
+        Err(err) => {
+            error!("[update/start contest] {err}");
+            err.to_string()
+        }
+        Ok(_) => "Contest started!".to_string(),
+    };
+    let res = ctx.api.send_message(SendMessage::new(owner, &text)).await;
+    if let Err(err) = res {
+        error!("[send message] {err}");
+    }
 
-    for user in users {
-        let member = ctx
-            .api
-            .get_chat_member(GetChatMember {
-                chat_id: contest.chan,
-                user_id: user,
-            })
-            .await;
-
-        let in_channel = member.is_ok();
-        if !in_channel {
-            let res = {
-                let guard = ctx.data.read();
-                let map = guard.get::<DBKey>().expect("db");
-                let conn = map.get().unwrap();
-                let mut stmt = conn
-                    .prepare("DELETE FROM invitations WHERE dest = ? and contest = ?")
-                    .unwrap();
-                stmt.execute(params![user, contest.id])
+    if let Ok(c) = c {
+        // Send message in the channel, indicating the contest name
+        // the end date, the prize, and pin it on top until the end date comes
+        // or the contest is stopped or deleted
+        let bot_name = {
+            let guard = ctx.data.read();
+            guard
+                .get::<NameKey>()
+                .expect("name")
+                .clone()
+                .replace('@', "")
+        };
+        // The seed of a random draw is committed now, and used only if the commitment
+        // reaches the announcement
+        let raffle = raffle::enabled(ctx, c.id);
+        let mut commitment = None;
+        if raffle {
+            match fairness::commit(ctx, c.id) {
+                Ok(hash) => commitment = Some(hash),
+                Err(err) => error!("[commit seed] {err}"),
+            }
+        }
+        let limits: String = raffle::rule(ctx, &c)
+            .into_iter()
+            .chain(points::rule(ctx, &c))
+            .chain(referrals::rule(ctx, &c))
+            .chain(caps::rules(ctx, &c))
+            .chain(networks::rule(ctx, &c))
+            .chain(sponsors::rule(ctx, &c))
+            .chain(
+                commitment
+                    .as_ref()
+                    .map(|hash| format!("Draw commitment (SHA-256): {hash}")),
+            )
+            .map(|rule| escape_markdown(&format!("\u{2022} {rule}\n"), None))
+            .collect();
+        // A giveaway is entered from the announcement and an activity contest is won by
+        // writing in the group: both without referral links
+        let giveaway = giveaway::enabled(ctx, c.id);
+        let activity = activity::enabled(ctx, c.id);
+        let mut published = false;
+        // The announcement is posted and pinned in every channel of the contest: the
+        // referral links started from a channel invite to that channel
+        for target in networks::channels(ctx, &c) {
+            let params =
+                BASE64URL.encode(format!("chan={}&contest={}", target.id, c.id).as_bytes());
+            let text = if giveaway {
+                giveaway::announcement(ctx, &c, commitment.as_deref())
+            } else if activity {
+                activity::announcement(ctx, &c)
+            } else {
+                announcement(&c, raffle, &limits, &bot_name, &params)
             };
-            if res.is_err() {
-                error!("[users validation] {}", res.err().unwrap());
+            let mut reply = SendMessage::new(target.id, &text);
+            reply.set_parse_mode(&ParseMode::MarkdownV2);
+            if giveaway {
+                reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+                    inline_keyboard: giveaway::keyboard(c.id, target.id),
+                }));
+            }
+            published |= post_and_pin(ctx, reply, owner).await;
+        }
+        if published && commitment.is_some() {
+            if let Err(err) = fairness::publish(ctx, c.id) {
+                error!("[publish commitment] {err}");
+            }
+        }
+    }
+}
+
+/// Builds the message with the final chart of the contest `c`, already escaped.
+///
+/// # Arguments
+/// * `c` - The contest
+/// * `results` - The frozen results
+/// * `winners` - The number of winners
+/// * `raffle` - Whether the winners have been drawn
+fn outcome(c: &Contest, results: &[ContestResult], winners: usize, raffle: bool) -> String {
+    let mut m = format!("\u{1f3c6} Contest ({}) finished \u{1f3c6}\n\n\n", c.name);
+    m += &results::chart(results, raffle);
+    if results.iter().any(|row| row.adjustment != 0) {
+        m += "\n(adjusted: credit manually changed by the owner)";
+    }
+    if raffle {
+        let _ = write!(
+            m,
+            "\n\nThe winners have been drawn at random, weighted by their tickets. \
+            The prize ({}) is being delivered to the {winners} winners \u{1f947}. Congratulations!!",
+            c.prize
+        );
+    } else {
+        let _ = write!(
+            m,
+            "\n\nThe prize ({}) is being delivered to our champion \u{1f947}. Congratulations!!",
+            c.prize
+        );
+    }
+    escape_markdown(&m, None)
+}
+
+/// Ranks the participants of the contest `c` and freezes the results: from now on, they
+/// are the official outcome. In the raffle mode, the winners are drawn with the seed
+/// committed at the start. Without a published commitment the draw can't be verified: the
+/// results follow the ranking, and the `owner` is told why.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+///
+/// # Returns
+/// The proof of the draw, `None` if there was no draw.
+async fn freeze(ctx: &Context, c: &Contest, owner: i64) -> Option<Vec<String>> {
+    let mut rank = if giveaway::enabled(ctx, c.id) {
+        giveaway::ranking(ctx, c).await
+    } else if activity::enabled(ctx, c.id) {
+        activity::ranking(ctx, c)
+    } else {
+        ranking(ctx, c)
+    };
+    let mut proof = None;
+    if raffle::enabled(ctx, c.id) {
+        // The seed has been committed when the contest started: it's revealed now
+        if let Some((seed, _)) = fairness::seed(ctx, c.id) {
+            let winners = raffle::winners(ctx, c.id);
+            let entries = raffle::entries(&rank);
+            proof = Some(fairness::proof(
+                &seed,
+                usize::try_from(winners).unwrap_or(0),
+                &entries,
+            ));
+            rank = raffle::draw(rank, winners, &seed);
+        } else {
+            let text = "No draw commitment has been published when the contest started (the \
+                mode has been changed afterwards): the winners can't be drawn verifiably, \
+                hence they follow the ranking.";
+            let res = ctx.api.send_message(SendMessage::new(owner, text)).await;
+            if let Err(err) = res {
+                error!("[no commitment send] {err}");
             }
         }
     }
+    if let Err(err) = results::save(ctx, c, &rank) {
+        error!("[save results] {err}");
+    }
+    proof
+}
+
+/// Stops the contest `c` of the channel `chan`: the invitees are validated, the results are
+/// frozen and posted in every channel of the contest, and the `owner` is put in contact with
+/// the winners.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chan` - The channel of the contest
+/// * `c` - The contest
+/// * `owner` - The owner of the contest
+///
+/// # Returns
+/// False if no one participated, hence there are no results to publish.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn stop(ctx: &Context, chan: &Channel, c: &Contest, owner: i64) -> bool {
+    validate_users(ctx, c).await;
+
+    // Stop contest on db
+    let c = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        let mut stmt = conn.prepare("UPDATE contests SET stopped = TRUE WHERE id = ? RETURNING name, prize, end, started_at").unwrap();
+        let mut iter = stmt
+            .query_map(params![c.id], |row| {
+                Ok(Contest {
+                    id: c.id,
+                    name: row.get(0)?,
+                    prize: row.get(1)?,
+                    end: row.get(2)?,
+                    started_at: row.get(3)?,
+                    stopped: true,
+                    chan: chan.id,
+                })
+            })
+            .unwrap();
+        iter.next().unwrap().unwrap()
+    };
+
+    let proof = freeze(ctx, &c, owner).await;
+    let drawn = proof.is_some();
+    let proof = proof.unwrap_or_default();
+    let results = results::get(ctx, c.id);
+    if results.is_empty() {
+        // No one partecipated in the challenge
+        let reply = SendMessage::new(
+            owner,
+            "No one partecipated to the challenge. Doing nothing.",
+        );
+        let res = ctx.api.send_message(reply).await;
+        if let Err(err) = res {
+            error!("[stop send] {err}");
+        }
+        let text = format!("\u{1f3c6} Contest ({}) finished: no participants.", c.name);
+        leaderboard::finish(ctx, &c, &escape_markdown(&text, None)).await;
+        return false;
+    }
+
+    let winners = results
+        .iter()
+        .filter(|row| row.prize.is_some())
+        .map(|row| row.user.clone())
+        .collect::<Vec<_>>();
+    let m = outcome(&c, &results, winners.len(), drawn);
+    // The live leaderboards become the results
+    leaderboard::finish(ctx, &c, &m).await;
+
+    // The results are posted and pinned in every channel of the contest
+    for target in networks::channels(ctx, &c) {
+        let mut reply = SendMessage::new(target.id, &m);
+        reply.set_parse_mode(&ParseMode::MarkdownV2);
+        post_and_pin(ctx, reply, owner).await;
+
+        // The proof of the draw, to verify it offline
+        for text in &proof {
+            let res = ctx
+                .api
+                .send_message(SendMessage::new(target.id, text))
+                .await;
+            if let Err(err) = res {
+                error!("[proof send] {err}");
+            }
+        }
+    }
+    if drawn {
+        if let Err(err) = fairness::reveal(ctx, c.id) {
+            error!("[reveal seed] {err}");
+        }
+    }
+
+    // The participants ranked in the tiers with a stock of codes receive their code
+    // privately, the other prizes are delivered by the owner
+    let delivered = prizes::deliver(ctx, chan, &c, &results).await;
+    fulfillment::open(ctx, &c, &results, &delivered).await;
+
+    // Put into communication the bot user and the winners
+    for winner in winners {
+        let code_sent = delivered.contains(&winner.id);
+        let name = users::display_name(&winner);
+        let text = if code_sent {
+            format!("The prize code has been sent privately to the winner {name}.")
+        } else if let Some(username) = winner.username {
+            format!("The winner usename is @{username}. Get in touch and send the prize!")
+        } else {
+            format!(
+                "The winner {name} has no username: you can communicate only through \
+                the bot."
+            )
+        };
+        let res = ctx.api.send_message(SendMessage::new(owner, &text)).await;
+        if let Err(err) = res {
+            error!("[stop send] {err}");
+        }
+        if !code_sent {
+            relay::start(ctx, chan, &c, winner.id).await;
+        }
+    }
+    true
 }
@@ -0,0 +1,274 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use log::error;
+use rusqlite::params;
+use telexide_fork::{api::APIEndpoint, model::User, prelude::*};
+
+use crate::persistence::types::{Channel, Contest, DBKey, InvitationStatus, OperatorKey};
+use crate::telegram::{audit, channels, settings};
+
+/// Checks the eligibility rules of the contest `c` for the `user` that wants to participate,
+/// hence to get a referral link. Returns the reason of the exclusion, if any.
+///
+/// NOTE: Telegram doesn't tell when a user joined a channel, hence the prior membership rule
+/// allows the current members of the channel, except the ones that joined with an invitation
+/// created after the contest started.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chan` - The channel of the contest
+/// * `c` - The contest
+/// * `user` - The user that wants to participate
+///
+/// # Errors
+/// Returns the reason of the exclusion, ready to be shown to the user.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn check_participant(
+    ctx: &Context,
+    chan: &Channel,
+    c: &Contest,
+    user: &User,
+) -> Result<(), String> {
+    if settings::enabled(ctx, c.id, settings::EXCLUDE_ADMINS) {
+        let operator = {
+            let guard = ctx.data.read();
+            guard.get::<OperatorKey>().copied().flatten()
+        };
+        let is_admin = channels::admins(ctx, chan.id, user.id)
+            .await
+            .iter()
+            .any(|admin| admin.user.id == user.id);
+        if is_admin || user.id == chan.registered_by || operator == Some(user.id) {
+            return Err("the owner and the admins of the channel can't participate.".to_string());
+        }
+    }
+
+    if settings::enabled(ctx, c.id, settings::REQUIRE_USERNAME) && user.username.is_none() {
+        return Err(
+            "only the users with a username can participate. Set your username in \
+            the Telegram settings and try again."
+                .to_string(),
+        );
+    }
+
+    if settings::enabled(ctx, c.id, settings::ALLOWLIST) {
+        let entries = allowlist(ctx, c.id);
+        let allowed = entries.contains(&user.id.to_string())
+            || user
+                .username
+                .as_ref()
+                .is_some_and(|username| entries.contains(&username.to_lowercase()));
+        if !allowed {
+            return Err("only the users in the allowlist can participate.".to_string());
+        }
+    }
+
+    if settings::enabled(ctx, c.id, settings::PRIOR_MEMBERSHIP) {
+        let joined_later = {
+            let guard = ctx.data.read();
+            let map = guard.get::<DBKey>().expect("db");
+            let conn = map.get().unwrap();
+            conn.query_row(
+                &format!(
+                    "SELECT COUNT(*) > 0 FROM invitations WHERE dest = ? AND chan = ? \
+                    AND date >= ? AND (status = ? OR {})",
+                    InvitationStatus::COUNTED
+                ),
+                params![user.id, chan.id, c.started_at, InvitationStatus::Left],
+                |row| row.get(0),
+            )
+            .unwrap_or(false)
+        };
+        if joined_later || !channels::is_member(ctx, chan.id, user.id).await {
+            return Err(
+                "only the members of the channel before the contest started can participate."
+                    .to_string(),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Checks the invitee filters of the contest `c` for the `invitee` that accepted the
+/// `invitation`. Returns the key of the first filter that failed and its description.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chan` - The channel of the contest
+/// * `c` - The contest
+/// * `invitee` - The user that accepted the invitation
+/// * `invitation` - The ID of the invitation just created
+///
+/// # Errors
+/// Returns the key of the failed filter and its description.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn check_invitee(
+    ctx: &Context,
+    chan: &Channel,
+    c: &Contest,
+    invitee: &User,
+    invitation: i64,
+) -> Result<(), (&'static str, &'static str)> {
+    if settings::enabled(ctx, c.id, settings::INVITEE_NOT_BOT) && invitee.is_bot {
+        return Err((settings::INVITEE_NOT_BOT, "bots don't count"));
+    }
+
+    if settings::enabled(ctx, c.id, settings::INVITEE_USERNAME) && invitee.username.is_none() {
+        return Err((
+            settings::INVITEE_USERNAME,
+            "invitees without a username don't count",
+        ));
+    }
+
+    // The current members can't accept an invitation: the past ones are known only if they
+    // joined the channel with a previous invitation
+    if settings::enabled(ctx, c.id, settings::INVITEE_NEW) {
+        let member_before = {
+            let guard = ctx.data.read();
+            let map = guard.get::<DBKey>().expect("db");
+            let conn = map.get().unwrap();
+            conn.query_row(
+                "SELECT COUNT(*) > 0 FROM invitations WHERE dest = ? AND chan = ? AND id <> ? \
+                AND status NOT IN (?, ?)",
+                params![
+                    invitee.id,
+                    chan.id,
+                    invitation,
+                    InvitationStatus::Pending,
+                    InvitationStatus::Rejected
+                ],
+                |row| row.get(0),
+            )
+            .unwrap_or(false)
+        };
+        if member_before {
+            return Err((
+                settings::INVITEE_NEW,
+                "invitees that have been members of the channel before don't count",
+            ));
+        }
+    }
+
+    if settings::enabled(ctx, c.id, settings::INVITEE_PREMIUM)
+        && !is_premium(ctx, chan.id, invitee.id).await
+    {
+        return Err((
+            settings::INVITEE_PREMIUM,
+            "only Telegram Premium invitees count",
+        ));
+    }
+    Ok(())
+}
+
+/// Returns true if the user is a Telegram Premium user.
+/// NOTE: Telexide doesn't parse the `is_premium` field of the users, hence it's read from
+/// the raw getChatMember response.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chat_id` - A chat the bot is admin of
+/// * `user_id` - The user
+pub async fn is_premium(ctx: &Context, chat_id: i64, user_id: i64) -> bool {
+    let data = serde_json::json!({ "chat_id": chat_id, "user_id": user_id });
+    match ctx.api.post(APIEndpoint::GetChatMember, Some(data)).await {
+        Ok(res) => res
+            .result
+            .and_then(|member| member["user"]["is_premium"].as_bool())
+            .unwrap_or(false),
+        Err(err) => {
+            error!("[is premium] {err}");
+            false
+        }
+    }
+}
+
+/// Returns the entries (user IDs and lowercase usernames) of the allowlist of the `contest`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+#[must_use]
+pub fn allowlist(ctx: &Context, contest: i64) -> Vec<String> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let mut stmt = conn
+        .prepare("SELECT entry FROM allowlists WHERE contest = ? ORDER BY entry ASC")
+        .unwrap();
+    let entries = stmt
+        .query_map(params![contest], |row| row.get(0))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    entries
+}
+
+/// Replaces the allowlist of the `contest` with the users in `text`: user IDs or usernames
+/// (with or without @), separated by spaces, commas or new lines. The change is added to
+/// the audit log. Returns the number of entries.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `text` - The allowlist written by the owner
+/// * `actor` - The user changing the allowlist
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails. In this case nothing changes.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn set_allowlist(
+    ctx: &Context,
+    contest: i64,
+    text: &str,
+    actor: i64,
+) -> rusqlite::Result<usize> {
+    let entries = text
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .map(|entry| entry.trim_start_matches('@').to_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .collect::<Vec<String>>();
+    {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let mut conn = map.get().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM allowlists WHERE contest = ?", params![contest])?;
+        for entry in &entries {
+            tx.execute(
+                "INSERT OR IGNORE INTO allowlists(contest, entry) VALUES(?, ?)",
+                params![contest, entry],
+            )?;
+        }
+        tx.commit()?;
+    }
+    audit::log(
+        ctx,
+        contest,
+        actor,
+        "set allowlist",
+        None,
+        Some(&entries.join(" ")),
+    )?;
+    Ok(entries.len())
+}
@@ -0,0 +1,362 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use rand::Rng;
+use rusqlite::params;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use telexide_fork::prelude::*;
+
+use crate::persistence::types::DBKey;
+
+/// Maximum length of the tickets list in a single message, below the Telegram limits.
+const MESSAGE_LEN: usize = 3500;
+
+/// Returns the hex encoding of the `bytes`.
+///
+/// # Arguments
+/// * `bytes` - The bytes to encode
+#[must_use]
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+/// Returns the commitment of the `seed`: the hex-encoded SHA-256 of the seed.
+///
+/// # Arguments
+/// * `seed` - The hex-encoded seed
+#[must_use]
+pub fn commitment(seed: &str) -> String {
+    hex(&Sha256::digest(seed.as_bytes()))
+}
+
+/// Returns the seed of the `contest` and its commitment, if the commitment has been published
+/// in the announcement: a seed nobody saw committed proves nothing.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+#[must_use]
+pub fn seed(ctx: &Context, contest: i64) -> Option<(String, String)> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.query_row(
+        "SELECT seed, commitment FROM draw_seeds WHERE contest = ? AND published",
+        params![contest],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .ok()
+}
+
+/// Generates the secret seed of the `contest`, if it doesn't have one, and returns its
+/// commitment.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the insertion fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn commit(ctx: &Context, contest: i64) -> rusqlite::Result<String> {
+    let seed = hex(&rand::thread_rng().gen::<[u8; 32]>());
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.execute(
+        "INSERT OR IGNORE INTO draw_seeds(contest, seed, commitment) VALUES(?, ?, ?)",
+        params![contest, seed, commitment(&seed)],
+    )?;
+    conn.query_row(
+        "SELECT commitment FROM draw_seeds WHERE contest = ?",
+        params![contest],
+        |row| row.get(0),
+    )
+}
+
+/// Records that the commitment of the seed of the `contest` has been published in the
+/// announcement.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn publish(ctx: &Context, contest: i64) -> rusqlite::Result<()> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.execute(
+        "UPDATE draw_seeds SET published = TRUE WHERE contest = ?",
+        params![contest],
+    )?;
+    Ok(())
+}
+
+/// Records that the seed of the `contest` has been revealed.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn reveal(ctx: &Context, contest: i64) -> rusqlite::Result<()> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.execute(
+        "UPDATE draw_seeds SET revealed_at = CURRENT_TIMESTAMP \
+        WHERE contest = ? AND revealed_at IS NULL",
+        params![contest],
+    )?;
+    Ok(())
+}
+
+/// Returns true if the winners of the finished `contest` have been drawn, hence if its seed
+/// has been revealed.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+#[must_use]
+pub fn drawn(ctx: &Context, contest: i64) -> bool {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.query_row(
+        "SELECT COUNT(*) > 0 FROM draw_seeds WHERE contest = ? AND revealed_at IS NOT NULL",
+        params![contest],
+        |row| row.get(0),
+    )
+    .unwrap()
+}
+
+/// Returns the tickets of a `score`: the score multiplied by 1000 and rounded.
+///
+/// # Arguments
+/// * `score` - The score of a participant
+#[must_use]
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+pub fn tickets(score: f64) -> u64 {
+    (score.max(0.0) * 1000.0).round() as u64
+}
+
+/// Draws at most `winners` entries. The algorithm is simple enough to be re-run offline:
+///
+/// 1. the entries are sorted by user ID, ascending, and the entries without tickets are
+///    removed;
+/// 2. the draw number `k` (starting from 0) computes `h = SHA-256("{seed}:{k}")`, takes its
+///    first 8 bytes as a big-endian unsigned integer, and reduces it modulo the total tickets;
+/// 3. the winner is the first entry whose cumulative tickets exceed the reduced value. The
+///    winner is removed from the entries, and the next draw starts, until all the winners are
+///    drawn or no entries are left.
+///
+/// # Arguments
+/// * `seed` - The hex-encoded seed
+/// * `winners` - The number of winners to draw
+/// * `entries` - The user ID and the tickets of every participant
+///
+/// # Returns
+/// The user IDs of the winners, in drawing order.
+#[must_use]
+pub fn draw(seed: &str, winners: usize, entries: &[(i64, u64)]) -> Vec<i64> {
+    let mut entries = entries
+        .iter()
+        .copied()
+        .filter(|(_, tickets)| *tickets > 0)
+        .collect::<Vec<_>>();
+    entries.sort_unstable();
+    let mut drawn = vec![];
+    for k in 0.. {
+        if drawn.len() >= winners || entries.is_empty() {
+            break;
+        }
+        let total: u64 = entries.iter().map(|(_, tickets)| tickets).sum();
+        let hash = Sha256::digest(format!("{seed}:{k}").as_bytes());
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&hash[..8]);
+        let value = u64::from_be_bytes(bytes) % total;
+        let mut cumulative = 0;
+        let position = entries
+            .iter()
+            .position(|(_, tickets)| {
+                cumulative += tickets;
+                value < cumulative
+            })
+            .unwrap_or(entries.len() - 1);
+        drawn.push(entries.remove(position).0);
+    }
+    drawn
+}
+
+/// Returns the proof of the draw: the revealed seed, its commitment, and the tickets of every
+/// participant, ready to be re-run with `raf verify`. The proof is split in messages that fit
+/// the Telegram limits.
+///
+/// # Arguments
+/// * `seed` - The hex-encoded seed
+/// * `winners` - The number of winners drawn
+/// * `entries` - The user ID and the tickets of every participant
+#[must_use]
+pub fn proof(seed: &str, winners: usize, entries: &[(i64, u64)]) -> Vec<String> {
+    let mut entries = entries.to_vec();
+    entries.sort_unstable();
+    let mut messages = vec![format!(
+        "\u{1f50f} Draw proof\n\nSeed: {seed}\nCommitment (SHA-256 of the seed): {}\n\n\
+        Verify it offline with:\nraf verify {seed} {} {winners} followed by the tickets below \
+        (user=tickets, the score \u{d7} 1000).",
+        commitment(seed),
+        commitment(seed)
+    )];
+    let mut tickets = String::new();
+    for (user, count) in &entries {
+        if tickets.len() > MESSAGE_LEN {
+            messages.push(tickets);
+            tickets = String::new();
+        }
+        let _ = write!(tickets, "{user}={count} ");
+    }
+    if !tickets.is_empty() {
+        messages.push(tickets);
+    }
+    messages
+}
+
+/// Verifies a draw offline: the arguments are the revealed seed, the commitment published in
+/// the announcement, the number of winners, and the tickets of every participant (as
+/// `user=tickets`).
+///
+/// # Arguments
+/// * `args` - The command line arguments
+///
+/// # Returns
+/// The user IDs of the winners, in drawing order.
+///
+/// # Errors
+/// Returns the reason of the failure: malformed arguments, or a seed that doesn't match the
+/// commitment.
+pub fn verify(args: &[String]) -> Result<Vec<i64>, String> {
+    if args.len() < 3 {
+        return Err("usage: raf verify SEED COMMITMENT WINNERS USER=TICKETS...".to_string());
+    }
+    let (seed, expected) = (&args[0], &args[1]);
+    if commitment(seed) != expected.to_lowercase() {
+        return Err("the seed doesn't match the commitment".to_string());
+    }
+    let winners = args[2]
+        .parse()
+        .map_err(|_| format!("{} is not a valid number of winners", args[2]))?;
+    let entries = args[3..]
+        .iter()
+        .map(|entry| {
+            entry
+                .split_once('=')
+                .and_then(|(user, tickets)| Some((user.parse().ok()?, tickets.parse().ok()?)))
+                .ok_or(format!("{entry} is not a valid user=tickets entry"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(draw(seed, winners, &entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: &str = "6a09e667f3bcc908b2fe8f7e1b1ee3f2a54ff53a5f1d36f1510e527fade682d1";
+
+    fn entries() -> Vec<(i64, u64)> {
+        vec![(42, 3000), (7, 1000), (99, 0), (13, 2500), (5, 500)]
+    }
+
+    #[test]
+    fn draw_is_reproducible() {
+        let first = draw(SEED, 3, &entries());
+        assert_eq!(first.len(), 3);
+        assert_eq!(first, draw(SEED, 3, &entries()));
+        // The order of the entries doesn't matter
+        let mut reversed = entries();
+        reversed.reverse();
+        assert_eq!(first, draw(SEED, 3, &reversed));
+    }
+
+    #[test]
+    fn draw_distinct_winners_with_tickets() {
+        let winners = draw(SEED, 10, &entries());
+        assert_eq!(winners.len(), 4);
+        assert!(!winners.contains(&99));
+        let mut unique = winners.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), winners.len());
+        assert!(draw(SEED, 3, &[]).is_empty());
+        assert!(draw(SEED, 0, &entries()).is_empty());
+    }
+
+    #[test]
+    fn verify_matches_draw() {
+        let mut args = vec![SEED.to_string(), commitment(SEED), "3".to_string()];
+        args.extend(
+            entries()
+                .iter()
+                .map(|(user, tickets)| format!("{user}={tickets}")),
+        );
+        assert_eq!(verify(&args), Ok(draw(SEED, 3, &entries())));
+        // The commitment is case insensitive
+        args[1] = args[1].to_uppercase();
+        assert_eq!(verify(&args), Ok(draw(SEED, 3, &entries())));
+    }
+
+    #[test]
+    fn verify_rejects_bad_arguments() {
+        let args = |commitment: String, winners: &str, entry: &str| {
+            vec![
+                SEED.to_string(),
+                commitment,
+                winners.to_string(),
+                entry.to_string(),
+            ]
+        };
+        assert!(verify(&args(commitment("other"), "1", "1=10")).is_err());
+        assert!(verify(&args(commitment(SEED), "one", "1=10")).is_err());
+        assert!(verify(&args(commitment(SEED), "1", "1:10")).is_err());
+        assert!(verify(&[SEED.to_string()]).is_err());
+    }
+
+    #[test]
+    fn tickets_of_score() {
+        assert_eq!(tickets(2.0), 2000);
+        assert_eq!(tickets(0.3333), 333);
+        assert_eq!(tickets(-1.0), 0);
+    }
+}
@@ -0,0 +1,308 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::Duration;
+use log::error;
+use rusqlite::params;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use telexide_fork::{
+    api::types::SendMessage,
+    model::{InlineKeyboardMarkup, ParseMode, ReplyMarkup},
+    prelude::*,
+};
+
+use crate::persistence::types::{Channel, Contest, DBKey, InvitationStatus, Invite, Suspect};
+use crate::telegram::messages::{callback_button, escape_markdown};
+use crate::telegram::{channels, contests, invitations, users};
+
+/// Number of invites accepted within `BURST_WINDOW` minutes that make a burst.
+const BURST_SIZE: usize = 5;
+/// Width, in minutes, of the window used to detect the bursts of accepted invites.
+const BURST_WINDOW: i64 = 10;
+/// Minimum number of invites a participant must have for the ratio based heuristics
+/// (incomplete profiles, new accounts, leavers) to be meaningful.
+const MIN_INVITES: usize = 3;
+/// Maximum number of invitees per participant checked by the leavers heuristic: every check is
+/// a request to Telegram, hence only the latest invitees are sampled.
+const LEAVERS_SAMPLE: usize = 10;
+/// Minimum number of users, unrelated to the contest, required to estimate which user IDs
+/// belong to brand-new accounts.
+const MIN_REFERENCE_USERS: usize = 20;
+/// Maximum number of minutes between the join of an invitee and its first invite, for the
+/// invitee to be considered part of a referral chain.
+const CHAIN_WINDOW: i64 = 60;
+/// Number of positions of the ranking considered "top referrers".
+const TOP_REFERRERS: usize = 10;
+/// Minimum score for a participant to be reported.
+const REPORT_SCORE: u32 = 30;
+
+/// Returns the user ID above which an account is considered brand-new: Telegram assigns the
+/// IDs incrementally, hence the accounts with the highest IDs are the newest.
+/// The threshold is the 90th percentile of the IDs of the users `RaF` knows that have not been
+/// invited in the `contest`, so the invitees under examination don't move it.
+/// Returns None if there are not enough users to make a meaningful estimate.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+fn new_account_threshold(ctx: &Context, contest: i64) -> Option<i64> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let mut stmt = conn
+        .prepare(
+            "SELECT id FROM users WHERE id NOT IN (SELECT dest FROM invitations WHERE contest = ?) \
+            ORDER BY id ASC",
+        )
+        .unwrap();
+    let ids = stmt
+        .query_map(params![contest], |row| row.get(0))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect::<Vec<i64>>();
+    if ids.len() < MIN_REFERENCE_USERS {
+        return None;
+    }
+    Some(ids[ids.len() * 9 / 10])
+}
+
+/// Returns the maximum number of `invites` accepted within `BURST_WINDOW` minutes.
+///
+/// # Arguments
+/// * `invites` - The invitations sent by a participant, ordered by date
+fn largest_burst(invites: &[&Invite]) -> usize {
+    let window = Duration::minutes(BURST_WINDOW);
+    let mut largest = 0;
+    let mut begin = 0;
+    for (end, invite) in invites.iter().enumerate() {
+        while invite.date - invites[begin].date > window {
+            begin += 1;
+        }
+        largest = largest.max(end - begin + 1);
+    }
+    largest
+}
+
+/// Applies the heuristics to the invitations sent by the participant `source` and returns
+/// its score (capped to 100) together with the reasons.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `source` - The participant ID
+/// * `list` - The counted invitations sent by `source`, ordered by date
+/// * `threshold` - The ID above which an account is considered brand-new, if known
+/// * `chained` - The invitees that became top referrers right after joining, with the delay
+async fn analyze(
+    ctx: &Context,
+    source: i64,
+    list: &[&Invite],
+    threshold: Option<i64>,
+    chained: &HashMap<i64, i64>,
+) -> (u32, Vec<String>) {
+    let mut score = 0;
+    let mut reasons = vec![];
+    let total = list.len();
+
+    let burst = largest_burst(list);
+    if burst >= BURST_SIZE {
+        score += 30;
+        reasons.push(format!(
+            "{burst} invites accepted within {BURST_WINDOW} minutes"
+        ));
+    }
+
+    if total >= MIN_INVITES {
+        let profiles = list
+            .iter()
+            .filter_map(|invite| users::get(ctx, invite.dest))
+            .collect::<Vec<_>>();
+        let incomplete = profiles
+            .iter()
+            .filter(|u| u.username.is_none() && u.last_name.is_none())
+            .count();
+        if incomplete * 2 >= total {
+            score += 25;
+            reasons.push(format!(
+                "{incomplete}/{total} invitees without username and last name"
+            ));
+        }
+
+        if let Some(threshold) = threshold {
+            let new = profiles.iter().filter(|u| u.id > threshold).count();
+            if new * 2 >= total {
+                score += 20;
+                reasons.push(format!("{new}/{total} invitees with brand-new accounts"));
+            }
+        }
+
+        let sample = &list[total.saturating_sub(LEAVERS_SAMPLE)..];
+        let mut left = 0;
+        for invite in sample {
+            if !channels::is_member(ctx, invite.chan, invite.dest).await {
+                left += 1;
+            }
+        }
+        if left * 2 >= sample.len() {
+            score += 25;
+            reasons.push(format!(
+                "{left}/{} of the latest invitees already left the channel",
+                sample.len()
+            ));
+        }
+    }
+
+    let children = list
+        .iter()
+        .filter(|invite| chained.contains_key(&invite.dest))
+        .count();
+    if children > 0 {
+        score += 20;
+        reasons.push(format!(
+            "{children} invitees became top referrers right after joining"
+        ));
+    }
+    if let Some(delay) = chained.get(&source) {
+        score += 20;
+        reasons.push(format!(
+            "became a top referrer {delay} minutes after joining"
+        ));
+    }
+    (score.min(100), reasons)
+}
+
+/// Analyzes the counted invitations of the `contest` and returns the participants that look
+/// suspicious, the most suspicious first. The heuristics are:
+///
+/// - bursts of accepted invites in a short time;
+/// - invitees without username and last name;
+/// - invitees with brand-new accounts (high user IDs);
+/// - invitees that already left the channel, checked on the latest `LEAVERS_SAMPLE` invitees of
+///   every participant;
+/// - chains: invitees that became top referrers right after joining.
+///
+/// NOTE: this function is async because it checks if the invitees are still members of the
+/// channel. The score is a heuristic: it's a hint for the owner, not a verdict.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest under examination
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn report(ctx: &Context, contest: &Contest) -> Vec<Suspect> {
+    let invites = invitations::get_all(ctx, contest.id, InvitationStatus::COUNTED);
+    let mut sent: BTreeMap<i64, Vec<&Invite>> = BTreeMap::new();
+    for invite in &invites {
+        sent.entry(invite.source).or_default().push(invite);
+    }
+    for list in sent.values_mut() {
+        list.sort_by_key(|invite| invite.date);
+    }
+    let threshold = new_account_threshold(ctx, contest.id);
+    let top = contests::ranking(ctx, contest)
+        .into_iter()
+        .take(TOP_REFERRERS)
+        .map(|row| row.user.id)
+        .collect::<Vec<i64>>();
+
+    // Invitees that became top referrers right after joining: invitee -> minutes
+    let mut chained: HashMap<i64, i64> = HashMap::new();
+    for invite in &invites {
+        if !top.contains(&invite.dest) {
+            continue;
+        }
+        if let Some(first) = sent.get(&invite.dest).and_then(|list| list.first()) {
+            let delay = (first.date - invite.date).num_minutes();
+            if (0..=CHAIN_WINDOW).contains(&delay) {
+                chained.insert(invite.dest, delay);
+            }
+        }
+    }
+
+    let mut suspects = vec![];
+    for (source, list) in &sent {
+        let (score, reasons) = analyze(ctx, *source, list, threshold, &chained).await;
+        if score >= REPORT_SCORE {
+            if let Some(user) = users::get(ctx, *source) {
+                suspects.push(Suspect {
+                    user,
+                    score,
+                    reasons,
+                });
+            }
+        }
+    }
+    suspects.sort_by_key(|suspect| Reverse(suspect.score));
+    suspects
+}
+
+/// Sends to `chat_id` the suspicious-activity report of the `contest`, together with the
+/// buttons to publish the results or to review the participants first.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chat_id` - The chat ID
+/// * `chan` - The channel of the contest
+/// * `contest` - The contest that is going to be stopped
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn display_report(ctx: &Context, chat_id: i64, chan: &Channel, contest: &Contest) {
+    let suspects = report(ctx, contest).await;
+    let mut text = format!("Suspicious activity report - {}\n\n", contest.name);
+    if suspects.is_empty() {
+        text += "No suspicious participants found.\n";
+    }
+    for suspect in &suspects {
+        let _ = writeln!(
+            text,
+            "{}/100 - {}",
+            suspect.score,
+            users::display_name(&suspect.user)
+        );
+        for reason in &suspect.reasons {
+            let _ = writeln!(text, "  - {reason}");
+        }
+    }
+    text += "\nThe score is a hint, not a verdict. Review the participants (and disqualify them \
+        if needed) before publishing the results: once published, the results can't change.";
+
+    let mut reply = SendMessage::new(chat_id, &escape_markdown(&text, None));
+    reply.set_parse_mode(&ParseMode::MarkdownV2);
+    reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+        inline_keyboard: vec![
+            vec![callback_button(
+                "\u{2705} Publish results",
+                &format!("stop_contest {} {}", chan.id, contest.id),
+            )],
+            vec![
+                callback_button(
+                    "\u{1f465} Participants",
+                    &format!("participants_contest {} {}", chan.id, contest.id),
+                ),
+                callback_button("\u{1f519} Manage", &format!("manage {}", chan.id)),
+            ],
+        ],
+    }));
+    let res = ctx.api.send_message(reply).await;
+    if res.is_err() {
+        let err = res.err().unwrap();
+        error!("[fraud report] {err}");
+    }
+}
@@ -0,0 +1,411 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use log::error;
+use rusqlite::params;
+use std::fmt::Write;
+use telexide_fork::{
+    api::types::SendMessage,
+    model::{InlineKeyboardButton, InlineKeyboardMarkup, ParseMode, ReplyMarkup},
+    prelude::*,
+};
+
+use crate::persistence::types::{
+    Channel, Contest, ContestResult, DBKey, Fulfillment, FulfillmentStatus,
+};
+use crate::telegram::messages::{callback_button, escape_markdown};
+use crate::telegram::{audit, channels, contests, prizes, settings, users};
+
+/// Returns the prize-fulfillment records of the `contest`, ordered by rank.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+#[must_use]
+pub fn get_all(ctx: &Context, contest: i64) -> Vec<Fulfillment> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let mut stmt = conn
+        .prepare(
+            "SELECT user, rank, prize, status, updated_at FROM fulfillments \
+            WHERE contest = ? ORDER BY rank ASC, date ASC",
+        )
+        .unwrap();
+    let fulfillments = stmt
+        .query_map(params![contest], |row| {
+            Ok(Fulfillment {
+                contest,
+                user: row.get(0)?,
+                rank: row.get(1)?,
+                prize: row.get(2)?,
+                status: row.get(3)?,
+                updated_at: row.get(4)?,
+            })
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    fulfillments
+}
+
+/// Returns true if the fulfillment can go `from` a status `to` another. The winner claims,
+/// confirms or disputes the prize, the owner ships it or marks it as delivered.
+///
+/// # Arguments
+/// * `from` - The current status
+/// * `to` - The new status
+/// * `by_owner` - True if the change is requested by the owner, false if by the winner
+#[must_use]
+pub fn allowed(from: FulfillmentStatus, to: FulfillmentStatus, by_owner: bool) -> bool {
+    use FulfillmentStatus::{Claimed, Delivered, Disputed, Notified, Shipped};
+    if from == Delivered || from == to {
+        return false;
+    }
+    match to {
+        Claimed => !by_owner && from == Notified,
+        Shipped => by_owner,
+        Delivered => true,
+        Disputed => !by_owner,
+        Notified => false,
+    }
+}
+
+/// Returns the buttons of the winner to change the status of the prize of the `contest`.
+///
+/// # Arguments
+/// * `contest` - The contest ID
+/// * `status` - The current status of the prize
+#[must_use]
+pub fn winner_keyboard(contest: i64, status: FulfillmentStatus) -> Vec<Vec<InlineKeyboardButton>> {
+    let mut row = vec![];
+    if status == FulfillmentStatus::Notified {
+        row.push(callback_button(
+            "\u{1f64b} Claim",
+            &format!("receipt {contest} claimed"),
+        ));
+    }
+    row.push(callback_button(
+        "\u{2705} Received",
+        &format!("receipt {contest} delivered"),
+    ));
+    row.push(callback_button(
+        "\u{26a0}\u{fe0f} Problem",
+        &format!("receipt {contest} disputed"),
+    ));
+    vec![row]
+}
+
+/// Creates the prize-fulfillment records of the winners of the contest `c`, and asks the
+/// winners without a prize code to claim their prize. The winners that received a prize code
+/// start from the shipped status.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The finished contest
+/// * `results` - The official results of the contest
+/// * `delivered` - The winners that received a prize code
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn open(ctx: &Context, c: &Contest, results: &[ContestResult], delivered: &[i64]) {
+    for result in results {
+        let code_sent = delivered.contains(&result.user.id);
+        let prize = match &result.prize {
+            Some(prize) => prize.clone(),
+            None if code_sent => format!("prize code of the tier #{}", result.rank),
+            None => continue,
+        };
+        let status = if code_sent {
+            FulfillmentStatus::Shipped
+        } else {
+            FulfillmentStatus::Notified
+        };
+        let res = {
+            let guard = ctx.data.read();
+            let map = guard.get::<DBKey>().expect("db");
+            let conn = map.get().unwrap();
+            conn.execute(
+                "INSERT OR IGNORE INTO fulfillments(contest, user, rank, prize, status) \
+                VALUES(?, ?, ?, ?, ?)",
+                params![c.id, result.user.id, result.rank, prize, status],
+            )
+        };
+        if let Err(err) = res {
+            error!("[open fulfillment] {err}");
+            continue;
+        }
+        if code_sent {
+            continue;
+        }
+        let text = format!(
+            "\u{1f3c6} Congratulations! You won the {} contest.\n\nPrize: {prize}\n\n\
+            Press Claim to claim it, and Received once you got it.",
+            c.name
+        );
+        let mut reply = SendMessage::new(result.user.id, &escape_markdown(&text, None));
+        reply.set_parse_mode(&ParseMode::MarkdownV2);
+        reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+            inline_keyboard: winner_keyboard(c.id, status),
+        }));
+        if let Err(err) = ctx.api.send_message(reply).await {
+            error!("[fulfillment send] {err}");
+        }
+    }
+}
+
+/// Changes to `status` the prize fulfillment of the winner `user` of the contest `c`, if the
+/// transition is allowed, and informs the other party. The change is added to the audit log.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chan` - The channel of the contest
+/// * `c` - The contest
+/// * `user` - The winner
+/// * `status` - The new status
+/// * `actor` - The user changing the status: the winner or the owner
+///
+/// # Errors
+/// Returns the reason of the failure, ready to be shown to the actor.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn update(
+    ctx: &Context,
+    chan: &Channel,
+    c: &Contest,
+    user: i64,
+    status: FulfillmentStatus,
+    actor: i64,
+) -> Result<(), String> {
+    let current = get_all(ctx, c.id)
+        .into_iter()
+        .find(|f| f.user == user)
+        .ok_or_else(|| "There's no prize to track.".to_string())?;
+    let by_owner = actor != user;
+    if !allowed(current.status, status, by_owner) {
+        return Err(format!(
+            "The prize is {}: it can't become {status}.",
+            current.status
+        ));
+    }
+    {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.execute(
+            "UPDATE fulfillments SET status = ?, updated_at = CURRENT_TIMESTAMP \
+            WHERE contest = ? AND user = ?",
+            params![status, c.id, user],
+        )
+        .map_err(|err| err.to_string())?;
+    }
+    if status == FulfillmentStatus::Delivered {
+        if let Err(err) = prizes::claim(ctx, c.id, user) {
+            error!("[claim prize code] {err}");
+        }
+    }
+    let res = audit::log(
+        ctx,
+        c.id,
+        actor,
+        &format!("fulfillment {status}"),
+        Some(user),
+        None,
+    );
+    if let Err(err) = res {
+        error!("[fulfillment audit] {err}");
+    }
+
+    let reply = if by_owner {
+        let text = format!(
+            "The status of your prize ({}) of the {} contest is now: {status}.",
+            current.prize, c.name
+        );
+        let mut reply = SendMessage::new(user, &escape_markdown(&text, None));
+        if status != FulfillmentStatus::Delivered {
+            reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+                inline_keyboard: winner_keyboard(c.id, status),
+            }));
+        }
+        reply
+    } else {
+        let name =
+            users::get(ctx, user).map_or_else(|| user.to_string(), |u| users::display_name(&u));
+        let text = format!(
+            "{name} marked the prize ({}) of the {} contest as {status}.",
+            current.prize, c.name
+        );
+        let mut reply = SendMessage::new(chan.registered_by, &escape_markdown(&text, None));
+        reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+            inline_keyboard: vec![vec![callback_button(
+                "\u{1f4e6} Fulfillment",
+                &format!("fulfillment {} {}", chan.id, c.id),
+            )]],
+        }));
+        reply
+    };
+    let mut reply = reply;
+    reply.set_parse_mode(&ParseMode::MarkdownV2);
+    if let Err(err) = ctx.api.send_message(reply).await {
+        error!("[fulfillment notify] {err}");
+    }
+    Ok(())
+}
+
+/// Reminds the owners of the prizes still pending after the days chosen in the settings of
+/// every contest. Every prize is reminded again only after the same number of days.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn remind(ctx: &Context) {
+    let pending: Vec<(i64, i64, i64, String, FulfillmentStatus)> = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT contest, user, rank, prize, status FROM fulfillments \
+                WHERE status <> 'delivered'",
+            )
+            .unwrap();
+        let pending = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        pending
+    };
+    for (contest, user, rank, prize, status) in pending {
+        let days: i64 = match settings::get(ctx, contest, settings::FULFILLMENT_REMINDER).parse() {
+            Ok(days) => days,
+            Err(_) => continue,
+        };
+        let due = {
+            let guard = ctx.data.read();
+            let map = guard.get::<DBKey>().expect("db");
+            let conn = map.get().unwrap();
+            conn.execute(
+                "UPDATE fulfillments SET reminded_at = CURRENT_TIMESTAMP \
+                WHERE contest = ?1 AND user = ?2 \
+                AND julianday('now') - julianday(updated_at) >= ?3 \
+                AND (reminded_at IS NULL OR julianday('now') - julianday(reminded_at) >= ?3)",
+                params![contest, user, days],
+            )
+        };
+        match due {
+            Ok(0) => continue,
+            Ok(_) => {}
+            Err(err) => {
+                error!("[fulfillment reminder] {err}");
+                continue;
+            }
+        }
+        let c = contests::get(ctx, contest);
+        let chan = c.as_ref().and_then(|c| channels::get(ctx, c.chan));
+        if let (Some(c), Some(chan)) = (c, chan) {
+            let name =
+                users::get(ctx, user).map_or_else(|| user.to_string(), |u| users::display_name(&u));
+            let text = format!(
+                "\u{23f0} The prize ({prize}) of {name} (#{rank}) in the {} contest is still \
+                {status}, for more than {days} days.",
+                c.name
+            );
+            let mut reply = SendMessage::new(chan.registered_by, &escape_markdown(&text, None));
+            reply.set_parse_mode(&ParseMode::MarkdownV2);
+            reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+                inline_keyboard: vec![vec![callback_button(
+                    "\u{1f4e6} Fulfillment",
+                    &format!("fulfillment {} {}", chan.id, c.id),
+                )]],
+            }));
+            if let Err(err) = ctx.api.send_message(reply).await {
+                error!("[fulfillment reminder send] {err}");
+            }
+        }
+    }
+}
+
+/// Sends to `chat_id` the prize-fulfillment records of the `contest`, and the buttons of the
+/// owner to chat with the winners and to change the status of their prizes.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chat_id` - The chat ID
+/// * `chan` - The channel of the contest
+/// * `contest` - The contest
+pub async fn display(ctx: &Context, chat_id: i64, chan: &Channel, contest: &Contest) {
+    let fulfillments = get_all(ctx, contest.id);
+    let mut text = format!("Prize fulfillment of {}\n\n", contest.name);
+    if fulfillments.is_empty() {
+        text += "No prizes to deliver, yet!";
+    }
+    let mut inline_keyboard = vec![];
+    for f in &fulfillments {
+        let name =
+            users::get(ctx, f.user).map_or_else(|| f.user.to_string(), |u| users::display_name(&u));
+        let _ = writeln!(
+            text,
+            "#{} {name}: {} - {} (since {})",
+            f.rank,
+            f.prize,
+            f.status,
+            f.updated_at.format("%Y-%m-%d")
+        );
+        let mut row = vec![callback_button(
+            &format!("\u{1f4ac} #{}", f.rank),
+            &format!("relay {} {} {} open", chan.id, contest.id, f.user),
+        )];
+        for status in [FulfillmentStatus::Shipped, FulfillmentStatus::Delivered] {
+            if allowed(f.status, status, true) {
+                row.push(callback_button(
+                    &format!("#{} {status}", f.rank),
+                    &format!("mark {} {} {} {status}", chan.id, contest.id, f.user),
+                ));
+            }
+        }
+        inline_keyboard.push(row);
+    }
+    inline_keyboard.push(vec![
+        callback_button(
+            "\u{1f381} Prize codes",
+            &format!("prizes {} {}", chan.id, contest.id),
+        ),
+        callback_button("\u{1f519} Manage", &format!("manage {}", chan.id)),
+    ]);
+
+    let mut reply = SendMessage::new(chat_id, &escape_markdown(&text, None));
+    reply.set_parse_mode(&ParseMode::MarkdownV2);
+    reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+        inline_keyboard,
+    }));
+    let res = ctx.api.send_message(reply).await;
+    if let Err(err) = res {
+        error!("[fulfillment list send] {err}");
+    }
+}
@@ -0,0 +1,209 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::{DateTime, Utc};
+use log::error;
+use rusqlite::params;
+use std::fmt::Write;
+use telexide_fork::{
+    model::{InlineKeyboardButton, User},
+    prelude::*,
+};
+
+use crate::persistence::types::{Channel, Contest, DBKey, Rank};
+use crate::telegram::messages::{callback_button, escape_markdown};
+use crate::telegram::{channels, eligibility, networks, participants, raffle, settings, users};
+
+/// Returns true if the `contest` is a giveaway: the members enter by pressing a button on the
+/// announcement, without referrals.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+#[must_use]
+pub fn enabled(ctx: &Context, contest: i64) -> bool {
+    settings::get(ctx, contest, settings::MODE) == "giveaway"
+}
+
+/// Returns the number of entries of the giveaway `contest`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+#[must_use]
+pub fn count(ctx: &Context, contest: i64) -> i64 {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.query_row(
+        "SELECT COUNT(*) FROM giveaway_entries WHERE contest = ?",
+        params![contest],
+        |row| row.get(0),
+    )
+    .unwrap()
+}
+
+/// Enters the `user` that pressed Participate in the giveaway `c`, until its end. The user must
+/// be a member of the channel where the button has been pressed, must not be disqualified, and
+/// must satisfy the eligibility rules of the contest.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chan` - The channel of the giveaway where the button has been pressed
+/// * `c` - The giveaway
+/// * `user` - The user that pressed Participate
+///
+/// # Returns
+/// The answer for the user.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn enter(ctx: &Context, chan: &Channel, c: &Contest, user: &User) -> String {
+    if c.started_at.is_none() || c.stopped || Utc::now() > c.end {
+        return "This giveaway is not running.".to_string();
+    }
+    if let Some(reason) = participants::disqualification(ctx, c.id, user.id) {
+        return format!("You have been disqualified from this giveaway.\n\nReason: {reason}");
+    }
+    if !channels::is_member(ctx, chan.id, user.id).await {
+        return format!("Join {} to participate!", chan.name);
+    }
+    if let Err(reason) = eligibility::check_participant(ctx, chan, c, user).await {
+        return format!("You can't participate: {reason}");
+    }
+    let res = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.execute(
+            "INSERT OR IGNORE INTO users(id, first_name, last_name, username) VALUES(?, ?, ?, ?)",
+            params![user.id, user.first_name, user.last_name, user.username],
+        )
+        .and_then(|_| {
+            conn.execute(
+                "INSERT OR IGNORE INTO giveaway_entries(contest, user) VALUES(?, ?)",
+                params![c.id, user.id],
+            )
+        })
+    };
+    match res {
+        Ok(0) => "You are already participating. Good luck!".to_string(),
+        Ok(_) => "\u{1f389} You are in! Stay in the channel until the draw. Good luck!".to_string(),
+        Err(err) => {
+            error!("[giveaway enter] {err}");
+            format!("Error: {err}")
+        }
+    }
+}
+
+/// Returns the entries of the giveaway `c` that are still members of one of its channels, as a
+/// ranking where every entry has one ticket, in entry order. The members that left and the
+/// disqualified participants can't win.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The giveaway
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+pub async fn ranking(ctx: &Context, c: &Contest) -> Vec<Rank> {
+    let entries = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT user, date FROM giveaway_entries WHERE contest = ?1 \
+                AND user NOT IN (SELECT user FROM disqualified_participants WHERE contest = ?1) \
+                ORDER BY date ASC",
+            )
+            .unwrap();
+        let entries = stmt
+            .query_map(params![c.id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect::<Vec<(i64, DateTime<Utc>)>>();
+        entries
+    };
+    let channels = networks::channels(ctx, c);
+    let mut rank = vec![];
+    for (user, date) in entries {
+        let mut member = false;
+        for chan in &channels {
+            if channels::is_member(ctx, chan.id, user).await {
+                member = true;
+                break;
+            }
+        }
+        if !member {
+            continue;
+        }
+        if let Some(user) = users::get(ctx, user) {
+            rank.push(Rank {
+                rank: 0,
+                invites: 0.0,
+                points: 1.0,
+                indirect: 0.0,
+                adjustment: 0,
+                score: 1.0,
+                user,
+                last_invite: date,
+            });
+        }
+    }
+    for (position, row) in (1..).zip(rank.iter_mut()) {
+        row.rank = position;
+    }
+    rank
+}
+
+/// Returns the Participate button of the announcement of the giveaway `contest`, posted in
+/// the channel `chan`.
+///
+/// # Arguments
+/// * `contest` - The contest ID
+/// * `chan` - The channel ID where the announcement is posted
+#[must_use]
+pub fn keyboard(contest: i64, chan: i64) -> Vec<Vec<InlineKeyboardButton>> {
+    vec![vec![callback_button(
+        "\u{1f389} Participate",
+        &format!("participate {contest} {chan}"),
+    )]]
+}
+
+/// Returns the announcement of the giveaway `c`, already escaped for `MarkdownV2`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The giveaway
+/// * `commitment` - The commitment of the seed of the draw, if any
+#[must_use]
+pub fn announcement(ctx: &Context, c: &Contest, commitment: Option<&str>) -> String {
+    let mut text = format!(
+        "\u{1f389}{name} giveaway \u{1f389}\nPress Participate to win a {prize}!\n\n\
+        At the end of the giveaway ({end}) {winners} winners are drawn at random among the \
+        members of the channel that pressed Participate. Stay in the channel until the draw!\n",
+        name = c.name,
+        prize = c.prize,
+        end = c.end,
+        winners = raffle::winners(ctx, c.id)
+    );
+    if let Some(commitment) = commitment {
+        let _ = writeln!(text, "\u{2022} Draw commitment (SHA-256): {commitment}");
+    }
+    escape_markdown(&text, None)
+}
@@ -13,30 +13,58 @@
 // limitations under the License.
 
 use chrono::{DateTime, Utc};
-use data_encoding::BASE64URL;
 use log::{error, info};
 use rusqlite::params;
 use tabular::{Row, Table};
 use telexide_fork::model::{
-    Chat, ChatMember, InlineKeyboardButton, InlineKeyboardMarkup, ParseMode, ReplyMarkup,
+    CallbackQuery, Chat, InlineKeyboardButton, InlineKeyboardMarkup, ParseMode, ReplyMarkup,
     UpdateContent,
 };
 use telexide_fork::{
-    api::types::{AnswerCallbackQuery, GetChatMember, PinChatMessage, SendMessage},
+    api::types::{AnswerCallbackQuery, GetChatMember, SendMessage},
     prelude::*,
 };
 use tokio::time::{sleep, Duration};
 
-use crate::persistence::types::{Channel, Contest, DBKey, NameKey, User};
+use crate::persistence::types::{
+    BeingManagedContest, Challenge, Channel, Contest, DBKey, FulfillmentStatus, InvitationStatus,
+    NameKey, Sponsor,
+};
+use crate::telegram::activity;
+use crate::telegram::adjustments;
+use crate::telegram::attribution;
+use crate::telegram::audit;
+use crate::telegram::caps;
+use crate::telegram::challenges::{self, Outcome};
 use crate::telegram::channels;
+use crate::telegram::codes;
 use crate::telegram::commands::start;
 use crate::telegram::contests;
+use crate::telegram::eligibility;
+use crate::telegram::fraud;
+use crate::telegram::fulfillment;
+use crate::telegram::giveaway;
+use crate::telegram::invitations;
+use crate::telegram::invitee_rewards;
 use crate::telegram::messages::{
-    delete_message, display_main_commands, display_manage_menu, escape_markdown,
+    contests_keyboard, delete_message, display_main_commands, display_manage_menu, escape_markdown,
     remove_loading_icon,
 };
+use crate::telegram::milestones;
+use crate::telegram::networks;
+use crate::telegram::participants;
+use crate::telegram::points;
+use crate::telegram::prizes;
+use crate::telegram::prompts;
+use crate::telegram::relay;
+use crate::telegram::settings;
+use crate::telegram::sponsors;
+use crate::telegram::templates;
 use crate::telegram::users;
 
+/// Alert shown when a button refers to a contest that doesn't exist anymore.
+const GONE: &str = "This contest doesn't exist anymore.";
+
 /// Callback function invoked every time Telegram sends a callback message.
 /// It implements the FSM for the contests management. It alwasy refers to a chan.
 /// In fact, the variable `chan_id` in the code is always defined.
@@ -71,6 +99,8 @@ pub async fn callback(ctx: Context, update: Update) {
     let mut main = false;
     // Start/Stop/Delete Contest commands
     let (mut start_contest, mut delete_contest, mut stop_contest) = (false, false, false);
+    // Contest selection menus and actions on a contest
+    let mut management = None;
     let mut contest_id = 0;
     if data.contains('✅') {
         let mut iter = data.split_ascii_whitespace();
@@ -97,6 +127,94 @@ pub async fn callback(ctx: Context, update: Update) {
             error!("[callback handler] {}", res.err().unwrap());
         }
         return;
+    } else if data.starts_with("challenge") {
+        // Answer to the human-verification challenge: the invitee is not managing anything
+        let mut iter = data.split_ascii_whitespace();
+        iter.next(); // challenge
+        let id: i64 = iter.next().unwrap().parse().unwrap();
+        let answer = iter.next().unwrap_or_default();
+        remove_loading_icon(&ctx, &callback.id, None).await;
+        delete_message(&ctx, chat_id, parent_message).await;
+        if let Some(challenge) = challenges::get(&ctx, id).filter(|c| c.user == sender_id) {
+            answer_challenge(&ctx, &challenge, answer).await;
+        }
+        return;
+    } else if data.starts_with("receipt") {
+        // The winner claims, confirms or disputes the prize: the winner is not managing
+        // anything
+        let mut iter = data.split_ascii_whitespace();
+        iter.next(); // receipt
+        let contest: i64 = iter.next().unwrap().parse().unwrap();
+        let status = FulfillmentStatus::parse(iter.next().unwrap_or_default());
+        let c = contests::get(&ctx, contest);
+        let chan = c.as_ref().and_then(|c| channels::get(&ctx, c.chan));
+        let text = if let (Some(c), Some(chan), Some(status)) = (c, chan, status) {
+            match fulfillment::update(&ctx, &chan, &c, sender_id, status, sender_id).await {
+                Ok(()) if status == FulfillmentStatus::Disputed => {
+                    "The owner has been informed of the problem.".to_string()
+                }
+                Ok(()) => format!("Thank you! The prize is now {status} \u{1f381}"),
+                Err(err) => err,
+            }
+        } else {
+            "This prize doesn't exist anymore.".to_string()
+        };
+        remove_loading_icon(&ctx, &callback.id, Some(&text)).await;
+        return;
+    } else if data.starts_with("participate") {
+        // A member enters a giveaway from the announcement: the member is not managing anything
+        let mut iter = data.split_ascii_whitespace();
+        iter.next(); // participate
+        let contest: i64 = iter.next().unwrap().parse().unwrap();
+        let c = contests::get(&ctx, contest);
+        // The channel where the announcement is posted, among the channels of the giveaway
+        let chan = c.as_ref().and_then(|c| {
+            let chan = iter.next().and_then(|id| id.parse().ok()).unwrap_or(c.chan);
+            networks::includes(&ctx, c, chan)
+                .then(|| channels::get(&ctx, chan))
+                .flatten()
+        });
+        let text = if let (Some(c), Some(chan)) = (c, chan) {
+            giveaway::enter(&ctx, &chan, &c, &callback.from).await
+        } else {
+            "This giveaway doesn't exist anymore.".to_string()
+        };
+        remove_loading_icon(&ctx, &callback.id, Some(&text)).await;
+        return;
+    } else if data.starts_with("sponsors_check") {
+        // The invitee joined the partner chats: the invitee is not managing anything
+        let mut iter = data.split_ascii_whitespace();
+        iter.next(); // sponsors_check
+        let id: i64 = iter.next().unwrap().parse().unwrap();
+        let invite = invitations::get(&ctx, id)
+            .filter(|i| i.dest == sender_id && i.status == InvitationStatus::Pending);
+        let c = invite.as_ref().and_then(|i| contests::get(&ctx, i.contest));
+        let chan = invite.as_ref().and_then(|i| channels::get(&ctx, i.chan));
+        let text = if let (Some(invite), Some(c), Some(chan)) = (invite, c, chan) {
+            let missing = sponsors::missing(&ctx, c.id, sender_id).await;
+            if Utc::now() > c.end {
+                Some("The contest is finished.".to_string())
+            } else if !channels::is_member(&ctx, chan.id, sender_id).await {
+                Some(format!("Join {} first!", chan.name))
+            } else if missing.is_empty() {
+                delete_message(&ctx, chat_id, parent_message).await;
+                record_join(&ctx, sender_id, &chan, &c, Some(id), invite.source).await;
+                None
+            } else {
+                Some(format!(
+                    "Join {} too!",
+                    missing
+                        .iter()
+                        .map(|sponsor| sponsor.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+            }
+        } else {
+            Some("This invitation can't change anymore.".to_string())
+        };
+        remove_loading_icon(&ctx, &callback.id, text.as_deref()).await;
+        return;
     } else if data.starts_with("manage") {
         let mut iter = data.split_ascii_whitespace();
         iter.next(); // manage
@@ -112,6 +230,9 @@ pub async fn callback(ctx: Context, update: Update) {
         iter.next(); // delete
         chan_id = iter.next().unwrap().parse().unwrap();
         create = true;
+    } else if let Some((id, parsed)) = Management::parse(&data) {
+        chan_id = id;
+        management = Some(parsed);
     } else if data.starts_with("delete_contest") {
         let mut iter = data.split_ascii_whitespace();
         iter.next(); // delete
@@ -214,6 +335,21 @@ pub async fn callback(ctx: Context, update: Update) {
     }
     let chan = chan.unwrap();
 
+    let owner_only = management.as_ref().is_some_and(Management::owner_only);
+    if owner_only && chan.registered_by != sender_id {
+        remove_loading_icon(&ctx, &callback.id, Some("You are not the owner!")).await;
+        return;
+    }
+
+    // Back to the management, the owner isn't writing to the winners anymore: e.g. the text
+    // of a new contest must not be relayed
+    let relaying = matches!(management, Some(Management::Contest(_, Action::Relay(..))));
+    if !accepted && !relaying {
+        if let Err(err) = relay::leave(&ctx, sender_id) {
+            error!("[relay leave] {err}");
+        }
+    }
+
     if accepted {
         // getChatMember always returns a ChatMember, even if the user never joined the chan.
         // if the request fails, the user does not exists and we should exit
@@ -226,18 +362,9 @@ pub async fn callback(ctx: Context, update: Update) {
             })
             .await;
 
-        let member_joined = |m: ChatMember| -> bool {
-            match m {
-                ChatMember::Administrator(_)
-                | ChatMember::Creator(_)
-                | ChatMember::Member(_)
-                | ChatMember::Restricted(_) => true,
-                ChatMember::Kicked(_) | ChatMember::Left(_) => false,
-            }
-        };
         match member {
             Ok(m) => {
-                if member_joined(m) {
+                if channels::joined(&m) {
                     let text = format!(
                         "You are already a member of [{}]({})\\.",
                         escape_markdown(&chan.name.to_string(), None),
@@ -268,6 +395,78 @@ pub async fn callback(ctx: Context, update: Update) {
             }
         }
 
+        // Accepting is the first step of the invitation lifecycle: the invitation
+        // is pending until the user joins the channel
+        let c = contests::get(&ctx, contest_id);
+        let invitation = match c {
+            Some(ref c) if Utc::now() <= c.end && networks::includes(&ctx, c, chan.id) => {
+                let res = invitations::create(&ctx, source, dest, chan.id, c.id);
+                if res.is_err() {
+                    error!("[create invitation] {}", res.as_ref().unwrap_err());
+                }
+                res.ok()
+            }
+            _ => None,
+        };
+
+        // The invitations of the participants that don't satisfy the eligibility rules (the
+        // links can be built by hand), the invitees that don't satisfy the filters of the
+        // contest, and the invitations beyond the caps of the contest, are recorded but don't
+        // count
+        let mut rejected = false;
+        if let (Some(id), Some(c)) = (invitation, c.as_ref()) {
+            let member = ctx
+                .api
+                .get_chat_member(GetChatMember {
+                    chat_id: chan.id,
+                    user_id: source,
+                })
+                .await;
+            let participant = match member {
+                Ok(member) => {
+                    eligibility::check_participant(&ctx, &chan, c, member.get_user()).await
+                }
+                Err(err) => Err(err.to_string()),
+            };
+            let filter = if participant.is_ok() {
+                eligibility::check_invitee(&ctx, &chan, c, &callback.from, id).await
+            } else {
+                Ok(())
+            };
+            let cap = (participant.is_ok() && filter.is_ok())
+                .then(|| caps::check(&ctx, c, source, id))
+                .transpose();
+            let reason = match (participant, filter, cap) {
+                (Err(description), _, _) => {
+                    Some(format!("participant not eligible: {description}"))
+                }
+                (_, Err((key, description)), _) => {
+                    Some(format!("invitee filter {key}: {description}"))
+                }
+                (_, _, Err((key, description))) => {
+                    // The participant must know why the invitation doesn't count
+                    let text = format!(
+                        "{} accepted your invitation, but it doesn't count in the {} contest: \
+                        {description}.",
+                        callback.from.first_name, c.name
+                    );
+                    if let Err(err) = ctx.api.send_message(SendMessage::new(source, &text)).await {
+                        error!("[cap notify] {err}");
+                    }
+                    Some(format!("cap {key}: {description}"))
+                }
+                _ => None,
+            };
+            if let Some(reason) = reason {
+                rejected = true;
+                let res =
+                    invitations::set_status(&ctx, id, InvitationStatus::Rejected, Some(&reason));
+                if let Err(err) = res {
+                    error!("[invitee filter] {err}");
+                }
+            }
+        }
+
         let res = ctx
             .api
             .answer_callback_query(AnswerCallbackQuery {
@@ -281,112 +480,17 @@ pub async fn callback(ctx: Context, update: Update) {
         if res.is_err() {
             error!("[callback handler] {}", res.err().unwrap());
         }
-        let text = format!(
-            "Please join \u{1f449} [{}]({}) within the next 10 seconds\\.",
-            escape_markdown(&chan.name.to_string(), None),
-            chan.link
-        );
-        let mut reply = SendMessage::new(sender_id, &text);
-        reply.set_parse_mode(&ParseMode::MarkdownV2);
-        let res = ctx.api.send_message(reply).await;
-        if res.is_err() {
-            let err = res.err().unwrap();
-            error!("[please join] {}", err);
-        }
-
-        sleep(Duration::from_secs(10)).await;
-        let member = ctx
-            .api
-            .get_chat_member(GetChatMember {
-                chat_id: chan.id,
-                user_id: sender_id,
-            })
-            .await;
-
-        // The unwrap is likely to not fail, since the previous request is identical and succeded
-        let joined = member_joined(member.unwrap());
-        if joined {
-            info!("Refer OK!");
-            let c = contests::get(&ctx, contest_id);
-            if c.is_none() {
-                error!("[refer ok] Invalid contest passed in url");
-                let res = ctx
-                    .api
-                    .send_message(SendMessage::new(
-                        sender_id,
-                        "You joined the channel but the contest does not exist.",
-                    ))
-                    .await;
-                if res.is_err() {
-                    let err = res.err().unwrap();
-                    error!("[failed to insert invitation] {}", err);
-                }
-            } else {
-                let c = c.unwrap();
-                let now: DateTime<Utc> = Utc::now();
-                if now > c.end {
-                    info!("Joining with expired contest");
-                    let res = ctx
-                        .api
-                        .send_message(SendMessage::new(
-                            sender_id,
-                            "You joined the group/channel but the contest is finished",
-                        ))
-                        .await;
-                    if res.is_err() {
-                        let err = res.err().unwrap();
-                        error!("[failed to insert invitation] {}", err);
-                    }
-                } else {
-                    let res = {
-                        let guard = ctx.data.read();
-                        let map = guard.get::<DBKey>().expect("db");
-                        let conn = map.get().unwrap();
-                        conn.execute(
-                            "INSERT INTO invitations(source, dest, chan, contest) VALUES(?, ?, ?, ?)",
-                            params![source, dest, chan.id, contest_id],
-                        )
-                    };
-                    if res.is_err() {
-                        let err = res.err().unwrap();
-                        error!("[insert invitation] {}", err);
-                        let res = ctx
-                            .api
-                            .send_message(SendMessage::new(
-                                sender_id,
-                                "Failed to insert invitation: this invitation might already exist!",
-                            ))
-                            .await;
-                        if res.is_err() {
-                            let err = res.err().unwrap();
-                            error!("[failed to insert invitation] {}", err);
-                        }
-                    } else {
-                        let text = format!(
-                            "You joined [{}]({}) \u{1f917}",
-                            escape_markdown(&chan.name.to_string(), None),
-                            chan.link
-                        );
-                        let mut reply = SendMessage::new(sender_id, &text);
-                        reply.set_parse_mode(&ParseMode::MarkdownV2);
-                        let res = ctx.api.send_message(reply).await;
-                        if res.is_err() {
-                            let err = res.err().unwrap();
-                            error!("[joined send] {}", err);
-                        }
-                    }
-                }
-            }
-        } else {
-            info!("User not joined the channel after 10 seconds...");
-            let text = escape_markdown("You haven't joined the channel within 10 seconds :(", None);
-            let mut reply = SendMessage::new(sender_id, &text);
-            reply.set_parse_mode(&ParseMode::MarkdownV2);
-            let res = ctx.api.send_message(reply).await;
-            if res.is_err() {
-                let err = res.err().unwrap();
-                error!("[not join] {}", err);
+        // With the human verification enabled, the invitee must solve the challenge before
+        // being asked to join: the answer arrives in another update
+        let kind = c.as_ref().map_or_else(String::new, |c| {
+            settings::get(&ctx, c.id, settings::CHALLENGE)
+        });
+        match invitation {
+            Some(id) if !rejected && kind != "none" && !kind.is_empty() => {
+                // The unanswered challenge expires on the late answer, or in the scheduler
+                challenges::send(&ctx, &kind, id, sender_id).await;
             }
+            _ => join(&ctx, sender_id, &chan, c, invitation, source).await,
         }
         delete_message(&ctx, chat_id, parent_message).await;
     }
@@ -473,8 +577,11 @@ pub async fn callback(ctx: Context, update: Update) {
                         .iter()
                         .map(|contest| InlineKeyboardButton {
                             text: contest.name.clone(),
-                            // stop_contest, channel id, contest id
-                            callback_data: Some(format!("stop_contest {} {}", chan.id, contest.id)),
+                            // review_contest, channel id, contest id
+                            callback_data: Some(format!(
+                                "review_contest {} {}",
+                                chan.id, contest.id
+                            )),
                             callback_game: None,
                             login_url: None,
                             pay: None,
@@ -502,7 +609,10 @@ pub async fn callback(ctx: Context, update: Update) {
 
     if stop_contest {
         // Clean up ranks from users that joined and then left the channel
-        let c = contests::get(&ctx, contest_id).unwrap();
+        let Some(c) = of_channel(contests::get(&ctx, contest_id), &chan) else {
+            remove_loading_icon(&ctx, &callback.id, Some(GONE)).await;
+            return;
+        };
         if c.stopped {
             let reply = SendMessage::new(chat_id, "Contest already stopped. Doing nothing.");
             let res = ctx.api.send_message(reply).await;
@@ -512,149 +622,9 @@ pub async fn callback(ctx: Context, update: Update) {
             }
             display_manage_menu(&ctx, chat_id, &chan).await;
             delete_message(&ctx, chat_id, parent_message).await;
-        } else {
-            contests::validate_users(&ctx, &c).await;
-
-            // Stop contest on db
-            let c = {
-                let guard = ctx.data.read();
-                let map = guard.get::<DBKey>().expect("db");
-                let conn = map.get().unwrap();
-                let mut stmt = conn.prepare("UPDATE contests SET stopped = TRUE WHERE id = ? RETURNING name, prize, end, started_at").unwrap();
-                let mut iter = stmt
-                    .query_map(params![contest_id], |row| {
-                        Ok(Contest {
-                            id: contest_id,
-                            name: row.get(0)?,
-                            prize: row.get(1)?,
-                            end: row.get(2)?,
-                            started_at: row.get(3)?,
-                            stopped: true,
-                            chan: chan.id,
-                        })
-                    })
-                    .unwrap();
-                iter.next().unwrap().unwrap()
-            };
-
-            // Create rank
-            let rank = contests::ranking(&ctx, &c);
-            if rank.is_empty() {
-                // No one partecipated in the challenge
-                let reply = SendMessage::new(
-                    sender_id,
-                    "No one partecipated to the challenge. Doing nothing.",
-                );
-                let res = ctx.api.send_message(reply).await;
-                if res.is_err() {
-                    let err = res.err().unwrap();
-                    error!("[stop send] {}", err);
-                }
-                display_manage_menu(&ctx, chat_id, &chan).await;
-                delete_message(&ctx, chat_id, parent_message).await;
-            } else {
-                // Send top-10 to the channel and pin the message
-                let mut m = format!("\u{1f3c6} Contest ({}) finished \u{1f3c6}\n\n\n", c.name);
-                let winner = rank[0].user.clone();
-                for row in rank {
-                    let user = row.user;
-                    let rank = row.rank;
-                    let invites = row.invites;
-                    if rank == 1 {
-                        m += "\u{1f947}#1!";
-                    } else if rank <= 3 {
-                        m += &format!("\u{1f3c6} #{rank}");
-                    } else {
-                        m += &format!("#{rank}");
-                    }
-
-                    m += &format!(
-                        " {}{}{} - {}\n",
-                        user.first_name,
-                        match user.last_name {
-                            Some(last_name) => format!(" {last_name}"),
-                            None => String::new(),
-                        },
-                        match user.username {
-                            Some(username) => format!(" ({username})"),
-                            None => String::new(),
-                        },
-                        invites
-                    );
-                }
-                m += &format!(
-                    "\n\nThe prize ({}) is being delivered to our champion \u{1f947}. Congratulations!!",
-                    c.prize
-                );
-
-                m = escape_markdown(&m, None);
-
-                let mut reply = SendMessage::new(c.chan, &m);
-                reply.set_parse_mode(&ParseMode::MarkdownV2);
-                let res = ctx.api.send_message(reply).await;
-                if res.is_err() {
-                    let err = res.unwrap_err();
-                    error!("[send message] {}", err);
-                } else {
-                    // Pin message
-                    let res = ctx
-                        .api
-                        .pin_chat_message(PinChatMessage {
-                            chat_id: c.chan,
-                            message_id: res.unwrap().message_id,
-                            disable_notification: false,
-                        })
-                        .await;
-                    if res.is_err() {
-                        let err = res.unwrap_err();
-                        error!("[stop pin message] {}", err);
-                        let reply = SendMessage::new(sender_id, &err.to_string());
-                        let res = ctx.api.send_message(reply).await;
-                        if res.is_err() {
-                            error!("[stop pin message2] {}", res.unwrap_err());
-                        }
-                    }
-                }
-
-                // Put into communication the bot user and the winner
-                let direct_communication = winner.username.is_some();
-                let text = if direct_communication {
-                    let username = winner.username.unwrap();
-                    format!("The winner usename is @{username}. Get in touch and send the prize!")
-                } else {
-                    "The winner has no username. It means you can communicate only through the bot.\n\n\
-                Write NOW a message that will be delivered to the winner (if you can, just send the prize!).\n\n
-                NOTE: You can only send up to one message, hence a good idea is to share your username with the winner\
-                in order to make they start a commucation with you in private.".to_string()
-                };
-                let mut reply = SendMessage::new(sender_id, &escape_markdown(&text, None));
-                reply.set_parse_mode(&ParseMode::MarkdownV2);
-                let res = ctx.api.send_message(reply).await;
-                if res.is_err() {
-                    let err = res.err().unwrap();
-                    error!("[stop send] {}", err);
-                }
-                if !direct_communication {
-                    // Outside of FSM
-                    let res = {
-                        let guard = ctx.data.read();
-                        let map = guard.get::<DBKey>().expect("db");
-                        let conn = map.get().unwrap();
-                        // add user to contact, the owner (me), the contest
-                        // in order to add more constraint to verify outside of this FMS
-                        // to validate and put the correct owner in contact with the correct winner
-                        conn.execute(
-                            "INSERT INTO being_contacted_users(user, owner) VALUES(?, ?)",
-                            params![winner.id, sender_id],
-                        )
-                    };
-
-                    if res.is_err() {
-                        let err = res.err().unwrap();
-                        error!("[insert being_contacted_users] {}", err);
-                    }
-                }
-            }
+        } else if !contests::stop(&ctx, &chan, &c, sender_id).await {
+            display_manage_menu(&ctx, chat_id, &chan).await;
+            delete_message(&ctx, chat_id, parent_message).await;
         }
 
         remove_loading_icon(&ctx, &callback.id, None).await;
@@ -689,6 +659,12 @@ pub async fn callback(ctx: Context, update: Update) {
             error!("[create send] {}", err);
         }
 
+        // The next message is the new contest: it's not an answer to a previous prompt
+        let res = prompts::clear(&ctx, sender_id);
+        if res.is_err() {
+            error!("[clear prompts] {}", res.unwrap_err());
+        }
+
         // adding chan to being_managed_channels since the raw
         // reply falls outiside this FSM
         let res = {
@@ -832,19 +808,13 @@ pub async fn callback(ctx: Context, update: Update) {
     }
 
     if delete_contest {
-        let res = {
-            let guard = ctx.data.read();
-            let map = guard.get::<DBKey>().expect("db");
-            let conn = map.get().unwrap();
-            let mut stmt = conn.prepare("DELETE FROM contests WHERE id = ?").unwrap();
-            stmt.execute(params![contest_id])
-        };
-        let text = if res.is_err() {
-            let err = res.unwrap_err();
-            error!("[delete from contests] {}", err);
-            format!("Error: {err}. You can't stop a contest with already some partecipant, this is unfair!")
-        } else {
-            "Done!".to_string()
+        let res = contests::delete(&ctx, contest_id, chan.id);
+        let text = match res {
+            Ok(()) => "Done!".to_string(),
+            Err(reason) => {
+                error!("[delete from contests] {reason}");
+                format!("You can't delete the contest: {reason}.")
+            }
         };
         let res = ctx
             .api
@@ -860,9 +830,23 @@ pub async fn callback(ctx: Context, update: Update) {
         delete_message(&ctx, chat_id, parent_message).await;
     }
 
+    match management {
+        Some(Management::Menu(menu)) => menu.select(&ctx, callback, &chan).await,
+        Some(Management::Contest(contest, action)) => {
+            if let Some(c) = of_channel(contests::get(&ctx, contest), &chan) {
+                action.execute(&ctx, callback, &chan, &c).await;
+            } else {
+                remove_loading_icon(&ctx, &callback.id, Some(GONE)).await;
+            }
+        }
+        None => {}
+    }
+
     if start_contest {
-        // if contest_id is not valid, this panics (that's ok, the user is doing nasty things)
-        let c = contests::get(&ctx, contest_id).unwrap();
+        let Some(c) = of_channel(contests::get(&ctx, contest_id), &chan) else {
+            remove_loading_icon(&ctx, &callback.id, Some(GONE)).await;
+            return;
+        };
         if c.started_at.is_some() {
             let text = "You can't start an already started contest.";
             let res = ctx
@@ -874,120 +858,7 @@ pub async fn callback(ctx: Context, update: Update) {
                 error!("[send message] {}", err);
             }
         } else {
-            let c = {
-                let now: DateTime<Utc> = Utc::now();
-                let guard = ctx.data.read();
-                let map = guard.get::<DBKey>().expect("db");
-                let conn = map.get().unwrap();
-                let mut stmt = conn.prepare("UPDATE contests SET started_at = ? WHERE id = ? RETURNING name, prize, end").unwrap();
-                let mut iter = stmt
-                    .query_map(params![now, contest_id], |row| {
-                        Ok(Contest {
-                            id: contest_id,
-                            name: row.get(0)?,
-                            prize: row.get(1)?,
-                            end: row.get(2)?,
-                            started_at: Some(now),
-                            stopped: false,
-                            chan: chan.id,
-                        })
-                    })
-                    .unwrap();
-                iter.next().unwrap()
-            };
-            let text = if c.is_err() {
-                let err = c.as_ref().err().unwrap();
-                error!("[update/start contest] {}", err);
-                err.to_string()
-            } else {
-                "Contest started!".to_string()
-            };
-            let res = ctx
-                .api
-                .send_message(SendMessage::new(sender_id, &text))
-                .await;
-            if res.is_err() {
-                let err = res.err().unwrap();
-                error!("[send message] {}", err);
-            }
-
-            if c.is_ok() {
-                let c = c.unwrap();
-                // Send message in the channel, indicating the contest name
-                // the end date, the prize, and pin it on top until the end date comes
-                // or the contest is stopped or deleted
-                let bot_name = {
-                    let guard = ctx.data.read();
-                    guard
-                        .get::<NameKey>()
-                        .expect("name")
-                        .clone()
-                        .replace('@', "")
-                };
-                let params =
-                    BASE64URL.encode(format!("chan={}&contest={}", chan.id, c.id).as_bytes());
-                let text = format!(
-                    "{title}\n\n{rules}\n\n{bot_link}",
-                    title = escape_markdown(
-                        &format!(
-                            "\u{1f525}{name} contest \u{1f525}\nWho invites more friends wins a {prize}!",
-                            prize = c.prize,
-                            name = c.name
-                        ),
-                        None
-                    ),
-                    rules = format!(
-                        "{} **{prize}**\n{disclaimer}",
-                        escape_markdown(
-                            &format!(
-                                "1. Start the contest bot using the link below\n\
-                            2. The bot gives you a link\n\
-                            3. Share the link with your friends!\n\n\
-                            At the end of the contest ({end_date}) the user that referred more friends \
-                            will win a ",
-                                end_date = c.end
-                            ),
-                            None
-                        ),
-                        prize = escape_markdown(&c.prize, None),
-                        disclaimer =
-                            escape_markdown("You can check your rank with the /rank command", None),
-                    ),
-                    bot_link = escape_markdown(
-                        &format!(
-                            "https://t.me/{bot_name}?start={params}"
-                        ),
-                        None
-                    ),
-                );
-
-                let mut reply = SendMessage::new(c.chan, &text);
-                reply.set_parse_mode(&ParseMode::MarkdownV2);
-                let res = ctx.api.send_message(reply).await;
-                if res.is_err() {
-                    let err = res.unwrap_err();
-                    error!("[send message] {}", err);
-                } else {
-                    // Pin message
-                    let res = ctx
-                        .api
-                        .pin_chat_message(PinChatMessage {
-                            chat_id: c.chan,
-                            message_id: res.unwrap().message_id,
-                            disable_notification: false,
-                        })
-                        .await;
-                    if res.is_err() {
-                        let err = res.unwrap_err();
-                        error!("[pin message] {}", err);
-                        let reply = SendMessage::new(sender_id, &err.to_string());
-                        let res = ctx.api.send_message(reply).await;
-                        if res.is_err() {
-                            error!("[pin message2] {}", res.unwrap_err());
-                        }
-                    }
-                }
-            }
+            contests::start(&ctx, &chan, contest_id, sender_id).await;
         }
 
         remove_loading_icon(&ctx, &callback.id, None).await;
@@ -1043,8 +914,25 @@ pub async fn message(ctx: Context, update: Update) {
         // and we should check if the message is among the accepted ones.
         //
         // It can be a group registration flow, or a channel begin managed, or other.
+        // The invitees writing in a group earn the activity bonus of the points model
+        if message.chat.get_id() != sender_id {
+            let res = points::record_activity(&ctx, message.chat.get_id(), sender_id);
+            if let Err(err) = res {
+                error!("[record activity] {err}");
+            }
+        }
         let text = message.get_text();
+        // The messages written in a group count in its activity contests
+        if let (Chat::Group(_) | Chat::SuperGroup(_), Some(text)) = (&message.chat, &text) {
+            let from = message.from.as_ref().unwrap();
+            let res = activity::record(&ctx, message.chat.get_id(), from, text).await;
+            if let Err(err) = res {
+                error!("[record message] {err}");
+            }
+        }
         if text.is_none() {
+            // Media can be sent only through the relay between owners and winners
+            relay::deliver(&ctx, message).await;
             return;
         }
         let text = text.unwrap();
@@ -1071,7 +959,7 @@ pub async fn message(ctx: Context, update: Update) {
                     error!("[inner start] {:?}", res.unwrap_err());
                 }
             } else {
-                let commands = vec!["help", "register", "contest", "list", "rank"];
+                let commands = vec!["help", "register", "contest", "list", "rank", "results"];
                 for command in commands {
                     if text.starts_with(&format!("/{command}@{bot_name}")) {
                         let chat_id = message.chat.get_id();
@@ -1089,6 +977,14 @@ pub async fn message(ctx: Context, update: Update) {
             return;
         }
 
+        // An invitee can be answering to the human-verification challenge, in the private chat
+        if message.chat.get_id() == sender_id {
+            if let Some(challenge) = challenges::waiting_text(&ctx, sender_id) {
+                answer_challenge(&ctx, &challenge, &text).await;
+                return;
+            }
+        }
+
         // From here below, we are interested only in messages sent from owners
         let owners = users::owners(&ctx)
             .iter()
@@ -1096,12 +992,27 @@ pub async fn message(ctx: Context, update: Update) {
             .collect::<Vec<i64>>();
         let is_owner = owners.iter().any(|&id| id == sender_id);
         if !is_owner {
+            // A winner can be answering to the owner through the relay
+            relay::deliver(&ctx, message).await;
             return;
         }
 
-        // Check if some of the user channel's are being managed
-        // in that case it's plausible that the user is sending the message in this format
-        // ```
+        // The owner can be answering to a prompt of the contest management, in the private chat
+        if message.chat.get_id() == sender_id {
+            if let Some(prompt) = prompts::pending(&ctx, sender_id) {
+                answer_prompt(&ctx, &prompt, &text).await;
+                return;
+            }
+        }
+
+        // The owner can be chatting with a winner (or be a winner, chatting with another owner)
+        if relay::deliver(&ctx, message).await {
+            return;
+        }
+
+        // Check if some of the user channel's are being managed
+        // in that case it's plausible that the user is sending the message in this format
+        // ```
         // contest name
         // end date (YYYY-MM-DD hh:mm TZ)
         // prize
@@ -1200,78 +1111,1255 @@ pub async fn message(ctx: Context, update: Update) {
                 // else, if no channel is being edited, but we received a 3 lines message
                 // it's just a message, do nothing (?)
             }
-        } else {
-            // text splitted in a number of rows != 3 -> it can be a message
-            // being sent from an owner to a winner
-            let winner = {
-                let guard = ctx.data.read();
-                let map = guard.get::<DBKey>().expect("db");
-                let conn = map.get().unwrap();
-                // In the being_contacted_users we have all the winner to be ever contacted
-                // we can join the contest and the owner and filter with the current user_id
-                // limiting only by the last one that matches all these conditions, to be almost
-                // sure to link the owner with the winner (correct pair)
-                let mut stmt = conn
-                        .prepare(
-                            "SELECT users.id, users.first_name, users.last_name, users.username FROM users \
-                            INNER JOIN being_contacted_users ON users.id = being_contacted_users.user \
-                            WHERE being_contacted_users.owner = ? AND being_contacted_users.contacted IS FALSE \
-                            ORDER BY being_contacted_users.id DESC LIMIT 1"
-                        )
-                        .unwrap();
-                let user = stmt
-                    .query_map(params![sender_id], |row| {
-                        Ok(User {
-                            id: row.get(0)?,
-                            first_name: row.get(1)?,
-                            last_name: row.get(2)?,
-                            username: row.get(3)?,
-                        })
-                    })
-                    .unwrap()
-                    .map(Result::unwrap)
-                    .next();
-                user
-            };
-            if winner.is_some() {
-                let winner = winner.unwrap();
-                let mut reply = SendMessage::new(winner.id, &text);
+        }
+    }
+
+    info!("message handler end");
+}
+
+/// The contest selection menus of the management interface: `<menu> <chan>`.
+#[derive(Debug, Clone, Copy)]
+enum Menu {
+    /// The started contests, to inspect their invitations
+    Breakdown,
+    /// The started contests, to manage their participants
+    Participants,
+    /// The contests not finished yet, to configure them
+    Settings,
+    /// All the contests, to repeat or clone them
+    Templates,
+}
+
+impl Menu {
+    /// Sends to the owner the contests of the channel `chan` to choose from, or tells that
+    /// there are none.
+    ///
+    /// # Arguments
+    /// * `ctx` - Telexide context
+    /// * `callback` - The callback of the pressed button
+    /// * `chan` - The channel
+    async fn select(self, ctx: &Context, callback: &CallbackQuery, chan: &Channel) {
+        let (none, text, next) = match self {
+            Menu::Breakdown => (
+                "You have no started contests!",
+                "Select the contest to inspect",
+                "breakdown_contest",
+            ),
+            Menu::Participants => (
+                "You have no started contests!",
+                "Select the contest whose participants you want to manage",
+                "participants_contest",
+            ),
+            Menu::Settings => (
+                "You have no contests to configure!",
+                "Select the contest to configure",
+                "settings_contest",
+            ),
+            Menu::Templates => (
+                "You have no contests to repeat!",
+                "Select the contest to repeat or clone",
+                "templates_contest",
+            ),
+        };
+        let contests = contests::get_all(ctx, chan.id)
+            .into_iter()
+            .filter(|c| match self {
+                Menu::Breakdown | Menu::Participants => c.started_at.is_some(),
+                Menu::Settings => !c.stopped,
+                Menu::Templates => true,
+            })
+            .collect::<Vec<Contest>>();
+        if contests.is_empty() {
+            remove_loading_icon(ctx, &callback.id, Some(none)).await;
+            return;
+        }
+        let mut reply = SendMessage::new(callback.from.id, &escape_markdown(text, None));
+        reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+            inline_keyboard: contests_keyboard(&contests, next, chan.id),
+        }));
+        reply.set_parse_mode(&ParseMode::MarkdownV2);
+        if let Err(err) = ctx.api.send_message(reply).await {
+            error!("[{next} send] {err}");
+        }
+        remove_loading_icon(ctx, &callback.id, None).await;
+        let message = callback.message.as_ref().unwrap();
+        delete_message(ctx, message.chat.get_id(), message.message_id).await;
+    }
+}
+
+/// The actions on a contest of the management interface:
+/// `<action> <chan> <contest> [<arguments>]`.
+#[derive(Debug, Clone)]
+enum Action {
+    /// The suspicious activity report, shown before stopping the contest
+    Review,
+    /// The invitations breakdown per participant
+    Breakdown,
+    /// The participants of the contest
+    Participants,
+    /// The detail of a participant
+    Participant(i64),
+    /// Invalidates an invitation
+    Revoke(i64),
+    /// Restores an invitation
+    Restore(i64),
+    /// Disqualifies a participant, telling the participant or not
+    Disqualify(i64, bool),
+    /// Cancels the disqualification of a participant
+    Requalify(i64),
+    /// Adjusts the credit of a participant
+    Adjust(i64),
+    /// The audit log
+    Audit,
+    /// The settings, after changing the setting with the key, if any
+    Settings(Option<String>),
+    /// Replaces the allowlist
+    Allowlist,
+    /// The milestone rewards
+    Milestones,
+    /// Replaces the milestones
+    MilestonesEdit,
+    /// Adds codes to the stock
+    Codes(String),
+    /// The stocks of prize codes
+    Prizes,
+    /// The fulfillment of the prizes, after changing the status of a winner, if any
+    Fulfillment(Option<(i64, FulfillmentStatus)>),
+    /// Opens, leaves or closes the chat with a winner
+    Relay(i64, String),
+    /// The reward of the invitees
+    InviteeReward,
+    /// Replaces the message sent to the invitees
+    InviteeMessage,
+    /// The points model
+    Points,
+    /// Replaces the points model
+    PointsEdit,
+    /// The channels of the contest, after linking or unlinking a channel, if any
+    Network(Option<i64>),
+    /// The partner chats, after removing one, if any
+    Sponsors(Option<i64>),
+    /// Adds a partner chat
+    SponsorAdd,
+    /// The recurring contests repeating the contest
+    Templates,
+    /// Adds a recurring contest
+    TemplateAdd,
+    /// Removes a recurring contest
+    TemplateRemove(i64),
+    /// Clones the contest as a draft
+    TemplateClone,
+}
+
+impl Action {
+    /// Executes the action on the contest `c` of the channel `chan`, pressed by the owner.
+    ///
+    /// # Arguments
+    /// * `ctx` - Telexide context
+    /// * `callback` - The callback of the pressed button
+    /// * `chan` - The channel
+    /// * `c` - The contest
+    async fn execute(self, ctx: &Context, callback: &CallbackQuery, chan: &Channel, c: &Contest) {
+        let owner = callback.from.id;
+        let message = callback.message.as_ref().unwrap();
+        let (chat_id, parent_message) = (message.chat.get_id(), message.message_id);
+        let mut alert = None;
+        match self {
+            Action::Review => {
+                // The owner reviews the suspicious participants before publishing the results
+                remove_loading_icon(ctx, &callback.id, None).await;
+                fraud::display_report(ctx, chat_id, chan, c).await;
+                delete_message(ctx, chat_id, parent_message).await;
+                return;
+            }
+            Action::Breakdown | Action::Audit => {
+                let (text, tag) = if let Action::Audit = self {
+                    (audit_log(ctx, c.id), "audit log")
+                } else {
+                    (breakdown(ctx, c.id), "breakdown contest")
+                };
+                let mut reply = SendMessage::new(owner, &text);
                 reply.set_parse_mode(&ParseMode::MarkdownV2);
-                let res = ctx.api.send_message(reply).await;
-                if res.is_err() {
-                    let err = res.err().unwrap();
-                    error!("[winner communication] {}", err);
+                if let Err(err) = ctx.api.send_message(reply).await {
+                    error!("[{tag}] {err}");
+                }
+                display_manage_menu(ctx, chat_id, chan).await;
+            }
+            Action::Participants => participants::display_list(ctx, chat_id, chan, c).await,
+            Action::Participant(user) => participants::display(ctx, chat_id, chan, c, user).await,
+            Action::Revoke(id) | Action::Restore(id) => {
+                let revoke = matches!(self, Action::Revoke(_));
+                if let Some((source, res)) = invitation(ctx, c, id, revoke, owner) {
+                    alert = Some(res);
+                    participants::display(ctx, chat_id, chan, c, source).await;
                 } else {
-                    let reply = SendMessage::new(sender_id, "Message delivered to the winner!");
-                    let res = ctx.api.send_message(reply).await;
-                    if res.is_err() {
-                        let err = res.err().unwrap();
-                        error!("[winner postcom] {}", err);
+                    participants::display_list(ctx, chat_id, chan, c).await;
+                }
+            }
+            Action::Requalify(user) => {
+                let res = participants::requalify(ctx, c.id, user, owner);
+                alert = Some(done(res, "requalify"));
+                participants::display(ctx, chat_id, chan, c, user).await;
+            }
+            Action::Settings(key) => {
+                if let Some(key) = key {
+                    let setting = settings::definition(&key);
+                    if let Err(err) = settings::changeable(c, &key) {
+                        alert = Some(err);
+                    } else if setting.choices.is_empty() {
+                        // Numeric settings are written by the owner, outside of this FSM
+                        let text = format!(
+                            "Write the new value of {} (0 for unlimited).",
+                            setting.label
+                        );
+                        ask(ctx, owner, c.id, "setting", Some(setting.key), &text).await;
+                        remove_loading_icon(ctx, &callback.id, None).await;
+                        return;
+                    } else if let Err(err) = settings::cycle(ctx, c.id, &key, owner) {
+                        error!("[set setting] {err}");
+                        alert = Some(format!("Error: {err}"));
                     }
-                    // Set the winner user as contacted
-                    let res = {
-                        let guard = ctx.data.read();
-                        let map = guard.get::<DBKey>().expect("db");
-                        let conn = map.get().unwrap();
-                        // add user to contact, the owner (me), the contest
-                        // in order to add more constraint to verify outside of this FMS
-                        // to validate and put the correct owner in contact with the correct winner
-                        conn.execute(
-                            "UPDATE being_contacted_users SET contacted = TRUE WHERE owner = ? AND user = ?",
-                            params![sender_id, winner.id],
-                        )
-                    };
+                }
+                settings::display(ctx, chat_id, chan, c).await;
+            }
+            Action::Milestones => milestones::display(ctx, chat_id, chan, c).await,
+            Action::Prizes => prizes::display(ctx, chat_id, chan, c).await,
+            Action::Fulfillment(mark) => {
+                if let Some((winner, status)) = mark {
+                    let res = fulfillment::update(ctx, chan, c, winner, status, owner).await;
+                    alert = res.err();
+                }
+                fulfillment::display(ctx, chat_id, chan, c).await;
+            }
+            Action::Relay(winner, action) => {
+                let alert = relay(ctx, chan, c, winner, &action, owner).await;
+                remove_loading_icon(ctx, &callback.id, alert.as_deref()).await;
+                return;
+            }
+            Action::InviteeReward => invitee_rewards::display(ctx, chat_id, chan, c).await,
+            Action::Points => points::display(ctx, chat_id, chan, c).await,
+            Action::Network(toggle) => {
+                if let Some(other) = toggle {
+                    let res = channels::get(ctx, other).map_or_else(
+                        || Err("This channel doesn't exist anymore.".to_string()),
+                        |other| networks::toggle(ctx, c, &other, owner),
+                    );
+                    alert = res.err();
+                }
+                networks::display(ctx, chat_id, chan, c).await;
+            }
+            Action::Sponsors(remove) => {
+                alert = remove.and_then(|chat| sponsors::remove(ctx, c, chat, owner).err());
+                sponsors::display(ctx, chat_id, chan, c).await;
+            }
+            Action::Templates | Action::TemplateRemove(_) | Action::TemplateClone => {
+                alert = template(ctx, c, &self, owner);
+                templates::display(ctx, chat_id, chan, c).await;
+            }
+            prompt => alert = prompt.ask(ctx, owner, c).await,
+        }
+        remove_loading_icon(ctx, &callback.id, alert.as_deref()).await;
+        delete_message(ctx, chat_id, parent_message).await;
+    }
 
-                    if res.is_err() {
-                        let err = res.err().unwrap();
-                        error!("[insert being_contacted_users] {}", err);
+    /// Asks the `owner` to write the answer of the action, outside of this FSM: the action is
+    /// completed by `answer_prompt`. Returns the alert for the owner, if the action isn't
+    /// allowed.
+    ///
+    /// # Arguments
+    /// * `ctx` - Telexide context
+    /// * `owner` - The owner of the contest
+    /// * `c` - The contest
+    async fn ask(self, ctx: &Context, owner: i64, c: &Contest) -> Option<String> {
+        // The rules outside of the settings are locked like the settings
+        if matches!(
+            self,
+            Action::MilestonesEdit | Action::PointsEdit | Action::InviteeMessage
+        ) {
+            if let Err(err) = settings::rules_changeable(c) {
+                return Some(err);
+            }
+        }
+        let (action, arg, text) = match self {
+            Action::Disqualify(user, notify) => (
+                "disqualify",
+                Some(format!("{user} {}", u8::from(notify))),
+                "Write the reason of the disqualification.".to_string(),
+            ),
+            Action::Adjust(user) => (
+                "adjust",
+                Some(user.to_string()),
+                "Write the points to add (e.g. +2) or to remove (e.g. -1), followed by the \
+                reason.\n\nExample: +1 joined after the 10 seconds window"
+                    .to_string(),
+            ),
+            Action::Allowlist => (
+                "allowlist",
+                None,
+                "Write the users allowed to participate: usernames or user IDs, separated by \
+                spaces or new lines. The current allowlist is replaced."
+                    .to_string(),
+            ),
+            Action::MilestonesEdit => (
+                "milestones",
+                None,
+                "Write the milestones, one per line: the number of invites to reach and the \
+                reward (e.g. \"5 Sticker pack\"). The current milestones are replaced."
+                    .to_string(),
+            ),
+            Action::PointsEdit => (
+                "points",
+                None,
+                "Write the points model: the points per invite, the bonus per invitee still a \
+                member when the contest finishes, the bonus per invitee active in the group, and \
+                the penalty per invitee that left (e.g. \"1 1 2 1\"). Write \"-\" to count the \
+                invites instead."
+                    .to_string(),
+            ),
+            Action::SponsorAdd => (
+                "sponsor",
+                None,
+                "Write the ID of the partner channel or group (e.g. -1001234567890). The bot must \
+                be an admin of it, to check the membership of the invitees."
+                    .to_string(),
+            ),
+            Action::TemplateAdd => (
+                "template",
+                None,
+                "Write the recurring contest in 4 lines: the name, the prize, how long every \
+                instance lasts (e.g. 7d or 12h), and the schedule: weekly (Monday 00:00 UTC), \
+                monthly (the first day at 00:00 UTC), or a cron expression in UTC (e.g. \
+                \"0 18 * * 5\"). The name can contain {n}, {date}, {week} and {month}, e.g. \
+                \"Monthly referral race {month}\"."
+                    .to_string(),
+            ),
+            Action::InviteeMessage => (
+                "invitee_message",
+                None,
+                "Write the message sent to the invitees once their invitation qualifies, or \
+                \"-\" to remove it."
+                    .to_string(),
+            ),
+            Action::Codes(pool) => {
+                let text = format!(
+                    "Write the codes to add to the stock {pool}, separated by spaces or new \
+                    lines. Every code is sent once."
+                );
+                ("codes", Some(pool), text)
+            }
+            _ => return None,
+        };
+        ask(ctx, owner, c.id, action, arg.as_deref(), &text).await;
+        None
+    }
+}
+
+/// A button of the management interface of a channel.
+#[derive(Debug, Clone)]
+enum Management {
+    /// Selects a contest of the channel
+    Menu(Menu),
+    /// Acts on the contest with the ID
+    Contest(i64, Action),
+}
+
+impl Management {
+    /// Parses the `data` of a callback: returns the channel and the pressed button, or `None`
+    /// if the data doesn't come from the management interface.
+    ///
+    /// # Arguments
+    /// * `data` - The data of the callback
+    fn parse(data: &str) -> Option<(i64, Self)> {
+        let args = data.split_ascii_whitespace().collect::<Vec<_>>();
+        let number = |i: usize| args.get(i).and_then(|arg| arg.parse::<i64>().ok());
+        let name = *args.first()?;
+        let chan = number(1)?;
+        let menu = match name {
+            "breakdown" => Menu::Breakdown,
+            "participants" => Menu::Participants,
+            "settings" => Menu::Settings,
+            "templates" => Menu::Templates,
+            _ => {
+                let action = Self::action(name, &args, number(3))?;
+                return Some((chan, Management::Contest(number(2)?, action)));
+            }
+        };
+        Some((chan, Management::Menu(menu)))
+    }
+
+    /// Returns the action on a contest with the `name`, given the `args` of the callback and
+    /// its `target` (a user, an invitation, a channel or a template).
+    ///
+    /// # Arguments
+    /// * `name` - The name of the action
+    /// * `args` - All the arguments of the callback, the name included
+    /// * `target` - The fourth argument, if it's a number
+    fn action(name: &str, args: &[&str], target: Option<i64>) -> Option<Action> {
+        let arg = |i: usize| args.get(i).copied().unwrap_or_default();
+        Some(match name {
+            "review_contest" => Action::Review,
+            "breakdown_contest" => Action::Breakdown,
+            "participants_contest" => Action::Participants,
+            "participant" => Action::Participant(target?),
+            "revoke" => Action::Revoke(target?),
+            "restore" => Action::Restore(target?),
+            "dq" => Action::Disqualify(target?, arg(4) == "1"),
+            "requalify" => Action::Requalify(target?),
+            "adjust" => Action::Adjust(target?),
+            "audit" => Action::Audit,
+            "settings_contest" => Action::Settings(None),
+            // The unknown settings are ignored
+            "set" => settings::SETTINGS
+                .iter()
+                .any(|s| s.key == arg(3))
+                .then(|| Action::Settings(Some(arg(3).to_string())))?,
+            "allowlist" => Action::Allowlist,
+            "milestones" => Action::Milestones,
+            "milestones_edit" => Action::MilestonesEdit,
+            "codes" => Action::Codes(args.get(3)?.to_string()),
+            "prizes" => Action::Prizes,
+            "fulfillment" => Action::Fulfillment(None),
+            // An invalid status is ignored: the transition to notified is never allowed
+            "mark" => Action::Fulfillment(Some((
+                target?,
+                FulfillmentStatus::parse(arg(4)).unwrap_or(FulfillmentStatus::Notified),
+            ))),
+            "relay" => Action::Relay(target?, arg(4).to_string()),
+            "invitee_reward" => Action::InviteeReward,
+            "invitee_message" => Action::InviteeMessage,
+            "points" => Action::Points,
+            "points_edit" => Action::PointsEdit,
+            "network" => Action::Network(None),
+            "network_toggle" => Action::Network(Some(target?)),
+            "sponsors" => Action::Sponsors(None),
+            "sponsor_add" => Action::SponsorAdd,
+            "sponsor_remove" => Action::Sponsors(Some(target?)),
+            "templates_contest" => Action::Templates,
+            "template_add" => Action::TemplateAdd,
+            "template_remove" => Action::TemplateRemove(target?),
+            "template_clone" => Action::TemplateClone,
+            _ => return None,
+        })
+    }
+
+    /// Returns true if only the owner of the channel can press the button: the admins can
+    /// review a contest before stopping it.
+    fn owner_only(&self) -> bool {
+        !matches!(self, Management::Contest(_, Action::Review))
+    }
+}
+
+/// Returns the contest `c`, if it belongs to the channel `chan` whose buttons were pressed: the
+/// owner of a channel can't act on the contests of the other channels.
+///
+/// # Arguments
+/// * `c` - The contest referred by the button, if it exists
+/// * `chan` - The channel of the button
+fn of_channel(c: Option<Contest>, chan: &Channel) -> Option<Contest> {
+    c.filter(|c| c.chan == chan.id)
+}
+
+/// Returns the alert for the owner after an action on a participant: "Done!" or the error.
+///
+/// # Arguments
+/// * `res` - The result of the action
+/// * `tag` - The tag of the error in the log
+fn done(res: rusqlite::Result<()>, tag: &str) -> String {
+    if let Err(err) = res {
+        error!("[{tag}] {err}");
+        format!("Error: {err}")
+    } else {
+        "Done!".to_string()
+    }
+}
+
+/// Invalidates, if `revoke`, or restores the invitation `id` of the contest `c`, as chosen by
+/// the `owner`. Returns the participant that sent the invitation and the alert for the owner, or
+/// `None` if the invitation doesn't belong to the contest.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+/// * `id` - The invitation ID
+/// * `revoke` - True to invalidate the invitation, false to restore it
+/// * `owner` - The owner of the contest
+fn invitation(
+    ctx: &Context,
+    c: &Contest,
+    id: i64,
+    revoke: bool,
+    owner: i64,
+) -> Option<(i64, String)> {
+    let invite = invitations::get(ctx, id).filter(|i| i.contest == c.id)?;
+    let res = if revoke {
+        invitations::revoke(ctx, &invite, owner, "revoked by the owner")
+    } else {
+        match invitations::restore(ctx, &invite, owner) {
+            Ok(false) => {
+                return Some((
+                    invite.source,
+                    "This invitation can't be restored.".to_string(),
+                ))
+            }
+            res => res.map(|_| ()),
+        }
+    };
+    Some((invite.source, done(res, "revoke/restore")))
+}
+
+/// Asks the `owner` to write the answer of the prompt `action` of the `contest`, outside of the
+/// FSM, explaining what to write with the `text`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `owner` - The owner of the contest
+/// * `contest` - The contest ID
+/// * `action` - The prompt
+/// * `arg` - The argument of the prompt, if any
+/// * `text` - What the owner has to write
+async fn ask(ctx: &Context, owner: i64, contest: i64, action: &str, arg: Option<&str>, text: &str) {
+    let res = prompts::ask(ctx, owner, contest, action, arg);
+    if let Err(err) = res {
+        error!("[ask {action}] {err}");
+    }
+    let res = ctx.api.send_message(SendMessage::new(owner, text)).await;
+    if let Err(err) = res {
+        error!("[{action} send] {err}");
+    }
+}
+
+/// Opens, leaves or closes, as chosen by the `owner` with the `action`, the chat with the
+/// `winner` of the contest `c`. Returns the alert for the owner, if any.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chan` - The channel of the contest
+/// * `c` - The contest
+/// * `winner` - The winner
+/// * `action` - open, leave or close
+/// * `owner` - The owner of the contest
+async fn relay(
+    ctx: &Context,
+    chan: &Channel,
+    c: &Contest,
+    winner: i64,
+    action: &str,
+    owner: i64,
+) -> Option<String> {
+    match action {
+        "open" => {
+            relay::start(ctx, chan, c, winner).await;
+            None
+        }
+        "leave" => {
+            if let Err(err) = relay::leave(ctx, owner) {
+                error!("[relay leave] {err}");
+            }
+            Some(
+                "Your messages are not delivered to the winner anymore. Press Reply to write \
+                again."
+                    .to_string(),
+            )
+        }
+        "close" => Some(match relay::close(ctx, c.id, owner, winner) {
+            Ok(true) => "Chat closed.".to_string(),
+            Ok(false) => "The chat is already closed.".to_string(),
+            Err(err) => {
+                error!("[relay close] {err}");
+                format!("Error: {err}")
+            }
+        }),
+        _ => None,
+    }
+}
+
+/// Removes a recurring contest repeating the contest `c`, or clones `c` as a draft, as chosen
+/// by the `owner` with the `action`. Returns the alert for the owner, if any.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+/// * `action` - The action on the recurring contests
+/// * `owner` - The owner of the contest
+fn template(ctx: &Context, c: &Contest, action: &Action, owner: i64) -> Option<String> {
+    match action {
+        Action::TemplateRemove(template) => templates::remove(ctx, c.id, *template, owner)
+            .err()
+            .map(|err| {
+                error!("[remove template] {err}");
+                format!("Error: {err}")
+            }),
+        Action::TemplateClone => Some(match templates::clone(ctx, c, owner) {
+            Ok(draft) => format!(
+                "Draft {} created, ending on {}. Start it from the Start menu.",
+                draft.name,
+                draft.end.format("%Y-%m-%d %H:%M UTC")
+            ),
+            Err(err) => {
+                error!("[clone contest] {err}");
+                format!("Error: {err}")
+            }
+        }),
+        _ => None,
+    }
+}
+
+/// Returns the invitations breakdown per participant of the `contest`, already escaped.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+fn breakdown(ctx: &Context, contest: i64) -> String {
+    let rows = invitations::breakdown(ctx, contest);
+    if rows.is_empty() {
+        return escape_markdown("No invitations for this contest, yet!", None);
+    }
+    let mut table = Table::new("{:<} | {:>} | {:>} | {:>} | {:>} | {:>} | {:>} | {:>}");
+    table.add_row(
+        Row::new()
+            .with_cell("User")
+            .with_cell("Pending")
+            .with_cell("Joined")
+            .with_cell("Qualified")
+            .with_cell("Left")
+            .with_cell("Disq.")
+            .with_cell("Adj.")
+            .with_cell("Rej."),
+    );
+    for row in rows {
+        let mut cells = Row::new().with_cell(users::display_name(&row.user));
+        for count in row.counts {
+            cells.add_cell(count);
+        }
+        table.add_row(cells);
+    }
+    format!(
+        "```\n{}```\n\n{}",
+        escape_markdown(&table.to_string(), Some("pre")),
+        escape_markdown(
+            "Only joined, qualified and adjusted invitations count in the ranking.\n\
+            Better view on desktop.",
+            None
+        )
+    )
+}
+
+/// Returns the last actions of the audit log of the `contest`, already escaped.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+fn audit_log(ctx: &Context, contest: i64) -> String {
+    let entries = audit::get(ctx, contest, 30);
+    if entries.is_empty() {
+        return escape_markdown("The audit log of this contest is empty.", None);
+    }
+    let mut table = Table::new("{:<} | {:<} | {:<} | {:>} | {:<}");
+    table.add_row(
+        Row::new()
+            .with_cell("Date")
+            .with_cell("Actor")
+            .with_cell("Action")
+            .with_cell("Target")
+            .with_cell("Reason"),
+    );
+    for entry in entries {
+        table.add_row(
+            Row::new()
+                .with_cell(entry.date.format("%Y-%m-%d %H:%M"))
+                .with_cell(users::display_name(&entry.actor))
+                .with_cell(entry.action)
+                .with_cell(entry.target.map_or_else(String::new, |t| t.to_string()))
+                .with_cell(entry.reason.unwrap_or_default()),
+        );
+    }
+    format!(
+        "```\n{}```\n\n{}",
+        escape_markdown(&table.to_string(), Some("pre")),
+        escape_markdown(
+            "Last 30 actions, dates in UTC. The target is a user ID for \
+            disqualify/requalify/adjust and an invitation ID for revoke/restore.",
+            None
+        )
+    )
+}
+
+/// The view shown to the owner after answering a prompt.
+enum Back {
+    /// The detail of a participant
+    Participant(i64),
+    /// The settings of the contest
+    Settings,
+    /// The milestones of the contest
+    Milestones,
+    /// The prize codes of the contest
+    Prizes,
+    /// The reward of the invitees of the contest
+    InviteeReward,
+    /// The points model of the contest
+    Points,
+    /// The partner chats of the contest
+    Sponsors,
+    /// The recurring contests repeating the contest
+    Templates,
+}
+
+impl Back {
+    /// Sends the view to the `owner`.
+    ///
+    /// # Arguments
+    /// * `ctx` - Telexide context
+    /// * `owner` - The owner of the contest
+    /// * `chan` - The channel of the contest
+    /// * `c` - The contest
+    async fn display(self, ctx: &Context, owner: i64, chan: &Channel, c: &Contest) {
+        match self {
+            Back::Participant(user) => participants::display(ctx, owner, chan, c, user).await,
+            Back::Settings => settings::display(ctx, owner, chan, c).await,
+            Back::Milestones => milestones::display(ctx, owner, chan, c).await,
+            Back::Prizes => prizes::display(ctx, owner, chan, c).await,
+            Back::InviteeReward => invitee_rewards::display(ctx, owner, chan, c).await,
+            Back::Points => points::display(ctx, owner, chan, c).await,
+            Back::Sponsors => sponsors::display(ctx, owner, chan, c).await,
+            Back::Templates => templates::display(ctx, owner, chan, c).await,
+        }
+    }
+}
+
+/// Handles the message `text` written by the owner as answer to the `prompt`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `prompt` - The prompt the owner is answering to
+/// * `text` - The text written by the owner
+///
+/// # Panics
+/// Panics if the connection to the DB fails or if telegram returns an error.
+async fn answer_prompt(ctx: &Context, prompt: &BeingManagedContest, text: &str) {
+    // A prompt is answered only once
+    if let Err(err) = prompts::clear(ctx, prompt.owner) {
+        error!("[clear prompt] {err}");
+    }
+    let c = contests::get(ctx, prompt.contest);
+    let chan = c.as_ref().and_then(|c| channels::get(ctx, c.chan));
+    if c.is_none() || chan.is_none() {
+        return;
+    }
+    let (c, chan) = (c.unwrap(), chan.unwrap());
+    let mut args = prompt
+        .arg
+        .as_deref()
+        .unwrap_or_default()
+        .split_ascii_whitespace();
+
+    // The answer is followed by the view the owner was in
+    let (reply, back) = match prompt.action.as_str() {
+        "disqualify" => {
+            let user: i64 = args.next().unwrap().parse().unwrap();
+            let notify = args.next() == Some("1");
+            let reply = disqualify(ctx, &chan, &c, prompt.owner, user, notify, text).await;
+            (reply, Back::Participant(user))
+        }
+        "allowlist" => {
+            let reply = match eligibility::set_allowlist(ctx, c.id, text, prompt.owner) {
+                Ok(count) => format!("Allowlist updated: {count} users."),
+                Err(err) => {
+                    error!("[set allowlist] {err}");
+                    format!("Error: {err}")
+                }
+            };
+            (reply, Back::Settings)
+        }
+        "adjust" => {
+            let user: i64 = args.next().unwrap().parse().unwrap();
+            let reply = adjust(ctx, &c, user, text, prompt.owner);
+            (reply, Back::Participant(user))
+        }
+        "setting" => {
+            let key = args.next().unwrap();
+            let res = settings::changeable(&c, key)
+                .and_then(|()| settings::set_number(ctx, c.id, key, text, prompt.owner));
+            let reply = match res {
+                Ok(()) => {
+                    if key == settings::MAX_PARTICIPANTS {
+                        participants::promote(ctx, &chan, &c).await;
+                    }
+                    "Setting updated!".to_string()
+                }
+                Err(err) => format!("Error: {err}. Nothing changed."),
+            };
+            (reply, Back::Settings)
+        }
+        "milestones" | "points" | "invitee_message" => {
+            set_rule(ctx, &c, &prompt.action, text, prompt.owner)
+        }
+        "sponsor" => {
+            let reply = match sponsors::add(ctx, &c, text, prompt.owner).await {
+                Ok(sponsor) => format!("{} added to the partners!", sponsor.name),
+                Err(err) => format!("Error: {err}. Nothing changed."),
+            };
+            (reply, Back::Sponsors)
+        }
+        "template" => (add_template(ctx, c.id, text, prompt.owner), Back::Templates),
+        "codes" => add_codes(ctx, c.id, args.next().unwrap(), text, prompt.owner),
+        _ => return,
+    };
+    let res = ctx
+        .api
+        .send_message(SendMessage::new(prompt.owner, &reply))
+        .await;
+    if let Err(err) = res {
+        error!("[{} send] {err}", prompt.action);
+    }
+    back.display(ctx, prompt.owner, &chan, &c).await;
+}
+
+/// Replaces the rule `action` (milestones, points or `invitee_message`) of the contest `c` with
+/// the one written by the `owner`, and returns the reply for the owner and its view. The rules
+/// are locked like the settings, once the contest started.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+/// * `action` - The rule to replace
+/// * `text` - The rule written by the owner
+/// * `owner` - The owner of the contest
+fn set_rule(ctx: &Context, c: &Contest, action: &str, text: &str, owner: i64) -> (String, Back) {
+    let back = match action {
+        "milestones" => Back::Milestones,
+        "points" => Back::Points,
+        _ => Back::InviteeReward,
+    };
+    let res = settings::rules_changeable(c).and_then(|()| match action {
+        "milestones" => milestones::set(ctx, c.id, text, owner)
+            .map(|count| format!("Milestones updated: {count} milestones.")),
+        "points" => {
+            points::set(ctx, c.id, text, owner).map(|()| "Points model updated!".to_string())
+        }
+        _ => invitee_rewards::set_message(ctx, c.id, text, owner)
+            .map(|()| "Invitee reward updated!".to_string())
+            .map_err(|err| {
+                error!("[set invitee message] {err}");
+                err.to_string()
+            }),
+    });
+    (
+        res.unwrap_or_else(|err| format!("Error: {err}. Nothing changed.")),
+        back,
+    )
+}
+
+/// Adjusts the credit of the `user` in the contest `c` as written by the `owner`, and returns
+/// the reply for the owner.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+/// * `user` - The participant
+/// * `text` - The adjustment written by the owner
+/// * `owner` - The owner of the contest
+fn adjust(ctx: &Context, c: &Contest, user: i64, text: &str, owner: i64) -> String {
+    let res = adjustments::parse(text).and_then(|(points, reason)| {
+        adjustments::add(ctx, c, user, points, &reason, owner).map(|()| points)
+    });
+    match res {
+        Ok(points) => format!("Credit adjusted by {points:+}!"),
+        Err(err) => format!("Error: {err}. Nothing changed."),
+    }
+}
+
+/// Adds the recurring contest written by the `owner`, repeating the `contest`, and returns the
+/// reply for the owner.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `text` - The template written by the owner
+/// * `owner` - The owner of the contest
+fn add_template(ctx: &Context, contest: i64, text: &str, owner: i64) -> String {
+    match templates::add(ctx, contest, text, owner) {
+        Ok(template) => format!(
+            "Recurring contest {} added! The first instance starts on {}.",
+            template.name,
+            template.next_run.format("%Y-%m-%d %H:%M UTC")
+        ),
+        Err(err) => format!("Error: {err}. Nothing changed."),
+    }
+}
+
+/// Adds the codes written by the `owner` to the stock `pool` of the `contest`, and returns the
+/// reply for the owner and the view of the stock.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `pool` - The stock
+/// * `text` - The codes written by the owner
+/// * `owner` - The owner adding the codes
+fn add_codes(ctx: &Context, contest: i64, pool: &str, text: &str, owner: i64) -> (String, Back) {
+    let reply = match codes::add(ctx, contest, pool, text, owner) {
+        Ok(count) => format!("{count} codes added to the stock {pool}."),
+        Err(err) => {
+            error!("[add codes] {err}");
+            format!("Error: {err}")
+        }
+    };
+    if pool.starts_with("prize:") {
+        (reply, Back::Prizes)
+    } else if pool == invitee_rewards::POOL {
+        (reply, Back::InviteeReward)
+    } else {
+        (reply, Back::Milestones)
+    }
+}
+
+/// Disqualifies the participant `user` of the contest `c` for the `reason` written by the
+/// `owner`, and returns the reply for the owner.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chan` - The channel of the contest
+/// * `c` - The contest
+/// * `owner` - The owner disqualifying the participant
+/// * `user` - The participant to disqualify
+/// * `notify` - Whether the participant must be informed
+/// * `reason` - The reason of the disqualification
+async fn disqualify(
+    ctx: &Context,
+    chan: &Channel,
+    c: &Contest,
+    owner: i64,
+    user: i64,
+    notify: bool,
+    reason: &str,
+) -> String {
+    let res = participants::disqualify(ctx, c.id, user, owner, reason);
+    if let Err(err) = res {
+        error!("[disqualify] {err}");
+        return format!("Error: {err}");
+    }
+    if notify {
+        let reply = SendMessage::new(
+            user,
+            &format!(
+                "You have been disqualified from the contest {}.\n\nReason: {reason}",
+                c.name
+            ),
+        );
+        if let Err(err) = ctx.api.send_message(reply).await {
+            error!("[notify disqualified] {err}");
+        }
+    }
+    // The disqualified participant frees a slot
+    participants::promote(ctx, chan, c).await;
+    "Participant disqualified!".to_string()
+}
+
+/// Asks the invitee `sender_id` to join the `chan` and, after 10 seconds, records the outcome
+/// in the `invitation`: joined (or disqualified, if the `source` has been disqualified) or
+/// still pending.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `sender_id` - The invitee
+/// * `chan` - The channel to join
+/// * `c` - The contest the invitation belongs to, if exists
+/// * `invitation` - The pending invitation, if it has been created
+/// * `source` - The user who sent the invitation
+async fn join(
+    ctx: &Context,
+    sender_id: i64,
+    chan: &Channel,
+    c: Option<Contest>,
+    invitation: Option<i64>,
+    source: i64,
+) {
+    let text = format!(
+        "Please join \u{1f449} [{}]({}) within the next 10 seconds\\.",
+        escape_markdown(&chan.name, None),
+        chan.link
+    );
+    let mut reply = SendMessage::new(sender_id, &text);
+    reply.set_parse_mode(&ParseMode::MarkdownV2);
+    let res = ctx.api.send_message(reply).await;
+    if let Err(err) = res {
+        error!("[please join] {err}");
+    }
+
+    sleep(Duration::from_secs(10)).await;
+    let member = ctx
+        .api
+        .get_chat_member(GetChatMember {
+            chat_id: chan.id,
+            user_id: sender_id,
+        })
+        .await;
+
+    // The unwrap is likely to not fail, since the previous request is identical and succeded
+    let joined = channels::joined(&member.unwrap());
+    if joined {
+        info!("Refer OK!");
+        if let Some(c) = c {
+            let now: DateTime<Utc> = Utc::now();
+            if now > c.end {
+                info!("Joining with expired contest");
+                let res = ctx
+                    .api
+                    .send_message(SendMessage::new(
+                        sender_id,
+                        "You joined the group/channel but the contest is finished",
+                    ))
+                    .await;
+                if let Err(err) = res {
+                    error!("[failed to insert invitation] {err}");
+                }
+            } else {
+                // The invitation counts once the invitee joined the partner chats too
+                let missing = sponsors::missing(ctx, c.id, sender_id).await;
+                match invitation {
+                    Some(id) if !missing.is_empty() => {
+                        ask_sponsors(ctx, sender_id, id, &missing).await;
                     }
+                    _ => record_join(ctx, sender_id, chan, &c, invitation, source).await,
                 }
+            }
+        } else {
+            error!("[refer ok] Invalid contest passed in url");
+            let res = ctx
+                .api
+                .send_message(SendMessage::new(
+                    sender_id,
+                    "You joined the channel but the contest does not exist.",
+                ))
+                .await;
+            if let Err(err) = res {
+                error!("[failed to insert invitation] {err}");
+            }
+        }
+    } else {
+        info!("User not joined the channel after 10 seconds...");
+        if let Some(id) = invitation {
+            let res = invitations::set_status(
+                ctx,
+                id,
+                InvitationStatus::Pending,
+                Some("not joined within 10 seconds"),
+            );
+            if let Err(err) = res {
+                error!("[pending invitation] {err}");
+            }
+        }
+        let text = escape_markdown("You haven't joined the channel within 10 seconds :(", None);
+        let mut reply = SendMessage::new(sender_id, &text);
+        reply.set_parse_mode(&ParseMode::MarkdownV2);
+        let res = ctx.api.send_message(reply).await;
+        if let Err(err) = res {
+            error!("[not join] {err}");
+        }
+    }
+}
+
+/// Asks the invitee `sender_id` to join the `missing` partner chats: the `invitation` stays
+/// pending until the invitee joined all of them.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `sender_id` - The invitee
+/// * `invitation` - The pending invitation
+/// * `missing` - The partner chats the invitee hasn't joined
+async fn ask_sponsors(ctx: &Context, sender_id: i64, invitation: i64, missing: &[Sponsor]) {
+    let res = invitations::set_status(
+        ctx,
+        invitation,
+        InvitationStatus::Pending,
+        Some("partner chats not joined"),
+    );
+    if let Err(err) = res {
+        error!("[pending invitation] {err}");
+    }
+    let mut reply = SendMessage::new(
+        sender_id,
+        "Almost done! Your invitation counts once you join our partners too. Join them, then \
+        press the button below.",
+    );
+    reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+        inline_keyboard: sponsors::keyboard(missing, invitation),
+    }));
+    if let Err(err) = ctx.api.send_message(reply).await {
+        error!("[ask sponsors] {err}");
+    }
+}
+
+/// Records that the invitee `sender_id` joined the `chan`, updating the `invitation` of the
+/// contest `c` and applying the attribution policy, and informs the invitee and the
+/// participants that lost the credit.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `sender_id` - The invitee
+/// * `chan` - The joined channel
+/// * `c` - The contest the invitation belongs to
+/// * `invitation` - The pending invitation, if it has been created
+/// * `source` - The user who sent the invitation
+async fn record_join(
+    ctx: &Context,
+    sender_id: i64,
+    chan: &Channel,
+    c: &Contest,
+    invitation: Option<i64>,
+    source: i64,
+) {
+    // The invitations of disqualified participants are recorded, but don't count
+    let (status, reason) = match participants::disqualification(ctx, c.id, source) {
+        Some(_) => (
+            InvitationStatus::Disqualified,
+            Some("participant disqualified"),
+        ),
+        None => (InvitationStatus::Joined, None),
+    };
+    // A rejected invitation stays rejected, and a counted one is credited only once. Several
+    // participants can invite the same user: the attribution policy of the contest decides
+    // who gets the credit, when the invitation starts counting
+    let mut decisions = vec![];
+    let res = match invitation.and_then(|id| invitations::get(ctx, id)) {
+        Some(invite)
+            if invite.status == InvitationStatus::Rejected || invite.status.is_counted() =>
+        {
+            Ok(())
+        }
+        Some(invite) if status == InvitationStatus::Joined => {
+            attribution::apply(ctx, &invite).map(|applied| decisions = applied)
+        }
+        Some(invite) => invitations::set_status(ctx, invite.id, status, reason),
+        None => Err(rusqlite::Error::QueryReturnedNoRows),
+    };
+    if let Err(err) = res {
+        error!("[insert invitation] {err}");
+        let res = ctx
+            .api
+            .send_message(SendMessage::new(
+                sender_id,
+                "Failed to insert invitation: this invitation might already exist!",
+            ))
+            .await;
+        if let Err(err) = res {
+            error!("[failed to insert invitation] {err}");
+        }
+    } else {
+        let text = format!(
+            "You joined [{}]({}) \u{1f917}",
+            escape_markdown(&chan.name, None),
+            chan.link
+        );
+        let mut reply = SendMessage::new(sender_id, &text);
+        reply.set_parse_mode(&ParseMode::MarkdownV2);
+        let res = ctx.api.send_message(reply).await;
+        if let Err(err) = res {
+            error!("[joined send] {err}");
+        }
+        attribution::notify(ctx, c, &decisions).await;
+        milestones::check(ctx, chan, c, source).await;
+    }
+}
 
-                display_main_commands(&ctx, sender_id).await;
+/// Checks the `answer` of the invitee to the `challenge`. If solved, the invitee is asked to
+/// join the channel, otherwise the invitee is informed that the invitation has been rejected.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `challenge` - The challenge being answered
+/// * `answer` - The answer of the invitee
+async fn answer_challenge(ctx: &Context, challenge: &Challenge, answer: &str) {
+    let text = match challenges::check(ctx, challenge, answer) {
+        Ok(Outcome::Solved) => {
+            let invite = invitations::get(ctx, challenge.invitation);
+            let chan = invite.as_ref().and_then(|i| channels::get(ctx, i.chan));
+            if let (Some(invite), Some(chan)) = (invite, chan) {
+                let reply = SendMessage::new(challenge.user, "Challenge solved \u{2705}");
+                if let Err(err) = ctx.api.send_message(reply).await {
+                    error!("[challenge solved] {err}");
+                }
+                let c = contests::get(ctx, invite.contest);
+                join(
+                    ctx,
+                    challenge.user,
+                    &chan,
+                    c,
+                    Some(invite.id),
+                    invite.source,
+                )
+                .await;
             }
+            return;
         }
+        Ok(Outcome::Failed) => "Wrong answer: the invitation has been rejected.",
+        Ok(Outcome::Expired) => "Time is up: the invitation has been rejected.",
+        Ok(Outcome::Closed) => return,
+        Err(err) => {
+            error!("[check challenge] {err}");
+            return;
+        }
+    };
+    let res = ctx
+        .api
+        .send_message(SendMessage::new(challenge.user, text))
+        .await;
+    if let Err(err) = res {
+        error!("[challenge outcome] {err}");
     }
+}
 
-    info!("message handler end");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contest(id: i64, chan: i64) -> Contest {
+        Contest {
+            id,
+            name: format!("contest {id}"),
+            prize: "prize".to_string(),
+            end: Utc::now(),
+            started_at: None,
+            stopped: false,
+            chan,
+        }
+    }
+
+    fn channel(id: i64, registered_by: i64) -> Channel {
+        Channel {
+            id,
+            registered_by,
+            link: String::new(),
+            name: format!("channel {id}"),
+        }
+    }
+
+    #[test]
+    fn parse_management() {
+        assert!(matches!(
+            Management::parse("dq 10 20 30 1"),
+            Some((10, Management::Contest(20, Action::Disqualify(30, true))))
+        ));
+        assert!(matches!(
+            Management::parse("participants 10"),
+            Some((10, Management::Menu(Menu::Participants)))
+        ));
+        assert!(Management::parse("revoke 10 20").is_none());
+        assert!(Management::parse("set 10 20 unknown").is_none());
+        assert!(Management::parse("manage 10").is_none());
+    }
+
+    #[test]
+    fn contest_of_another_channel() {
+        let mine = channel(10, 1);
+        let theirs = channel(11, 2);
+        assert_eq!(
+            of_channel(Some(contest(20, 10)), &mine).map(|c| c.id),
+            Some(20)
+        );
+        // The owner of a channel crafts a button for the contest of another channel
+        assert!(of_channel(Some(contest(21, 11)), &mine).is_none());
+        assert!(of_channel(Some(contest(20, 10)), &theirs).is_none());
+        assert!(of_channel(None, &mine).is_none());
+    }
 }
@@ -0,0 +1,378 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::Utc;
+use rusqlite::{params, Connection, Transaction};
+use telexide_fork::prelude::*;
+
+use crate::persistence::types::{DBKey, InvitationStatus, Invite, StatusBreakdown, User};
+use crate::telegram::audit;
+
+/// Creates a `Pending` invitation from `source` to `dest` for the `chan`, because of the
+/// `contest`, and returns its ID. If a pending invitation with the same source, dest,
+/// channel and contest already exists (e.g. the invitee accepted but didn't join in time)
+/// that invitation is reused. The invitations of the other contests are never touched.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `source` - The user who's inviting
+/// * `dest` - The user who's being invited
+/// * `chan` - The channel `dest` is being invited into
+/// * `contest` - The contest the invitation belongs to
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the invitation can't be created. In particular, if the
+/// same invitation already exists and it's not pending, the error is
+/// `rusqlite::Error::QueryReturnedNoRows`.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn create(
+    ctx: &Context,
+    source: i64,
+    dest: i64,
+    chan: i64,
+    contest: i64,
+) -> rusqlite::Result<i64> {
+    let id = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.query_row(
+            "INSERT INTO invitations(source, dest, chan, contest, status) VALUES(?, ?, ?, ?, ?) \
+            ON CONFLICT(source, dest, chan, contest) DO UPDATE SET status = excluded.status \
+            WHERE invitations.status = excluded.status RETURNING id",
+            params![source, dest, chan, contest, InvitationStatus::Pending],
+            |row| row.get(0),
+        )?
+    };
+    set_status(ctx, id, InvitationStatus::Pending, None)?;
+    Ok(id)
+}
+
+/// Changes the status of the invitation with ID `id`, and adds the change to the
+/// invitation history.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `id` - The invitation ID
+/// * `status` - The new status
+/// * `reason` - Optional reason of the change
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails. In this case nothing changes.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn set_status(
+    ctx: &Context,
+    id: i64,
+    status: InvitationStatus,
+    reason: Option<&str>,
+) -> rusqlite::Result<()> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let mut conn = map.get().unwrap();
+    let tx = conn.transaction()?;
+    set_status_tx(&tx, id, status, reason)?;
+    tx.commit()
+}
+
+/// Same as `set_status`, as part of the transaction `tx` of the caller.
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+pub(crate) fn set_status_tx(
+    tx: &Transaction,
+    id: i64,
+    status: InvitationStatus,
+    reason: Option<&str>,
+) -> rusqlite::Result<()> {
+    tx.execute(
+        "UPDATE invitations SET status = ?, reason = ?, updated_at = ? WHERE id = ?",
+        params![status, reason, Utc::now(), id],
+    )?;
+    tx.execute(
+        "INSERT INTO invitation_events(invitation, status, reason) VALUES(?, ?, ?)",
+        params![id, status, reason],
+    )?;
+    Ok(())
+}
+
+/// Returns the invitation with ID `id`, if exists.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `id` - The invitation ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+#[must_use]
+pub fn get(ctx: &Context, id: i64) -> Option<Invite> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.query_row(
+        "SELECT date, source, dest, chan, contest, status, reason, updated_at, credit \
+        FROM invitations WHERE id = ?",
+        params![id],
+        |row| {
+            Ok(Invite {
+                id,
+                date: row.get(0)?,
+                source: row.get(1)?,
+                dest: row.get(2)?,
+                chan: row.get(3)?,
+                contest: row.get(4)?,
+                status: row.get(5)?,
+                reason: row.get(6)?,
+                updated_at: row.get(7)?,
+                credit: row.get(8)?,
+            })
+        },
+    )
+    .ok()
+}
+
+/// Invalidates the `invite`, setting it `Disqualified`. The action is added to the audit log
+/// of the contest.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `invite` - The invitation to revoke
+/// * `actor` - The user revoking the invitation
+/// * `reason` - The reason of the revocation
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+pub fn revoke(ctx: &Context, invite: &Invite, actor: i64, reason: &str) -> rusqlite::Result<()> {
+    set_status(ctx, invite.id, InvitationStatus::Disqualified, Some(reason))?;
+    audit::log(
+        ctx,
+        invite.contest,
+        actor,
+        "revoke",
+        Some(invite.id),
+        Some(reason),
+    )
+}
+
+/// Returns the status of an invitation with the `status` once restored, or `None` if it can't
+/// be restored. A `Disqualified` invitation goes back to the status it had `before` being
+/// disqualified, `Joined` for the invitations older than their history; a `Left` invitation
+/// is manually credited, hence becomes `Adjusted`. A `Rejected` invitation is never credited.
+///
+/// # Arguments
+/// * `status` - The current status of the invitation
+/// * `before` - The last status of the invitation before being disqualified, if any
+fn restored(
+    status: InvitationStatus,
+    before: Option<InvitationStatus>,
+) -> Option<InvitationStatus> {
+    match status {
+        InvitationStatus::Disqualified => Some(before.unwrap_or(InvitationStatus::Joined)),
+        InvitationStatus::Left => Some(InvitationStatus::Adjusted),
+        _ => None,
+    }
+}
+
+/// Restores the invitation, as described by `restored`, and returns true if the invitation
+/// changed. The action is added to the audit log of the contest.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `invite` - The invitation to restore
+/// * `actor` - The user restoring the invitation
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails. In this case nothing changes.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn restore(ctx: &Context, invite: &Invite, actor: i64) -> rusqlite::Result<bool> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let mut conn = map.get().unwrap();
+    let tx = conn.transaction()?;
+    let changed = restore_tx(&tx, invite, actor)?;
+    tx.commit()?;
+    Ok(changed)
+}
+
+/// Same as `restore`, as part of the transaction `tx` of the caller.
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+pub(crate) fn restore_tx(tx: &Transaction, invite: &Invite, actor: i64) -> rusqlite::Result<bool> {
+    let before = if invite.status == InvitationStatus::Disqualified {
+        tx.query_row(
+            "SELECT status FROM invitation_events WHERE invitation = ? AND status <> ? \
+            ORDER BY id DESC LIMIT 1",
+            params![invite.id, InvitationStatus::Disqualified],
+            |row| row.get(0),
+        )
+        .ok()
+    } else {
+        None
+    };
+    let Some(status) = restored(invite.status, before) else {
+        return Ok(false);
+    };
+    set_status_tx(tx, invite.id, status, Some("restored by the owner"))?;
+    audit::log_tx(tx, invite.contest, actor, "restore", Some(invite.id), None)?;
+    Ok(true)
+}
+
+/// Returns all the invitations sent by `source` in the `contest`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `source` - The participant ID
+#[must_use]
+pub fn sent_by(ctx: &Context, contest: i64, source: i64) -> Vec<Invite> {
+    get_all(ctx, contest, &format!("source = {source}"))
+}
+
+/// Returns all the invitations of the `contest`, matching the SQL `condition` on the
+/// `invitations` table (e.g. `InvitationStatus::COUNTED`).
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `condition` - SQL condition, use "TRUE" to get all the invitations
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+#[must_use]
+pub fn get_all(ctx: &Context, contest: i64, condition: &str) -> Vec<Invite> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    get_all_tx(&conn, contest, condition)
+}
+
+/// Same as `get_all`, on the connection `conn`: usually, the transaction of the caller.
+///
+/// # Panics
+/// Panics if the returned data is corrupt.
+#[must_use]
+pub(crate) fn get_all_tx(conn: &Connection, contest: i64, condition: &str) -> Vec<Invite> {
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT id, date, source, dest, chan, status, reason, updated_at, credit \
+            FROM invitations WHERE contest = ? AND ({condition}) ORDER BY id ASC"
+        ))
+        .unwrap();
+
+    let invitations = stmt
+        .query_map(params![contest], |row| {
+            Ok(Invite {
+                id: row.get(0)?,
+                date: row.get(1)?,
+                source: row.get(2)?,
+                dest: row.get(3)?,
+                chan: row.get(4)?,
+                contest,
+                status: row.get(5)?,
+                reason: row.get(6)?,
+                updated_at: row.get(7)?,
+                credit: row.get(8)?,
+            })
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    invitations
+}
+
+/// Returns, for every participant of the `contest`, the number of invitations per status.
+/// The participants are ordered by number of counted invitations, in descending order.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+#[must_use]
+pub fn breakdown(ctx: &Context, contest: i64) -> Vec<StatusBreakdown> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let counts = InvitationStatus::ALL
+        .iter()
+        .map(|status| format!("SUM(invitations.status = '{status}')"))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT users.id, users.first_name, users.last_name, users.username, {counts} \
+            FROM invitations INNER JOIN users ON invitations.source = users.id \
+            WHERE invitations.contest = ? GROUP BY users.id \
+            ORDER BY SUM(invitations.{counted}) DESC, users.id ASC",
+            counted = InvitationStatus::COUNTED
+        ))
+        .unwrap();
+
+    let breakdown = stmt
+        .query_map(params![contest], |row| {
+            let mut counts = [0; InvitationStatus::ALL.len()];
+            for (i, count) in counts.iter_mut().enumerate() {
+                *count = row.get(4 + i)?;
+            }
+            Ok(StatusBreakdown {
+                user: User {
+                    id: row.get(0)?,
+                    first_name: row.get(1)?,
+                    last_name: row.get(2)?,
+                    username: row.get(3)?,
+                },
+                counts,
+            })
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    breakdown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restore_disqualified() {
+        use InvitationStatus::{Adjusted, Disqualified, Joined, Left, Qualified, Rejected};
+        assert_eq!(restored(Disqualified, Some(Qualified)), Some(Qualified));
+        assert_eq!(restored(Disqualified, Some(Left)), Some(Left));
+        assert_eq!(restored(Disqualified, None), Some(Joined));
+        // A rejected invitation stays rejected, even if disqualified in the meantime
+        assert_eq!(restored(Disqualified, Some(Rejected)), Some(Rejected));
+        assert_eq!(restored(Left, None), Some(Adjusted));
+    }
+
+    #[test]
+    fn restore_never_credits_rejected() {
+        for status in InvitationStatus::ALL {
+            if status != InvitationStatus::Disqualified && status != InvitationStatus::Left {
+                assert_eq!(restored(status, None), None);
+            }
+        }
+        assert!(
+            !restored(InvitationStatus::Rejected, Some(InvitationStatus::Joined))
+                .is_some_and(InvitationStatus::is_counted)
+        );
+    }
+}
@@ -0,0 +1,276 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use log::error;
+use rusqlite::params;
+use std::fmt::Write;
+use telexide_fork::{
+    api::types::SendMessage,
+    model::{InlineKeyboardMarkup, ParseMode, ReplyMarkup},
+    prelude::*,
+};
+
+use crate::persistence::types::{Channel, Contest, DBKey, Invite};
+use crate::telegram::messages::{callback_button, escape_markdown};
+use crate::telegram::{audit, channels, codes, settings};
+
+/// The name of the stock of codes given to the invitees.
+pub const POOL: &str = "invitee";
+
+/// Returns the welcome message sent to the invitees of the `contest`, if any.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+#[must_use]
+pub fn message(ctx: &Context, contest: i64) -> Option<String> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.query_row(
+        "SELECT message FROM invitee_rewards WHERE contest = ?",
+        params![contest],
+        |row| row.get(0),
+    )
+    .ok()
+}
+
+/// Sets the welcome message sent to the invitees of the `contest`. A single "-" removes it.
+/// The action is added to the audit log.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `text` - The message written by the owner
+/// * `actor` - The user changing the message
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn set_message(ctx: &Context, contest: i64, text: &str, actor: i64) -> rusqlite::Result<()> {
+    let text = text.trim();
+    {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        if text == "-" {
+            conn.execute(
+                "DELETE FROM invitee_rewards WHERE contest = ?",
+                params![contest],
+            )?;
+        } else {
+            conn.execute(
+                "INSERT OR REPLACE INTO invitee_rewards(contest, message) VALUES(?, ?)",
+                params![contest, text],
+            )?;
+        }
+    }
+    let action = if text == "-" {
+        "invitee reward removed"
+    } else {
+        "invitee reward"
+    };
+    audit::log(ctx, contest, actor, action, None, None)
+}
+
+/// Returns the number of invitees rewarded in the `contest`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+#[must_use]
+pub fn count(ctx: &Context, contest: i64) -> i64 {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.query_row(
+        "SELECT COUNT(*) FROM invitee_reward_deliveries WHERE contest = ?",
+        params![contest],
+        |row| row.get(0),
+    )
+    .unwrap()
+}
+
+/// Records that the invitee of the `invite` is being rewarded.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `invite` - The qualified invitation
+///
+/// # Returns
+/// False if the invitee has already been rewarded in the channel, by any contest.
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the insertion fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+fn record(ctx: &Context, invite: &Invite) -> rusqlite::Result<bool> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO invitee_reward_deliveries(chan, invitee, contest, invitation) \
+        VALUES(?, ?, ?, ?)",
+        params![invite.chan, invite.dest, invite.contest, invite.id],
+    )?;
+    Ok(inserted > 0)
+}
+
+/// Sends to the invitee of the qualified `invite` the reward of the contest `c`: the welcome
+/// message and a code from the invitee stock, if any. Every invitee is rewarded once per
+/// channel. The owner is notified when the stock runs out.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+/// * `invite` - The qualified invitation
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn deliver(ctx: &Context, c: &Contest, invite: &Invite) {
+    let message = message(ctx, c.id);
+    let (available, _) = codes::stock(ctx, c.id, POOL);
+    if message.is_none() && available == 0 {
+        return;
+    }
+    match record(ctx, invite) {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(err) => {
+            error!("[invitee reward record] {err}");
+            return;
+        }
+    }
+    let code = if available > 0 {
+        match codes::take(ctx, c.id, POOL, invite.dest) {
+            Ok(code) => code,
+            Err(err) => {
+                error!("[invitee code] {err}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(code) = &code {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        let res = conn.execute(
+            "UPDATE invitee_reward_deliveries SET code = ? WHERE chan = ? AND invitee = ?",
+            params![code, invite.chan, invite.dest],
+        );
+        if let Err(err) = res {
+            error!("[invitee code record] {err}");
+        }
+    }
+    let chan = channels::get(ctx, invite.chan);
+    let chan_name = chan
+        .as_ref()
+        .map_or("the channel", |chan| chan.name.as_str());
+
+    let mut text = format!("\u{1f91d} Thank you for joining {chan_name}!");
+    if let Some(message) = &message {
+        let _ = write!(text, "\n\n{message}");
+    }
+    if let Some(code) = &code {
+        let _ = write!(text, "\n\nHere's your welcome code: {code}");
+    }
+    if message.is_some() || code.is_some() {
+        let mut reply = SendMessage::new(invite.dest, &escape_markdown(&text, None));
+        reply.set_parse_mode(&ParseMode::MarkdownV2);
+        if let Err(err) = ctx.api.send_message(reply).await {
+            error!("[invitee reward send] {err}");
+        }
+    }
+
+    let (available, _) = codes::stock(ctx, c.id, POOL);
+    if code.is_some() && available == 0 {
+        if let Some(chan) = chan {
+            let text = format!(
+                "\u{26a0}\u{fe0f} The stock of invitee codes of the {} contest ran out: add more \
+                codes to keep rewarding the invitees with a code.",
+                c.name
+            );
+            let res = ctx
+                .api
+                .send_message(SendMessage::new(chan.registered_by, &text))
+                .await;
+            if let Err(err) = res {
+                error!("[invitee owner send] {err}");
+            }
+        }
+    }
+}
+
+/// Sends to `chat_id` the reward of the invitees of the `contest`: the welcome message, the
+/// stock of codes, and the number of invitees rewarded.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chat_id` - The chat ID
+/// * `chan` - The channel of the contest
+/// * `contest` - The contest
+pub async fn display(ctx: &Context, chat_id: i64, chan: &Channel, contest: &Contest) {
+    let mut text = format!("Invitee reward of {}\n\n", contest.name);
+    let message = message(ctx, contest.id);
+    let _ = writeln!(
+        text,
+        "Welcome message: {}",
+        message.as_deref().unwrap_or("none")
+    );
+    let (available, total) = codes::stock(ctx, contest.id, POOL);
+    let _ = writeln!(text, "Codes: {available}/{total}");
+    let _ = writeln!(text, "Invitees rewarded: {}", count(ctx, contest.id));
+    text += "\nThe invitees receive the reward privately, once their invitation qualifies. An \
+        invitee is rewarded once per channel, whatever the contest.";
+
+    // The welcome message is a rule: it can't change once the contest started. The stock of
+    // codes can grow anytime
+    let mut buttons = vec![];
+    if settings::rules_changeable(contest).is_ok() {
+        buttons.push(callback_button(
+            "\u{270f}\u{fe0f} Edit message",
+            &format!("invitee_message {} {}", chan.id, contest.id),
+        ));
+    }
+    buttons.push(callback_button(
+        "\u{1f39f} Add codes",
+        &format!("codes {} {} {POOL}", chan.id, contest.id),
+    ));
+    let mut reply = SendMessage::new(chat_id, &escape_markdown(&text, None));
+    reply.set_parse_mode(&ParseMode::MarkdownV2);
+    reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+        inline_keyboard: vec![
+            buttons,
+            vec![callback_button(
+                "\u{2699}\u{fe0f} Settings",
+                &format!("settings_contest {} {}", chan.id, contest.id),
+            )],
+        ],
+    }));
+    let res = ctx.api.send_message(reply).await;
+    if let Err(err) = res {
+        error!("[invitee reward send] {err}");
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::{DateTime, Duration, Utc};
+use log::error;
+use rusqlite::params;
+use std::fmt::Write;
+use telexide_fork::{
+    api::types::{EditMessageText, PinChatMessage, SendMessage},
+    model::ParseMode,
+    prelude::*,
+};
+
+use crate::persistence::types::{Contest, DBKey};
+use crate::telegram::messages::escape_markdown;
+use crate::telegram::{
+    activity, contests, fairness, giveaway, networks, points, raffle, settings, users,
+};
+
+/// Returns the number of participants shown in the live leaderboard of the `contest`, or
+/// `None` if the contest has no live leaderboard.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+#[must_use]
+pub fn size(ctx: &Context, contest: i64) -> Option<usize> {
+    settings::get(ctx, contest, settings::LEADERBOARD)
+        .parse()
+        .ok()
+}
+
+/// Returns the chart of the live leaderboard of the running contest `c`: the top `size`
+/// participants, with their tickets in the raffles. The chart doesn't contain the time, hence
+/// it changes only when the ranking changes.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+/// * `size` - The number of participants shown
+fn chart(ctx: &Context, c: &Contest, size: usize) -> String {
+    let mut text = format!("\u{1f4ca} {} - live leaderboard\n\n", c.name);
+    if giveaway::enabled(ctx, c.id) {
+        // The winners of a giveaway are drawn at the end: there's no chart before
+        let _ = writeln!(text, "Participants: {}", giveaway::count(ctx, c.id));
+    } else {
+        let raffle = raffle::enabled(ctx, c.id);
+        let (rank, unit) = if activity::enabled(ctx, c.id) {
+            (activity::ranking(ctx, c), "messages")
+        } else if raffle {
+            (contests::ranking(ctx, c), "tickets")
+        } else if points::formula(ctx, c.id).is_some() {
+            (contests::ranking(ctx, c), "points")
+        } else {
+            (contests::ranking(ctx, c), "invites")
+        };
+        if rank.is_empty() {
+            text += "No participants yet.\n";
+        }
+        for row in rank.iter().take(size) {
+            let medal = match row.rank {
+                1 => "\u{1f947}".to_string(),
+                2 => "\u{1f948}".to_string(),
+                3 => "\u{1f949}".to_string(),
+                rank => format!("#{rank}"),
+            };
+            let score = if raffle {
+                fairness::tickets(row.score).to_string()
+            } else {
+                row.score.to_string()
+            };
+            let _ = writeln!(
+                text,
+                "{medal} {} - {score} {unit}",
+                users::display_name(&row.user)
+            );
+        }
+    }
+    text
+}
+
+/// Returns the text of the live leaderboard of the running contest `c`: the `chart`, the time
+/// remaining and the time of the update, already escaped.
+///
+/// # Arguments
+/// * `c` - The contest
+/// * `chart` - The chart, as returned by `chart`
+fn text(c: &Contest, chart: &str) -> String {
+    let mut text = chart.to_string();
+    let left = c.end - Utc::now();
+    if left > Duration::zero() {
+        let _ = write!(
+            text,
+            "\nTime remaining: {}d {}h {}m",
+            left.num_days(),
+            left.num_hours() % 24,
+            left.num_minutes() % 60
+        );
+    } else {
+        text += "\nTime is up: the results are coming!";
+    }
+    let _ = write!(
+        text,
+        "\nUpdated at {} UTC",
+        Utc::now().format("%Y-%m-%d %H:%M")
+    );
+    escape_markdown(&text, None)
+}
+
+/// Posts, pins or updates the live leaderboards of the running contests that have one. Every
+/// channel of a contest has its own message, edited in place when the interval chosen in the
+/// settings has passed and the chart changed. Invoked periodically by the scheduler.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn refresh(ctx: &Context) {
+    let running: Vec<i64> = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT c.id FROM contests AS c INNER JOIN contest_settings AS s \
+                ON s.contest = c.id AND s.key = ? AND s.value <> 'off' \
+                WHERE c.started_at IS NOT NULL AND NOT c.stopped",
+            )
+            .unwrap();
+        let ids = stmt
+            .query_map(params![settings::LEADERBOARD], |row| row.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        ids
+    };
+    for c in running.into_iter().filter_map(|id| contests::get(ctx, id)) {
+        let Some(size) = size(ctx, c.id) else {
+            continue;
+        };
+        let interval =
+            Duration::minutes(settings::number(ctx, c.id, settings::LEADERBOARD_INTERVAL));
+        let chart = chart(ctx, &c, size);
+        for target in networks::channels(ctx, &c) {
+            let Some((message, last_chart, updated_at)) = get(ctx, c.id, target.id) else {
+                post(ctx, c.id, target.id, &chart, &text(&c, &chart)).await;
+                continue;
+            };
+            // The times change at every refresh: only the chart is compared
+            if Utc::now() - updated_at < interval || last_chart == chart {
+                continue;
+            }
+            edit(ctx, c.id, target.id, message, &chart, &text(&c, &chart)).await;
+        }
+    }
+}
+
+/// Replaces the live leaderboards of the finished contest `c` with the `results`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+/// * `results` - The final results, already escaped
+pub async fn finish(ctx: &Context, c: &Contest, results: &str) {
+    for target in networks::channels(ctx, c) {
+        if let Some((message, _, _)) = get(ctx, c.id, target.id) {
+            edit(ctx, c.id, target.id, message, results, results).await;
+        }
+    }
+}
+
+/// Returns the message of the live leaderboard of the `contest` in the channel `chan`, its
+/// chart and the last time it has been updated.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `chan` - The channel ID
+fn get(ctx: &Context, contest: i64, chan: i64) -> Option<(i64, String, DateTime<Utc>)> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.query_row(
+        "SELECT message, text, updated_at FROM leaderboards WHERE contest = ? AND chan = ?",
+        params![contest, chan],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )
+    .ok()
+}
+
+/// Posts and pins the live leaderboard of the `contest` in the channel `chan`, and stores its
+/// message and its chart.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `chan` - The channel ID
+/// * `chart` - The chart of the leaderboard
+/// * `text` - The leaderboard, already escaped
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+async fn post(ctx: &Context, contest: i64, chan: i64, chart: &str, text: &str) {
+    let mut reply = SendMessage::new(chan, text);
+    reply.set_parse_mode(&ParseMode::MarkdownV2);
+    let res = ctx.api.send_message(reply).await;
+    if let Err(err) = res {
+        error!("[leaderboard send] {err}");
+        return;
+    }
+    let message = res.unwrap();
+    let res = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.execute(
+            "INSERT INTO leaderboards(contest, chan, message, text, updated_at) \
+            VALUES(?, ?, ?, ?, ?)",
+            params![contest, chan, message.message_id, chart, Utc::now()],
+        )
+    };
+    if let Err(err) = res {
+        error!("[insert leaderboard] {err}");
+    }
+    let res = ctx
+        .api
+        .pin_chat_message(PinChatMessage {
+            chat_id: chan,
+            message_id: message.message_id,
+            disable_notification: true,
+        })
+        .await;
+    if let Err(err) = res {
+        error!("[leaderboard pin] {err}");
+    }
+}
+
+/// Edits in place the live leaderboard `message` of the `contest` in the channel `chan`, and
+/// stores its new chart.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `chan` - The channel ID
+/// * `message` - The message of the leaderboard
+/// * `chart` - The new chart
+/// * `text` - The new text, already escaped
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+async fn edit(ctx: &Context, contest: i64, chan: i64, message: i64, chart: &str, text: &str) {
+    let mut edit = EditMessageText::new(chan, message, text);
+    edit.parse_mode = Some(ParseMode::MarkdownV2);
+    if let Err(err) = ctx.api.edit_message_text(edit).await {
+        error!("[leaderboard edit] {err}");
+        return;
+    }
+    let res = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.execute(
+            "UPDATE leaderboards SET text = ?, updated_at = ? WHERE contest = ? AND chan = ?",
+            params![chart, Utc::now(), contest, chan],
+        )
+    };
+    if let Err(err) = res {
+        error!("[update leaderboard] {err}");
+    }
+}
@@ -19,7 +19,7 @@ use telexide_fork::{
     prelude::*,
 };
 
-use crate::persistence::types::Channel;
+use crate::persistence::types::{Channel, Contest};
 
 /// Sends to the `chat_id` the list of the commands.
 /// Used to show a raw menu to the user after the execution of any command.
@@ -36,7 +36,8 @@ pub async fn display_main_commands(ctx: &Context, chat_id: i64) {
         /register - Register a channel/group to the bot\n\
         /list - List your registered groups/channels\n\
         /contest - Start/Manage the referral contest\n\
-        /rank - Your rank in the challenges you joined\n",
+        /rank - Your rank in the challenges you joined\n\
+        /results - Official results of the finished contests\n",
         None,
     );
     let mut reply = SendMessage::new(chat_id, &text);
@@ -113,72 +114,32 @@ pub async fn display_manage_menu(ctx: &Context, chat_id: i64, chan: &Channel) {
     reply.set_parse_mode(&ParseMode::MarkdownV2);
     let inline_keyboard = vec![
         vec![
-            InlineKeyboardButton {
-                text: "\u{270d}\u{fe0f} Create".to_owned(),
-                // start, chan
-                callback_data: Some(format!("create {}", chan.id)),
-                callback_game: None,
-                login_url: None,
-                pay: None,
-                switch_inline_query: None,
-                switch_inline_query_current_chat: None,
-                url: None,
-            },
-            InlineKeyboardButton {
-                text: "\u{274c} Delete".to_owned(),
-                callback_data: Some(format!("delete {}", chan.id)),
-                callback_game: None,
-                login_url: None,
-                pay: None,
-                switch_inline_query: None,
-                switch_inline_query_current_chat: None,
-                url: None,
-            },
+            // create, chan
+            callback_button("\u{270d}\u{fe0f} Create", &format!("create {}", chan.id)),
+            callback_button("\u{274c} Delete", &format!("delete {}", chan.id)),
         ],
         vec![
-            InlineKeyboardButton {
-                text: "\u{25b6}\u{fe0f} Start".to_owned(),
-                // start, chan
-                callback_data: Some(format!("start {}", chan.id)),
-                callback_game: None,
-                login_url: None,
-                pay: None,
-                switch_inline_query: None,
-                switch_inline_query_current_chat: None,
-                url: None,
-            },
-            InlineKeyboardButton {
-                text: "\u{23f9} Stop".to_owned(),
-                callback_data: Some(format!("stop {}", chan.id)),
-                callback_game: None,
-                login_url: None,
-                pay: None,
-                switch_inline_query: None,
-                switch_inline_query_current_chat: None,
-                url: None,
-            },
+            // start, chan
+            callback_button("\u{25b6}\u{fe0f} Start", &format!("start {}", chan.id)),
+            callback_button("\u{23f9} Stop", &format!("stop {}", chan.id)),
         ],
         vec![
-            InlineKeyboardButton {
-                text: "\u{1f4c4}List".to_owned(),
-                callback_data: Some(format!("list {}", chan.id)),
-                callback_game: None,
-                login_url: None,
-                pay: None,
-                switch_inline_query: None,
-                switch_inline_query_current_chat: None,
-                url: None,
-            },
-            InlineKeyboardButton {
-                text: "\u{1f519}Menu".to_owned(),
-                callback_data: Some(format!("main {}", chan.id)),
-                callback_game: None,
-                login_url: None,
-                pay: None,
-                switch_inline_query: None,
-                switch_inline_query_current_chat: None,
-                url: None,
-            },
+            callback_button("\u{1f4ca} Invitations", &format!("breakdown {}", chan.id)),
+            callback_button(
+                "\u{1f465} Participants",
+                &format!("participants {}", chan.id),
+            ),
+        ],
+        vec![
+            callback_button(
+                "\u{2699}\u{fe0f} Settings",
+                &format!("settings {}", chan.id),
+            ),
+            callback_button("\u{1f501} Recurring", &format!("templates {}", chan.id)),
+        ],
+        vec![
+            callback_button("\u{1f4c4}List", &format!("list {}", chan.id)),
+            callback_button("\u{1f519}Menu", &format!("main {}", chan.id)),
         ],
     ];
     reply.set_parse_mode(&ParseMode::MarkdownV2);
@@ -221,3 +182,52 @@ pub async fn remove_loading_icon(ctx: &Context, callback_id: &str, text: Option<
         error!("[remove_loading_icon] {}", res.err().unwrap());
     }
 }
+
+/// Returns an inline keyboard button that, once clicked, sends `data` to the callback handler.
+///
+/// # Arguments
+/// * `text` - The button text
+/// * `data` - The callback data
+#[must_use]
+pub fn callback_button(text: &str, data: &str) -> InlineKeyboardButton {
+    InlineKeyboardButton {
+        text: text.to_owned(),
+        callback_data: Some(data.to_owned()),
+        callback_game: None,
+        login_url: None,
+        pay: None,
+        switch_inline_query: None,
+        switch_inline_query_current_chat: None,
+        url: None,
+    }
+}
+
+/// Returns the inline keyboard to use for selecting one of the `contests` of the channel
+/// `chan`. Every button sends to the callback handler the message `action chan contest.id`.
+///
+/// # Arguments
+/// * `contests` - The contests to choose from
+/// * `action` - The callback action to invoke with the selected contest
+/// * `chan` - The channel ID of the contests
+#[must_use]
+pub fn contests_keyboard(
+    contests: &[Contest],
+    action: &str,
+    chan: i64,
+) -> Vec<Vec<InlineKeyboardButton>> {
+    let mut partition_size: usize = contests.len() / 2;
+    if partition_size < 2 {
+        partition_size = 1;
+    }
+    contests
+        .chunks(partition_size)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .map(|contest| {
+                    callback_button(&contest.name, &format!("{action} {chan} {}", contest.id))
+                })
+                .collect()
+        })
+        .collect()
+}
@@ -0,0 +1,376 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use log::error;
+use rusqlite::params;
+use std::fmt::Write;
+use telexide_fork::{
+    api::types::SendMessage,
+    model::{InlineKeyboardMarkup, ParseMode, ReplyMarkup},
+    prelude::*,
+};
+
+use crate::persistence::types::{Achievement, Channel, Contest, DBKey, Milestone};
+use crate::telegram::messages::{callback_button, escape_markdown};
+use crate::telegram::{audit, codes, contests, settings, users};
+
+/// Returns the name of the stock of codes of the milestone reached with `threshold` invites.
+///
+/// # Arguments
+/// * `threshold` - The threshold of the milestone
+#[must_use]
+pub fn pool(threshold: u32) -> String {
+    format!("milestone:{threshold}")
+}
+
+/// Returns the milestones of the `contest`, the lowest threshold first.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+#[must_use]
+pub fn get_all(ctx: &Context, contest: i64) -> Vec<Milestone> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let mut stmt = conn
+        .prepare("SELECT threshold, reward FROM milestones WHERE contest = ? ORDER BY threshold")
+        .unwrap();
+    let milestones = stmt
+        .query_map(params![contest], |row| {
+            Ok(Milestone {
+                contest,
+                threshold: row.get(0)?,
+                reward: row.get(1)?,
+            })
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    milestones
+}
+
+/// Parses the milestones written by the owner: one per line, `<threshold> <reward>`.
+///
+/// # Arguments
+/// * `text` - The text written by the owner
+///
+/// # Errors
+/// Returns a string describing why the text is not valid.
+pub fn parse(text: &str) -> Result<Vec<(u32, String)>, String> {
+    let mut milestones: Vec<(u32, String)> = vec![];
+    for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let (threshold, reward) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let threshold = threshold
+            .parse::<u32>()
+            .ok()
+            .filter(|t| *t > 0)
+            .ok_or_else(|| format!("\"{threshold}\" is not a valid number of invites"))?;
+        let reward = reward.trim();
+        if reward.is_empty() {
+            return Err(format!("The reward of {threshold} invites is missing"));
+        }
+        if milestones.iter().any(|(t, _)| *t == threshold) {
+            return Err(format!("The milestone of {threshold} invites is repeated"));
+        }
+        milestones.push((threshold, reward.to_string()));
+    }
+    Ok(milestones)
+}
+
+/// Replaces the milestones of the `contest` with the ones written by the owner. The
+/// milestones already reached by the participants are not affected. The action is added to
+/// the audit log.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `text` - The text written by the owner
+/// * `actor` - The user changing the milestones
+///
+/// # Returns
+/// The number of milestones.
+///
+/// # Errors
+/// Returns the reason of the failure, ready to be shown to the owner.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn set(ctx: &Context, contest: i64, text: &str, actor: i64) -> Result<usize, String> {
+    let milestones = parse(text)?;
+    {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let mut conn = map.get().unwrap();
+        let tx = conn.transaction().map_err(|err| err.to_string())?;
+        tx.execute("DELETE FROM milestones WHERE contest = ?", params![contest])
+            .map_err(|err| err.to_string())?;
+        for (threshold, reward) in &milestones {
+            tx.execute(
+                "INSERT INTO milestones(contest, threshold, reward) VALUES(?, ?, ?)",
+                params![contest, threshold, reward],
+            )
+            .map_err(|err| err.to_string())?;
+        }
+        tx.commit().map_err(|err| err.to_string())?;
+    }
+    let thresholds = milestones
+        .iter()
+        .map(|(t, _)| t.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    audit::log(
+        ctx,
+        contest,
+        actor,
+        &format!("milestones {thresholds}"),
+        None,
+        None,
+    )
+    .map_err(|err| err.to_string())?;
+    Ok(milestones.len())
+}
+
+/// Returns the milestones reached by the `user` in the `contest`, the oldest first.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `user` - The participant ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+#[must_use]
+pub fn achieved(ctx: &Context, contest: i64, user: i64) -> Vec<Achievement> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let mut stmt = conn
+        .prepare(
+            "SELECT threshold, reward, code, date FROM milestone_achievements \
+            WHERE contest = ? AND user = ? ORDER BY threshold",
+        )
+        .unwrap();
+    let achievements = stmt
+        .query_map(params![contest, user], |row| {
+            Ok(Achievement {
+                milestone: Milestone {
+                    contest,
+                    threshold: row.get(0)?,
+                    reward: row.get(1)?,
+                },
+                user,
+                code: row.get(2)?,
+                date: row.get(3)?,
+            })
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    achievements
+}
+
+/// Returns the number of participants that reached the milestone.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `milestone` - The milestone
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+fn count_achieved(ctx: &Context, milestone: &Milestone) -> i64 {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.query_row(
+        "SELECT COUNT(*) FROM milestone_achievements WHERE contest = ? AND threshold = ?",
+        params![milestone.contest, milestone.threshold],
+        |row| row.get(0),
+    )
+    .unwrap()
+}
+
+/// Records the milestones of the contest `c` crossed by the participant `user`, and sends the
+/// rewards: the participant receives a code from the stock of the milestone, if any, and the
+/// owner is notified. Every milestone is reached only once. The thresholds are invites: only
+/// the credit of the direct invitations counts, not the points, the indirect credit or the
+/// adjustments.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chan` - The channel of the contest
+/// * `c` - The contest
+/// * `user` - The participant
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn check(ctx: &Context, chan: &Channel, c: &Contest, user: i64) {
+    let milestones = get_all(ctx, c.id);
+    if milestones.is_empty() {
+        return;
+    }
+    let invites = contests::ranking(ctx, c)
+        .into_iter()
+        .find(|rank| rank.user.id == user)
+        .map_or(0.0, |rank| rank.invites);
+    for milestone in milestones
+        .iter()
+        .filter(|m| f64::from(m.threshold) <= invites)
+    {
+        let inserted = {
+            let guard = ctx.data.read();
+            let map = guard.get::<DBKey>().expect("db");
+            let conn = map.get().unwrap();
+            conn.execute(
+                "INSERT OR IGNORE INTO milestone_achievements(contest, threshold, user, reward) \
+                VALUES(?, ?, ?, ?)",
+                params![c.id, milestone.threshold, user, milestone.reward],
+            )
+        };
+        match inserted {
+            Ok(1) => reward(ctx, chan, c, milestone, user).await,
+            Ok(_) => {}
+            Err(err) => error!("[milestone] {err}"),
+        }
+    }
+}
+
+/// Sends the reward of the `milestone` just reached by the `user`, and notifies the owner.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chan` - The channel of the contest
+/// * `c` - The contest
+/// * `milestone` - The milestone reached
+/// * `user` - The participant
+async fn reward(ctx: &Context, chan: &Channel, c: &Contest, milestone: &Milestone, user: i64) {
+    let pool = pool(milestone.threshold);
+    let (_, total) = codes::stock(ctx, c.id, &pool);
+    let code = if total > 0 {
+        match codes::take(ctx, c.id, &pool, user) {
+            Ok(code) => code,
+            Err(err) => {
+                error!("[milestone code] {err}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(code) = &code {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        let res = conn.execute(
+            "UPDATE milestone_achievements SET code = ? \
+            WHERE contest = ? AND threshold = ? AND user = ?",
+            params![code, c.id, milestone.threshold, user],
+        );
+        if let Err(err) = res {
+            error!("[milestone code] {err}");
+        }
+    }
+
+    let mut text = format!(
+        "\u{1f3c5} You reached {} invites in the {} contest!\n\nReward: {}",
+        milestone.threshold, c.name, milestone.reward
+    );
+    if let Some(code) = &code {
+        let _ = write!(text, "\nYour code: {code}");
+    }
+    if let Err(err) = ctx.api.send_message(SendMessage::new(user, &text)).await {
+        error!("[milestone send] {err}");
+    }
+
+    let name = users::get(ctx, user).map_or_else(|| user.to_string(), |u| users::display_name(&u));
+    let mut text = format!(
+        "\u{1f3c5} {name} reached {} invites in the {} contest.\n\nReward: {}",
+        milestone.threshold, c.name, milestone.reward
+    );
+    if code.is_some() {
+        text += "\nThe code has been sent to the participant.";
+    } else if total > 0 {
+        text += "\n\u{26a0}\u{fe0f} The stock of codes is empty: send the reward manually.";
+    }
+    let res = ctx
+        .api
+        .send_message(SendMessage::new(chan.registered_by, &text))
+        .await;
+    if let Err(err) = res {
+        error!("[milestone owner send] {err}");
+    }
+}
+
+/// Sends to `chat_id` the milestones of the `contest`, with the stock of codes of every
+/// milestone, and the buttons to change them.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chat_id` - The chat ID
+/// * `chan` - The channel of the contest
+/// * `contest` - The contest
+pub async fn display(ctx: &Context, chat_id: i64, chan: &Channel, contest: &Contest) {
+    let milestones = get_all(ctx, contest.id);
+    let mut text = format!("Milestones of {}\n\n", contest.name);
+    if milestones.is_empty() {
+        text += "No milestones, yet!\n";
+    }
+    let mut inline_keyboard = vec![];
+    for milestone in &milestones {
+        let (available, total) = codes::stock(ctx, contest.id, &pool(milestone.threshold));
+        let _ = writeln!(
+            text,
+            "{} invites: {} - reached by {}, codes {available}/{total}",
+            milestone.threshold,
+            milestone.reward,
+            count_achieved(ctx, milestone)
+        );
+        inline_keyboard.push(vec![callback_button(
+            &format!("\u{1f39f} Add codes for {} invites", milestone.threshold),
+            &format!(
+                "codes {} {} {}",
+                chan.id,
+                contest.id,
+                pool(milestone.threshold)
+            ),
+        )]);
+    }
+    text += "\nCodes: available/total. Without codes, only the reward description is sent.";
+    // The milestones are rules: they can't change once the contest started
+    let mut buttons = vec![];
+    if settings::rules_changeable(contest).is_ok() {
+        buttons.push(callback_button(
+            "\u{270f}\u{fe0f} Edit milestones",
+            &format!("milestones_edit {} {}", chan.id, contest.id),
+        ));
+    }
+    buttons.push(callback_button(
+        "\u{2699}\u{fe0f} Settings",
+        &format!("settings_contest {} {}", chan.id, contest.id),
+    ));
+    inline_keyboard.push(buttons);
+
+    let mut reply = SendMessage::new(chat_id, &escape_markdown(&text, None));
+    reply.set_parse_mode(&ParseMode::MarkdownV2);
+    reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+        inline_keyboard,
+    }));
+    let res = ctx.api.send_message(reply).await;
+    if let Err(err) = res {
+        error!("[milestones send] {err}");
+    }
+}
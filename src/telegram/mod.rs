@@ -23,22 +23,91 @@
 //!
 //! # What's inside this crate?
 //!
+//! - `activity`: the activity contests of the groups, won by who writes more qualifying messages.
+//! - `adjustments`: functions for the ledger of the manual credit adjustments of the participants.
+//! - `attribution`: the policy deciding who gets the credit when several participants invite
+//!   the same user.
+//! - `audit`: functions for writing and reading the log of the actions executed by the owners.
+//! - `caps`: the optional limits of a contest, like the maximum number of invites per participant.
+//! - `challenges`: the human-verification challenges sent to the invitees before crediting the
+//!   invitations.
 //! - `channels`: functions for working with channels, like registering the channels to `RaF` or
 //! getting the channels info. Despite the name, also groups and supergroups are supported, even
 //! though they are always considered channels. Under the hood, there's almost zero differences
 //! from the `RaF` goal.
+//! - `codes`: the stocks of reward codes (gift cards, coupons, ...) uploaded by the owners.
 //! - `commands`: the commands available to the `RaF` users, like `/start`, `/rank`, `/contest`. See
 //! `/help` for the complete list of commands.
-//! - `contests`: function for creating and updating the contests. The complete contest workflow is
-//! not here, but in the `handlers` crate - because of how Telegram (and Telexide) works.
+//! - `contests`: function for creating, updating, starting and stopping the contests. The
+//!   complete contest workflow is not here, but in the `handlers` crate - because of how
+//!   Telegram (and Telexide) works.
+//! - `eligibility`: the rules deciding who can participate in a contest.
+//! - `fairness`: the provably fair random draws, with commit-reveal seeds.
+//! - `fraud`: heuristics for spotting suspicious participants, reported to the owner before the
+//!   results are published.
+//! - `fulfillment`: the tracking of the prizes, from the notification of the winner to the
+//!   delivery.
+//! - `giveaway`: the contests without referrals, where the members press Participate and the
+//!   winners are drawn at random.
 //! - `handlers`: the handlers for callback events (buttons, user interactions) and user messages.
+//! - `invitations`: functions for managing the lifecycle (status and history) of the invitations.
+//! - `invitee_rewards`: the rewards given to the invitees, once their invitation qualifies.
+//! - `leaderboard`: the live leaderboard of a contest, pinned in the channel and updated in
+//!   place.
 //! - `messages`: functions for managing the text messages, like sending the `RaF` menu, working with
 //! markdown, ...
+//! - `milestones`: the rewards given to the participants that reach a number of invites.
+//! - `networks`: the multi-channel contests, where inviting friends to any of the linked channels
+//!   counts.
+//! - `participants`: functions for managing the participants of a contest (disqualification, ...).
+//! - `points`: the points model of a contest, replacing the raw count of the invitations.
+//! - `prizes`: the stocks of prize codes of every tier, delivered privately to the winners.
+//! - `prompts`: functions for tracking what the owners have been asked to write, outside of the
+//!   callback FSM.
+//! - `raffle`: the contest mode where every invite is a ticket, and the winners are drawn at
+//!   random.
+//! - `referrals`: the multi-level referral graph, crediting the invitations of the invitees.
+//! - `relay`: the two-way chats between the owners and the winners, relayed by the bot.
+//! - `results`: functions for storing and reading the official results of the finished contests.
+//! - `scheduler`: the periodic jobs, executed independently from the Telegram updates.
+//! - `settings`: the optional features of every contest, configurable by the owner.
+//! - `sponsors`: the partner chats of a contest, that the invitees must join too.
+//! - `templates`: the recurring contests, created, started and finished by the scheduler, and
+//!   the clones of the past contests.
 //! - `users`: functions for getting a specific users or all the users that are channel owners.
 
+pub mod activity;
+pub mod adjustments;
+pub mod attribution;
+pub mod audit;
+pub mod caps;
+pub mod challenges;
 pub mod channels;
+pub mod codes;
 pub mod commands;
 pub mod contests;
+pub mod eligibility;
+pub mod fairness;
+pub mod fraud;
+pub mod fulfillment;
+pub mod giveaway;
 pub mod handlers;
+pub mod invitations;
+pub mod invitee_rewards;
+pub mod leaderboard;
 pub mod messages;
+pub mod milestones;
+pub mod networks;
+pub mod participants;
+pub mod points;
+pub mod prizes;
+pub mod prompts;
+pub mod raffle;
+pub mod referrals;
+pub mod relay;
+pub mod results;
+pub mod scheduler;
+pub mod settings;
+pub mod sponsors;
+pub mod templates;
 pub mod users;
@@ -0,0 +1,242 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use log::error;
+use rusqlite::params;
+use std::fmt::Write;
+use telexide_fork::{
+    api::types::SendMessage,
+    model::{InlineKeyboardMarkup, ParseMode, ReplyMarkup},
+    prelude::*,
+};
+
+use crate::persistence::types::{Channel, Contest, DBKey};
+use crate::telegram::messages::{callback_button, escape_markdown};
+use crate::telegram::{audit, channels, contests};
+
+/// Returns the channels linked to the `contest`, besides the channel that owns it.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+#[must_use]
+pub fn linked(ctx: &Context, contest: i64) -> Vec<Channel> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let mut stmt = conn
+        .prepare(
+            "SELECT channels.id, channels.link, channels.name, channels.registered_by \
+            FROM contest_channels INNER JOIN channels ON contest_channels.chan = channels.id \
+            WHERE contest_channels.contest = ? ORDER BY channels.name",
+        )
+        .unwrap();
+    let linked = stmt
+        .query_map(params![contest], |row| {
+            Ok(Channel {
+                id: row.get(0)?,
+                link: row.get(1)?,
+                name: row.get(2)?,
+                registered_by: row.get(3)?,
+            })
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    linked
+}
+
+/// Returns all the channels of the contest `c`: the channel that owns it, followed by the
+/// linked ones. Inviting friends to any of them counts.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+#[must_use]
+pub fn channels(ctx: &Context, c: &Contest) -> Vec<Channel> {
+    channels::get(ctx, c.chan)
+        .into_iter()
+        .chain(linked(ctx, c.id))
+        .collect()
+}
+
+/// Returns true if the channel `chan` is one of the channels of the contest `c`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+/// * `chan` - The channel ID
+#[must_use]
+pub fn includes(ctx: &Context, c: &Contest, chan: i64) -> bool {
+    c.chan == chan || linked(ctx, c.id).iter().any(|linked| linked.id == chan)
+}
+
+/// Returns all the contests that involve the channel `chan`: the ones it owns, and the ones
+/// it's linked to.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chan` - The channel ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+#[must_use]
+pub fn contests(ctx: &Context, chan: i64) -> Vec<Contest> {
+    let ids: Vec<i64> = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT contest FROM contest_channels WHERE chan = ?")
+            .unwrap();
+        let ids = stmt
+            .query_map(params![chan], |row| row.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        ids
+    };
+    contests::get_all(ctx, chan)
+        .into_iter()
+        .chain(ids.into_iter().filter_map(|id| contests::get(ctx, id)))
+        .collect()
+}
+
+/// Links the channel `chan` to the contest `c`, or unlinks it if already linked. Only the
+/// channels registered by the owner can be linked, and only before the contest starts: the
+/// announcement is posted in every channel when the contest starts. The action is added to
+/// the audit log.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+/// * `chan` - The channel to link or unlink
+/// * `actor` - The owner changing the channels
+///
+/// # Errors
+/// Returns the reason of the failure, ready to be shown to the owner.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn toggle(ctx: &Context, c: &Contest, chan: &Channel, actor: i64) -> Result<(), String> {
+    if c.started_at.is_some() {
+        return Err("The channels can't change once the contest started.".to_string());
+    }
+    if chan.id == c.chan || chan.registered_by != actor {
+        return Err("You can link only your other channels.".to_string());
+    }
+    let unlink = linked(ctx, c.id).iter().any(|linked| linked.id == chan.id);
+    {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        if unlink {
+            conn.execute(
+                "DELETE FROM contest_channels WHERE contest = ? AND chan = ?",
+                params![c.id, chan.id],
+            )
+        } else {
+            conn.execute(
+                "INSERT INTO contest_channels(contest, chan) VALUES(?, ?)",
+                params![c.id, chan.id],
+            )
+        }
+        .map_err(|err| err.to_string())?;
+    }
+    let action = if unlink {
+        "unlink channel"
+    } else {
+        "link channel"
+    };
+    audit::log(ctx, c.id, actor, action, None, Some(&chan.name)).map_err(|err| err.to_string())
+}
+
+/// Returns the description of the channels of the contest `c`, shown in the announcement, or
+/// `None` if the contest involves only its own channel.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+#[must_use]
+pub fn rule(ctx: &Context, c: &Contest) -> Option<String> {
+    let channels = channels(ctx, c);
+    if channels.len() <= 1 {
+        return None;
+    }
+    Some(format!(
+        "Inviting friends to any of these channels counts: {}.",
+        channels
+            .iter()
+            .map(|chan| chan.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))
+}
+
+/// Sends to `chat_id` the channels of the `contest`, with a button per channel of the owner
+/// to link or unlink it.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chat_id` - The chat ID
+/// * `chan` - The channel of the contest
+/// * `contest` - The contest
+pub async fn display(ctx: &Context, chat_id: i64, chan: &Channel, contest: &Contest) {
+    let linked = linked(ctx, contest.id);
+    let mut text = format!(
+        "Channels of {}\n\nInviting friends to any of the linked channels counts in the \
+        contest: the announcement is posted and pinned in all of them.\n\n{} (owner)\n",
+        contest.name, chan.name
+    );
+    for other in &linked {
+        let _ = writeln!(text, "{}", other.name);
+    }
+    let mut inline_keyboard = vec![];
+    if contest.started_at.is_none() {
+        text += "\nPress a channel to link or unlink it.";
+        for other in channels::get_all(ctx, chan.registered_by) {
+            if other.id == chan.id {
+                continue;
+            }
+            let mark = if linked.iter().any(|l| l.id == other.id) {
+                "\u{2705}"
+            } else {
+                "\u{2795}"
+            };
+            inline_keyboard.push(vec![callback_button(
+                &format!("{mark} {}", other.name),
+                &format!("network_toggle {} {} {}", chan.id, contest.id, other.id),
+            )]);
+        }
+    } else {
+        text += "\nThe contest started: the channels can't change anymore.";
+    }
+    inline_keyboard.push(vec![callback_button(
+        "\u{2699}\u{fe0f} Settings",
+        &format!("settings_contest {} {}", chan.id, contest.id),
+    )]);
+
+    let mut reply = SendMessage::new(chat_id, &escape_markdown(&text, None));
+    reply.set_parse_mode(&ParseMode::MarkdownV2);
+    reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+        inline_keyboard,
+    }));
+    let res = ctx.api.send_message(reply).await;
+    if let Err(err) = res {
+        error!("[networks send] {err}");
+    }
+}
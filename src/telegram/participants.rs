@@ -0,0 +1,541 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use data_encoding::BASE64URL;
+use log::error;
+use rusqlite::params;
+use std::fmt::Write;
+use telexide_fork::{
+    api::types::SendMessage,
+    model::{InlineKeyboardMarkup, ParseMode, ReplyMarkup},
+    prelude::*,
+};
+
+use crate::persistence::types::{Channel, Contest, DBKey, InvitationStatus, NameKey};
+use crate::telegram::messages::{callback_button, escape_markdown};
+use crate::telegram::{adjustments, audit, invitations, networks, settings, users};
+
+/// Prefix of the reason of the invitations disqualified together with their participant.
+const DISQUALIFIED_PREFIX: &str = "participant disqualified";
+
+/// Returns the reason of the disqualification of `user` from the `contest`, if the user
+/// has been disqualified.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `user` - The participant ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+#[must_use]
+pub fn disqualification(ctx: &Context, contest: i64, user: i64) -> Option<String> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.query_row(
+        "SELECT reason FROM disqualified_participants WHERE contest = ? AND user = ?",
+        params![contest, user],
+        |row| row.get(0),
+    )
+    .ok()
+}
+
+/// Disqualifies the `user` from the `contest`: every invitation the user sent becomes
+/// `Disqualified` and the new ones won't count. The action is added to the audit log.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `user` - The participant to disqualify
+/// * `actor` - The user executing the action
+/// * `reason` - The reason of the disqualification
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if any update fails. In this case nothing changes.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn disqualify(
+    ctx: &Context,
+    contest: i64,
+    user: i64,
+    actor: i64,
+    reason: &str,
+) -> rusqlite::Result<()> {
+    let sent = invitations::sent_by(ctx, contest, user);
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let mut conn = map.get().unwrap();
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO disqualified_participants(contest, user, reason) VALUES(?, ?, ?)",
+        params![contest, user, reason],
+    )?;
+    let reason_invitation = format!("{DISQUALIFIED_PREFIX}: {reason}");
+    for invite in sent {
+        if invite.status != InvitationStatus::Disqualified {
+            invitations::set_status_tx(
+                &tx,
+                invite.id,
+                InvitationStatus::Disqualified,
+                Some(&reason_invitation),
+            )?;
+        }
+    }
+    audit::log_tx(&tx, contest, actor, "disqualify", Some(user), Some(reason))?;
+    tx.commit()
+}
+
+/// Cancels the disqualification of `user` from the `contest`. The invitations disqualified
+/// together with the participant are restored, the ones revoked one by one are not.
+/// The action is added to the audit log.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `user` - The participant to requalify
+/// * `actor` - The user executing the action
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if any update fails. In this case nothing changes.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn requalify(ctx: &Context, contest: i64, user: i64, actor: i64) -> rusqlite::Result<()> {
+    let sent = invitations::sent_by(ctx, contest, user);
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let mut conn = map.get().unwrap();
+    let tx = conn.transaction()?;
+    tx.execute(
+        "DELETE FROM disqualified_participants WHERE contest = ? AND user = ?",
+        params![contest, user],
+    )?;
+    for invite in sent {
+        let disqualified_with_participant = invite.status == InvitationStatus::Disqualified
+            && invite
+                .reason
+                .as_ref()
+                .is_some_and(|reason| reason.starts_with(DISQUALIFIED_PREFIX));
+        if disqualified_with_participant {
+            invitations::restore_tx(&tx, &invite, actor)?;
+        }
+    }
+    audit::log_tx(&tx, contest, actor, "requalify", Some(user), None)?;
+    tx.commit()
+}
+
+/// Returns the number of active participants of the `contest`. The disqualified participants
+/// don't take a slot.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+#[must_use]
+pub fn count_active(ctx: &Context, contest: i64) -> i64 {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.query_row(
+        "SELECT COUNT(*) FROM contest_participants p WHERE p.contest = ? AND p.status = 'active' \
+        AND NOT EXISTS(SELECT 1 FROM disqualified_participants d \
+        WHERE d.contest = p.contest AND d.user = p.user)",
+        params![contest],
+        |row| row.get(0),
+    )
+    .unwrap()
+}
+
+/// Returns the position of the `user` in the waitlist of the `contest`, if waitlisted.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `user` - The participant ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+#[must_use]
+pub fn waitlist_position(ctx: &Context, contest: i64, user: i64) -> Option<i64> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.query_row(
+        "SELECT COUNT(*) FROM contest_participants w, contest_participants p \
+        WHERE p.contest = ? AND p.user = ? AND p.status = 'waitlisted' \
+        AND w.contest = p.contest AND w.status = 'waitlisted' AND w.rowid <= p.rowid",
+        params![contest, user],
+        |row| row.get(0),
+    )
+    .ok()
+    .filter(|position| *position > 0)
+}
+
+/// Registers the `user` as participant of the `contest`, if not already registered. When the
+/// contest is full, the user is put on the waitlist.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `user` - The participant ID
+///
+/// # Returns
+/// The position in the waitlist, or `None` if the user is an active participant.
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the insertion fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn register(ctx: &Context, contest: i64, user: i64) -> rusqlite::Result<Option<i64>> {
+    let max = settings::number(ctx, contest, settings::MAX_PARTICIPANTS);
+    let status = if max == 0 || count_active(ctx, contest) < max {
+        "active"
+    } else {
+        "waitlisted"
+    };
+    {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.execute(
+            "INSERT OR IGNORE INTO contest_participants(contest, user, status) VALUES(?, ?, ?)",
+            params![contest, user, status],
+        )?;
+    }
+    Ok(waitlist_position(ctx, contest, user))
+}
+
+/// Returns the referral link of the `user` for the `contest`, inviting to the channel `chan`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chan` - The channel of the contest
+/// * `contest` - The contest
+/// * `user` - The participant ID
+///
+/// # Panics
+/// Panics if the bot name is missing from the context.
+#[must_use]
+pub fn referral_link(ctx: &Context, chan: &Channel, contest: &Contest, user: i64) -> String {
+    let bot_name = {
+        let guard = ctx.data.read();
+        guard
+            .get::<NameKey>()
+            .expect("name")
+            .clone()
+            .replace('@', "")
+    };
+    let params = BASE64URL
+        .encode(format!("chan={}&contest={}&source={user}", chan.id, contest.id).as_bytes());
+    format!("https://t.me/{bot_name}?start={params}")
+}
+
+/// Sends to the `user` the referral link for the `contest`. In a multi-channel contest, the
+/// user receives a link for every channel: inviting friends to any of them counts.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chan` - The channel of the contest
+/// * `contest` - The contest
+/// * `user` - The participant ID
+///
+/// # Errors
+/// Returns the telegram error if the message can't be sent.
+pub async fn send_link(
+    ctx: &Context,
+    chan: &Channel,
+    contest: &Contest,
+    user: i64,
+) -> telexide_fork::Result<()> {
+    let others = networks::channels(ctx, contest)
+        .into_iter()
+        .filter(|other| other.id != chan.id)
+        .collect::<Vec<_>>();
+    let mut text = format!(
+        "Thank you for joining the {contest_name} contest!\n\
+        Here's the link to use for inviting your friends to join {chan_name}:\n\n\
+        \u{1f449}\u{1f3fb}{invite_link}",
+        contest_name = contest.name,
+        chan_name = chan.name,
+        invite_link = referral_link(ctx, chan, contest, user)
+    );
+    if !others.is_empty() {
+        text += "\n\nInviting friends to the other channels of the contest counts too:";
+        for other in &others {
+            let _ = write!(
+                text,
+                "\n\n{}:\n\u{1f449}\u{1f3fb}{}",
+                other.name,
+                referral_link(ctx, other, contest, user)
+            );
+        }
+    }
+    let mut reply = SendMessage::new(user, &escape_markdown(&text, None));
+    reply.set_parse_mode(&ParseMode::MarkdownV2);
+    ctx.api.send_message(reply).await?;
+    Ok(())
+}
+
+/// Moves the first users of the waitlist of the `contest` to the free slots, and sends them
+/// their referral link. Without a maximum number of participants, the whole waitlist is
+/// promoted.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chan` - The channel of the contest
+/// * `contest` - The contest
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn promote(ctx: &Context, chan: &Channel, contest: &Contest) {
+    if contest.stopped {
+        return;
+    }
+    let max = settings::number(ctx, contest.id, settings::MAX_PARTICIPANTS);
+    let free = if max == 0 {
+        -1
+    } else {
+        (max - count_active(ctx, contest.id)).max(0)
+    };
+    let promoted: Vec<i64> = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT user FROM contest_participants WHERE contest = ? \
+                AND status = 'waitlisted' ORDER BY rowid ASC LIMIT ?",
+            )
+            .unwrap();
+        let users = stmt
+            .query_map(params![contest.id, free], |row| row.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        users
+    };
+    for user in promoted {
+        let res = {
+            let guard = ctx.data.read();
+            let map = guard.get::<DBKey>().expect("db");
+            let conn = map.get().unwrap();
+            conn.execute(
+                "UPDATE contest_participants SET status = 'active' WHERE contest = ? AND user = ?",
+                params![contest.id, user],
+            )
+        };
+        if let Err(err) = res {
+            error!("[promote] {err}");
+            continue;
+        }
+        if let Err(err) = send_link(ctx, chan, contest, user).await {
+            error!("[promote send] {err}");
+        }
+    }
+}
+
+/// Sends to `chat_id` the list of participants of the `contest`, with their invitations,
+/// and the buttons to manage every participant.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chat_id` - The chat ID
+/// * `chan` - The channel of the contest
+/// * `contest` - The contest
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn display_list(ctx: &Context, chat_id: i64, chan: &Channel, contest: &Contest) {
+    let rows = invitations::breakdown(ctx, contest.id);
+    let mut text = format!("Participants of {}\n\n", contest.name);
+    if rows.is_empty() {
+        text += "No participants, yet!";
+    }
+    let mut buttons = vec![];
+    for (i, row) in rows.iter().enumerate() {
+        let counted: i64 = InvitationStatus::ALL
+            .iter()
+            .zip(row.counts)
+            .filter(|(status, _)| status.is_counted())
+            .map(|(_, count)| count)
+            .sum();
+        let total: i64 = row.counts.iter().sum();
+        let name = users::display_name(&row.user);
+        let _ = write!(text, "{}. {name} - {counted}/{total} invites", i + 1);
+        if disqualification(ctx, contest.id, row.user.id).is_some() {
+            text += " \u{1f6ab} disqualified";
+        }
+        text += "\n";
+        // Telegram supports up to 100 buttons per keyboard
+        if i < 50 {
+            buttons.push(callback_button(
+                &format!("{}. {name}", i + 1),
+                &format!("participant {} {} {}", chan.id, contest.id, row.user.id),
+            ));
+        }
+    }
+    if !rows.is_empty() {
+        text += "\nInvites: counted/total. Select a participant to manage it.";
+    }
+    let waitlisted = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.query_row(
+            "SELECT COUNT(*) FROM contest_participants WHERE contest = ? AND status = 'waitlisted'",
+            params![contest.id],
+            |row| row.get::<_, i64>(0),
+        )
+        .unwrap()
+    };
+    if waitlisted > 0 {
+        let _ = write!(text, "\n{waitlisted} users in the waitlist.");
+    }
+
+    let mut inline_keyboard = buttons
+        .chunks(2)
+        .map(<[_]>::to_vec)
+        .collect::<Vec<Vec<_>>>();
+    inline_keyboard.push(vec![
+        callback_button(
+            "\u{1f4dc} Audit log",
+            &format!("audit {} {}", chan.id, contest.id),
+        ),
+        callback_button("\u{1f519} Manage", &format!("manage {}", chan.id)),
+    ]);
+
+    let mut reply = SendMessage::new(chat_id, &escape_markdown(&text, None));
+    reply.set_parse_mode(&ParseMode::MarkdownV2);
+    reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+        inline_keyboard,
+    }));
+    let res = ctx.api.send_message(reply).await;
+    if res.is_err() {
+        let err = res.err().unwrap();
+        error!("[participants list] {err}");
+    }
+}
+
+/// Sends to `chat_id` the detail of the participant `user` of the `contest`: the
+/// invitations sent, and the buttons to revoke/restore them or to disqualify the participant.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chat_id` - The chat ID
+/// * `chan` - The channel of the contest
+/// * `contest` - The contest
+/// * `user` - The participant ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn display(ctx: &Context, chat_id: i64, chan: &Channel, contest: &Contest, user: i64) {
+    let participant = users::get(ctx, user);
+    if participant.is_none() {
+        return;
+    }
+    let participant = participant.unwrap();
+    let disqualified = disqualification(ctx, contest.id, user);
+    let mut text = format!("{} - {}\n", users::display_name(&participant), contest.name);
+    if let Some(reason) = &disqualified {
+        let _ = writeln!(text, "\u{1f6ab} Disqualified: {reason}");
+    }
+    text += "\nInvitations:\n";
+
+    let mut buttons = vec![];
+    for invite in invitations::sent_by(ctx, contest.id, user) {
+        let invitee = users::get(ctx, invite.dest)
+            .map_or_else(|| invite.dest.to_string(), |u| users::display_name(&u));
+        let _ = write!(text, "#{} {invitee} - {}", invite.id, invite.status);
+        if invite.credit < 1.0 {
+            let _ = write!(text, " [credit {:.2}]", invite.credit);
+        }
+        if let Some(reason) = &invite.reason {
+            let _ = write!(text, " ({reason})");
+        }
+        text += "\n";
+
+        let (label, action) = match invite.status {
+            InvitationStatus::Disqualified | InvitationStatus::Left => ("Restore", "restore"),
+            _ => ("Revoke", "revoke"),
+        };
+        if buttons.len() < 90 {
+            buttons.push(callback_button(
+                &format!("{label} #{}", invite.id),
+                &format!("{action} {} {} {}", chan.id, contest.id, invite.id),
+            ));
+        }
+    }
+
+    let ledger = adjustments::get(ctx, contest.id, user);
+    if !ledger.is_empty() {
+        text += "\nAdjustments:\n";
+        for adjustment in ledger {
+            let _ = writeln!(
+                text,
+                "{} {:+} - {}",
+                adjustment.date.format("%Y-%m-%d %H:%M"),
+                adjustment.points,
+                adjustment.reason
+            );
+        }
+    }
+
+    let mut inline_keyboard = buttons
+        .chunks(3)
+        .map(<[_]>::to_vec)
+        .collect::<Vec<Vec<_>>>();
+    if disqualified.is_some() {
+        inline_keyboard.push(vec![callback_button(
+            "\u{2705} Requalify",
+            &format!("requalify {} {} {user}", chan.id, contest.id),
+        )]);
+    } else {
+        inline_keyboard.push(vec![
+            callback_button(
+                "\u{1f6ab} Disqualify",
+                &format!("dq {} {} {user} 0", chan.id, contest.id),
+            ),
+            callback_button(
+                "\u{1f6ab} Disqualify and notify",
+                &format!("dq {} {} {user} 1", chan.id, contest.id),
+            ),
+        ]);
+    }
+    inline_keyboard.push(vec![
+        callback_button(
+            "\u{270f}\u{fe0f} Adjust credit",
+            &format!("adjust {} {} {user}", chan.id, contest.id),
+        ),
+        callback_button(
+            "\u{1f519} Participants",
+            &format!("participants_contest {} {}", chan.id, contest.id),
+        ),
+    ]);
+
+    let mut reply = SendMessage::new(chat_id, &escape_markdown(&text, None));
+    reply.set_parse_mode(&ParseMode::MarkdownV2);
+    reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+        inline_keyboard,
+    }));
+    let res = ctx.api.send_message(reply).await;
+    if res.is_err() {
+        let err = res.err().unwrap();
+        error!("[participant detail] {err}");
+    }
+}
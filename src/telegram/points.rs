@@ -0,0 +1,357 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use log::error;
+use rusqlite::params;
+use std::fmt::Write;
+use telexide_fork::{
+    api::types::SendMessage,
+    model::{InlineKeyboardMarkup, ParseMode, ReplyMarkup},
+    prelude::*,
+};
+
+use crate::persistence::types::{
+    Channel, Contest, DBKey, InvitationStatus, PointsBreakdown, PointsFormula,
+};
+use crate::telegram::messages::{callback_button, escape_markdown};
+use crate::telegram::{audit, settings};
+
+/// Returns the points model of the `contest`, or `None` if the contest is won by the raw count
+/// of the invitations.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+#[must_use]
+pub fn formula(ctx: &Context, contest: i64) -> Option<PointsFormula> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.query_row(
+        "SELECT base, member_bonus, active_bonus, leave_penalty FROM point_rules \
+        WHERE contest = ?",
+        params![contest],
+        |row| {
+            Ok(PointsFormula {
+                base: row.get(0)?,
+                member_bonus: row.get(1)?,
+                active_bonus: row.get(2)?,
+                leave_penalty: row.get(3)?,
+            })
+        },
+    )
+    .ok()
+}
+
+/// Parses the points model written by the owner: four numbers separated by spaces, the points
+/// per invite, the bonus per invitee still a member, the bonus per active invitee, and the
+/// penalty per invitee that left (e.g. "1 1 2 1").
+///
+/// # Arguments
+/// * `text` - The text written by the owner
+///
+/// # Errors
+/// Returns the reason of the failure, ready to be shown to the owner.
+pub fn parse(text: &str) -> Result<PointsFormula, String> {
+    let numbers = text
+        .split_whitespace()
+        .map(|n| {
+            n.parse::<i64>()
+                .ok()
+                .filter(|n| *n >= 0)
+                .ok_or(format!("{n} is not a valid number of points"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    match numbers[..] {
+        [base, member_bonus, active_bonus, leave_penalty] => Ok(PointsFormula {
+            base,
+            member_bonus,
+            active_bonus,
+            leave_penalty,
+        }),
+        _ => Err("write exactly 4 numbers".to_string()),
+    }
+}
+
+/// Replaces the points model of the `contest` with the one written by the owner. A single "-"
+/// removes it: the contest is won by the raw count of the invitations. The action is added to
+/// the audit log.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `text` - The text written by the owner
+/// * `actor` - The user changing the points model
+///
+/// # Errors
+/// Returns the reason of the failure, ready to be shown to the owner.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn set(ctx: &Context, contest: i64, text: &str, actor: i64) -> Result<(), String> {
+    let formula = if text.trim() == "-" {
+        None
+    } else {
+        Some(parse(text)?)
+    };
+    {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        match formula {
+            Some(f) => conn.execute(
+                "INSERT OR REPLACE INTO point_rules(contest, base, member_bonus, active_bonus, \
+                leave_penalty) VALUES(?, ?, ?, ?, ?)",
+                params![
+                    contest,
+                    f.base,
+                    f.member_bonus,
+                    f.active_bonus,
+                    f.leave_penalty
+                ],
+            ),
+            None => conn.execute(
+                "DELETE FROM point_rules WHERE contest = ?",
+                params![contest],
+            ),
+        }
+        .map_err(|err| err.to_string())?;
+    }
+    let action = formula.map_or("points off".to_string(), |f| {
+        format!(
+            "points {} {} {} {}",
+            f.base, f.member_bonus, f.active_bonus, f.leave_penalty
+        )
+    });
+    audit::log(ctx, contest, actor, &action, None, None).map_err(|err| err.to_string())
+}
+
+/// Returns the SQL aggregate computing the points of the rows of `invitations` grouped by
+/// source. The query parameters are the points per invite (`?4`), the bonus per invitee still
+/// a member (`?5`), the bonus per active invitee (`?6`), and the penalty per invitee that left
+/// (`?7`).
+#[must_use]
+pub fn sql() -> String {
+    format!(
+        "SUM(CASE WHEN {counted} THEN credit * ?4 ELSE 0.0 END)
+        + SUM(CASE WHEN status = '{qualified}' THEN credit * ?5 ELSE 0.0 END)
+        + SUM(CASE WHEN {counted} AND id IN (SELECT invitation FROM invitation_activity)
+            THEN credit * ?6 ELSE 0.0 END)
+        - SUM(CASE WHEN status = '{left}' THEN credit * ?7 ELSE 0.0 END)",
+        counted = InvitationStatus::COUNTED,
+        qualified = InvitationStatus::Qualified,
+        left = InvitationStatus::Left,
+    )
+}
+
+/// Records that the `user` wrote in the group `chat`: the invitations of the user in the
+/// running contests of the group earn the activity bonus.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chat` - The group ID
+/// * `user` - The user that wrote the message
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the insertion fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn record_activity(ctx: &Context, chat: i64, user: i64) -> rusqlite::Result<()> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.execute(
+        "INSERT OR IGNORE INTO invitation_activity(invitation) \
+        SELECT invitations.id FROM invitations \
+        INNER JOIN contests ON invitations.contest = contests.id \
+        WHERE invitations.chan = ? AND invitations.dest = ? \
+        AND contests.started_at IS NOT NULL AND contests.stopped IS FALSE",
+        params![chat, user],
+    )?;
+    Ok(())
+}
+
+/// Returns the invitations of the `user` in the `contest` that earn (or lose) points, per rule
+/// of the points model.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `user` - The participant
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+#[must_use]
+pub fn breakdown(ctx: &Context, contest: i64, user: i64) -> PointsBreakdown {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.query_row(
+        &format!(
+            "SELECT COALESCE(SUM(CASE WHEN {counted} THEN credit ELSE 0.0 END), 0.0),
+            COALESCE(SUM(CASE WHEN status = '{qualified}' THEN credit ELSE 0.0 END), 0.0),
+            COALESCE(SUM(CASE WHEN {counted} AND id IN (SELECT invitation FROM invitation_activity)
+                THEN credit ELSE 0.0 END), 0.0),
+            COALESCE(SUM(CASE WHEN status = '{left}' THEN credit ELSE 0.0 END), 0.0)
+            FROM invitations WHERE contest = ? AND source = ?",
+            counted = InvitationStatus::COUNTED,
+            qualified = InvitationStatus::Qualified,
+            left = InvitationStatus::Left,
+        ),
+        params![contest, user],
+        |row| {
+            Ok(PointsBreakdown {
+                invites: row.get(0)?,
+                members: row.get(1)?,
+                active: row.get(2)?,
+                left: row.get(3)?,
+            })
+        },
+    )
+    .unwrap()
+}
+
+/// Returns the points earned with the `breakdown`, according to the formula `f`, one line per
+/// rule, and the total.
+///
+/// # Arguments
+/// * `f` - The points model
+/// * `breakdown` - The invitations of a participant, per rule
+#[must_use]
+pub fn explain(f: &PointsFormula, breakdown: &PointsBreakdown) -> (Vec<String>, f64) {
+    let rules = [
+        ("invites", breakdown.invites, f.base),
+        ("still members", breakdown.members, f.member_bonus),
+        ("active in the group", breakdown.active, f.active_bonus),
+        ("left", breakdown.left, -f.leave_penalty),
+    ];
+    let mut total = 0.0;
+    let mut lines = vec![];
+    for (label, count, points) in rules {
+        if points == 0 || count == 0.0 {
+            continue;
+        }
+        #[allow(clippy::cast_precision_loss)]
+        let earned = count * points as f64;
+        total += earned;
+        lines.push(format!("{count} {label} \u{d7} {points:+} = {earned:+}"));
+    }
+    (lines, total)
+}
+
+/// Returns the description of the points model of the contest `c`, shown in the
+/// announcement, or `None` if the contest is won by the raw count of the invitations.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+#[must_use]
+pub fn rule(ctx: &Context, c: &Contest) -> Option<String> {
+    let f = formula(ctx, c.id)?;
+    let mut rule = format!("Every invited friend is worth {} points", f.base);
+    if f.member_bonus != 0 {
+        let _ = write!(
+            rule,
+            ", +{} if still a member when the contest finishes",
+            f.member_bonus
+        );
+    }
+    if f.active_bonus != 0 {
+        let _ = write!(rule, ", +{} if active in the group", f.active_bonus);
+    }
+    if f.leave_penalty != 0 {
+        let _ = write!(rule, ", -{} if they leave", f.leave_penalty);
+    }
+    rule += ".";
+    Some(rule)
+}
+
+/// Sends to `chat_id` the points model of the `contest`, and the buttons to change it.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chat_id` - The chat ID
+/// * `chan` - The channel of the contest
+/// * `contest` - The contest
+pub async fn display(ctx: &Context, chat_id: i64, chan: &Channel, contest: &Contest) {
+    let mut text = format!("Points of {}\n\n", contest.name);
+    if let Some(f) = formula(ctx, contest.id) {
+        let _ = writeln!(text, "Points per invite: {}", f.base);
+        let _ = writeln!(text, "Bonus per invitee still a member: {}", f.member_bonus);
+        let _ = writeln!(
+            text,
+            "Bonus per invitee active in the group: {}",
+            f.active_bonus
+        );
+        let _ = writeln!(text, "Penalty per invitee that left: {}", f.leave_penalty);
+    } else {
+        text += "No points model: the contest is won by the number of invites.\n";
+    }
+    text += "\nThe bonus of the members is given when the contest finishes. An invitee is active \
+        once they write in the group while the contest is running.";
+
+    let mut reply = SendMessage::new(chat_id, &escape_markdown(&text, None));
+    reply.set_parse_mode(&ParseMode::MarkdownV2);
+    // The points model is a rule: it can't change once the contest started
+    let mut buttons = vec![];
+    if settings::rules_changeable(contest).is_ok() {
+        buttons.push(callback_button(
+            "\u{270f}\u{fe0f} Edit points",
+            &format!("points_edit {} {}", chan.id, contest.id),
+        ));
+    }
+    buttons.push(callback_button(
+        "\u{2699}\u{fe0f} Settings",
+        &format!("settings_contest {} {}", chan.id, contest.id),
+    ));
+    reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+        inline_keyboard: vec![buttons],
+    }));
+    let res = ctx.api.send_message(reply).await;
+    if let Err(err) = res {
+        error!("[points send] {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_formula() {
+        assert_eq!(
+            parse("10 5 2 3"),
+            Ok(PointsFormula {
+                base: 10,
+                member_bonus: 5,
+                active_bonus: 2,
+                leave_penalty: 3,
+            })
+        );
+        assert_eq!(parse(" 1  0 0 0 "), Ok(PointsFormula::default()));
+    }
+
+    #[test]
+    fn parse_malformed_formula() {
+        assert!(parse("1 2 3").is_err());
+        assert!(parse("1 2 3 4 5").is_err());
+        assert!(parse("-1 0 0 0").is_err());
+        assert!(parse("a b c d").is_err());
+        assert!(parse("").is_err());
+    }
+}
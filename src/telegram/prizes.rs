@@ -0,0 +1,306 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use log::error;
+use rusqlite::params;
+use std::fmt::Write;
+use telexide_fork::{
+    api::types::SendMessage,
+    model::{InlineKeyboardMarkup, ParseMode, ReplyMarkup},
+    prelude::*,
+};
+
+use crate::persistence::types::{
+    Channel, Contest, ContestResult, DBKey, FulfillmentStatus, PrizeDelivery,
+};
+use crate::telegram::messages::{callback_button, escape_markdown};
+use crate::telegram::{codes, fulfillment, users};
+
+/// Number of prize tiers (final positions) that can have a stock of codes.
+pub const TIERS: i64 = 3;
+
+/// Returns the name of the stock of codes of the prize `tier`.
+///
+/// # Arguments
+/// * `tier` - The final position in the chart
+#[must_use]
+pub fn pool(tier: i64) -> String {
+    format!("prize:{tier}")
+}
+
+/// Returns the prize deliveries of the `contest`, ordered by rank.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+#[must_use]
+pub fn get_all(ctx: &Context, contest: i64) -> Vec<PrizeDelivery> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let mut stmt = conn
+        .prepare(
+            "SELECT user, rank, code, status, claimed_at, date FROM prize_deliveries \
+            WHERE contest = ? ORDER BY rank ASC, date ASC",
+        )
+        .unwrap();
+    let deliveries = stmt
+        .query_map(params![contest], |row| {
+            Ok(PrizeDelivery {
+                contest,
+                user: row.get(0)?,
+                rank: row.get(1)?,
+                code: row.get(2)?,
+                status: row.get(3)?,
+                claimed_at: row.get(4)?,
+                date: row.get(5)?,
+            })
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    deliveries
+}
+
+/// Records the delivery of the prize of the `contest` to the `user`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `user` - The winner
+/// * `rank` - The final position of the winner
+/// * `code` - The code sent, if any
+/// * `status` - The outcome of the delivery
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the insertion fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+fn record(
+    ctx: &Context,
+    contest: i64,
+    user: i64,
+    rank: i64,
+    code: Option<&str>,
+    status: &str,
+) -> rusqlite::Result<()> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.execute(
+        "INSERT OR REPLACE INTO prize_deliveries(contest, user, rank, code, status) \
+        VALUES(?, ?, ?, ?, ?)",
+        params![contest, user, rank, code, status],
+    )?;
+    Ok(())
+}
+
+/// Sends privately to the winners of the contest `c`, and to the participants ranked in the
+/// first `TIERS` positions, a code from the stock of their tier: a stock of codes is a prize
+/// by itself. The tiers without a stock are skipped: the owner delivers their prize manually.
+/// A code that can't be delivered goes back to the stock. The owner is notified when a stock
+/// runs out, or when a code can't be delivered.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chan` - The channel of the contest
+/// * `c` - The finished contest
+/// * `results` - The official results of the contest
+///
+/// # Returns
+/// The winners that received their code.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn deliver(
+    ctx: &Context,
+    chan: &Channel,
+    c: &Contest,
+    results: &[ContestResult],
+) -> Vec<i64> {
+    let mut delivered = vec![];
+    for result in results
+        .iter()
+        .filter(|r| r.prize.is_some() || r.rank <= TIERS)
+    {
+        let pool = pool(result.rank);
+        let (_, total) = codes::stock(ctx, c.id, &pool);
+        if total == 0 {
+            continue;
+        }
+        let mut code = match codes::take(ctx, c.id, &pool, result.user.id) {
+            Ok(code) => code,
+            Err(err) => {
+                error!("[prize code] {err}");
+                None
+            }
+        };
+        let status = if let Some(taken) = code.clone() {
+            let text = format!(
+                "\u{1f3c6} Congratulations! You ranked #{} in the {} contest.\n\n\
+                Here's your prize code: {taken}\n\nPress Received once you redeemed it.",
+                result.rank, c.name
+            );
+            let mut reply = SendMessage::new(result.user.id, &escape_markdown(&text, None));
+            reply.set_parse_mode(&ParseMode::MarkdownV2);
+            reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+                inline_keyboard: fulfillment::winner_keyboard(c.id, FulfillmentStatus::Shipped),
+            }));
+            match ctx.api.send_message(reply).await {
+                Ok(_) => {
+                    delivered.push(result.user.id);
+                    "sent"
+                }
+                Err(err) => {
+                    error!("[prize send] {err}");
+                    match codes::release(ctx, c.id, &pool, &taken) {
+                        Ok(()) => code = None,
+                        Err(err) => error!("[prize release] {err}"),
+                    }
+                    "failed"
+                }
+            }
+        } else {
+            "out_of_stock"
+        };
+        if let Err(err) = record(
+            ctx,
+            c.id,
+            result.user.id,
+            result.rank,
+            code.as_deref(),
+            status,
+        ) {
+            error!("[prize record] {err}");
+        }
+        if status != "sent" {
+            let name = users::display_name(&result.user);
+            let text = if status == "failed" {
+                format!(
+                    "\u{26a0}\u{fe0f} The prize code of {name} (#{}) can't be delivered: the \
+                    winner can't be contacted by the bot. The code went back to the stock: \
+                    deliver the prize manually.",
+                    result.rank
+                )
+            } else {
+                format!(
+                    "\u{26a0}\u{fe0f} The stock of prize codes of the tier #{} ran out: {name} \
+                    didn't receive a code. Deliver the prize manually.",
+                    result.rank
+                )
+            };
+            let res = ctx
+                .api
+                .send_message(SendMessage::new(chan.registered_by, &text))
+                .await;
+            if let Err(err) = res {
+                error!("[prize owner send] {err}");
+            }
+        }
+    }
+    delivered
+}
+
+/// Records that the winner `user` claimed the prize code of the `contest`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `user` - The winner
+///
+/// # Returns
+/// False if the user has no prize code to claim, or if it has already been claimed.
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn claim(ctx: &Context, contest: i64, user: i64) -> rusqlite::Result<bool> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let changed = conn.execute(
+        "UPDATE prize_deliveries SET claimed_at = CURRENT_TIMESTAMP \
+        WHERE contest = ? AND user = ? AND status = 'sent' AND claimed_at IS NULL",
+        params![contest, user],
+    )?;
+    Ok(changed > 0)
+}
+
+/// Sends to `chat_id` the stocks of prize codes of every tier of the `contest`, the deliveries
+/// (if the contest is finished), and the buttons to add codes.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chat_id` - The chat ID
+/// * `chan` - The channel of the contest
+/// * `contest` - The contest
+pub async fn display(ctx: &Context, chat_id: i64, chan: &Channel, contest: &Contest) {
+    let mut text = format!("Prize codes of {}\n\n", contest.name);
+    let mut inline_keyboard = vec![];
+    for tier in 1..=TIERS {
+        let (available, total) = codes::stock(ctx, contest.id, &pool(tier));
+        let _ = writeln!(text, "#{tier}: codes {available}/{total}");
+        inline_keyboard.push(vec![callback_button(
+            &format!("\u{1f39f} Add codes for #{tier}"),
+            &format!("codes {} {} {}", chan.id, contest.id, pool(tier)),
+        )]);
+    }
+    text += "\nCodes: available/total. The participants ranked in a tier with codes receive one \
+        privately, when the contest finishes.\n";
+
+    let deliveries = get_all(ctx, contest.id);
+    if !deliveries.is_empty() {
+        text += "\nDeliveries\n";
+    }
+    for delivery in deliveries {
+        let name = users::get(ctx, delivery.user)
+            .map_or_else(|| delivery.user.to_string(), |u| users::display_name(&u));
+        let claimed = if delivery.claimed_at.is_some() {
+            ", claimed"
+        } else {
+            ""
+        };
+        let _ = writeln!(
+            text,
+            "#{} {name}: {}{claimed}",
+            delivery.rank, delivery.status
+        );
+    }
+    inline_keyboard.push(vec![
+        callback_button(
+            "\u{1f4e6} Fulfillment",
+            &format!("fulfillment {} {}", chan.id, contest.id),
+        ),
+        callback_button(
+            "\u{2699}\u{fe0f} Settings",
+            &format!("settings_contest {} {}", chan.id, contest.id),
+        ),
+    ]);
+
+    let mut reply = SendMessage::new(chat_id, &escape_markdown(&text, None));
+    reply.set_parse_mode(&ParseMode::MarkdownV2);
+    reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+        inline_keyboard,
+    }));
+    let res = ctx.api.send_message(reply).await;
+    if let Err(err) = res {
+        error!("[prizes send] {err}");
+    }
+}
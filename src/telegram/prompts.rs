@@ -0,0 +1,114 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use rusqlite::params;
+use telexide_fork::prelude::*;
+
+use crate::persistence::types::{BeingManagedContest, DBKey};
+
+/// Minutes the owner has to answer a prompt. Older prompts are ignored, so a message sent
+/// much later is not mistaken for an answer.
+const ANSWER_WINDOW: i64 = 10;
+
+/// Stores that the `owner` has been asked to write something for the `action` on the
+/// `contest`. Only the last prompt of an owner can be answered: the previous ones are
+/// discarded.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `owner` - The owner that must answer
+/// * `contest` - The contest being managed
+/// * `action` - The action the owner is answering to
+/// * `arg` - Optional argument of the action
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the insertion fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn ask(
+    ctx: &Context,
+    owner: i64,
+    contest: i64,
+    action: &str,
+    arg: Option<&str>,
+) -> rusqlite::Result<()> {
+    clear(ctx, owner)?;
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.execute(
+        "INSERT INTO being_managed_contests(owner, contest, action, arg) VALUES(?, ?, ?, ?)",
+        params![owner, contest, action, arg],
+    )?;
+    Ok(())
+}
+
+/// Returns the prompt the `owner` is expected to answer, if any.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `owner` - The owner
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+#[must_use]
+pub fn pending(ctx: &Context, owner: i64) -> Option<BeingManagedContest> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT id, contest, action, arg FROM being_managed_contests \
+            WHERE owner = ? AND done IS FALSE AND date >= datetime('now', '-{ANSWER_WINDOW} minutes') \
+            ORDER BY id DESC LIMIT 1"
+        ))
+        .unwrap();
+    let prompt = stmt
+        .query_map(params![owner], |row| {
+            Ok(BeingManagedContest {
+                id: row.get(0)?,
+                owner,
+                contest: row.get(1)?,
+                action: row.get(2)?,
+                arg: row.get(3)?,
+            })
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .next();
+    prompt
+}
+
+/// Discards all the prompts of the `owner`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `owner` - The owner
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn clear(ctx: &Context, owner: i64) -> rusqlite::Result<()> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.execute(
+        "UPDATE being_managed_contests SET done = TRUE WHERE owner = ? AND done IS FALSE",
+        params![owner],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,106 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::convert::TryFrom;
+use telexide_fork::prelude::*;
+
+use crate::persistence::types::{Contest, Rank};
+use crate::telegram::{fairness, settings};
+
+/// Returns true if the winners of the `contest` are drawn at random: in the raffle mode, and in
+/// the giveaways.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+#[must_use]
+pub fn enabled(ctx: &Context, contest: i64) -> bool {
+    matches!(
+        settings::get(ctx, contest, settings::MODE).as_str(),
+        "raffle" | "giveaway"
+    )
+}
+
+/// Returns the number of winners of the `contest`: the winners drawn in the raffle and
+/// giveaway modes, the first in the chart otherwise.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+#[must_use]
+pub fn winners(ctx: &Context, contest: i64) -> i64 {
+    if enabled(ctx, contest) {
+        settings::number(ctx, contest, settings::WINNERS).max(1)
+    } else {
+        1
+    }
+}
+
+/// Draws at most `winners` participants of the `rank` with the `seed`, without repeats. The
+/// score of every participant is the number of its tickets: the more tickets, the higher the
+/// chances. The participants without tickets can't win. See `fairness::draw`.
+///
+/// # Arguments
+/// * `rank` - The ranking of the contest
+/// * `winners` - The number of winners to draw
+/// * `seed` - The revealed seed of the contest
+///
+/// # Returns
+/// The drawn winners, in drawing order, followed by the other participants in ranking order.
+/// The positions are updated accordingly.
+#[must_use]
+pub fn draw(mut rank: Vec<Rank>, winners: i64, seed: &str) -> Vec<Rank> {
+    let drawn = fairness::draw(seed, usize::try_from(winners).unwrap_or(0), &entries(&rank));
+    let mut chart = vec![];
+    for user in drawn {
+        if let Some(position) = rank.iter().position(|row| row.user.id == user) {
+            chart.push(rank.remove(position));
+        }
+    }
+    chart.append(&mut rank);
+    for (position, row) in (1..).zip(chart.iter_mut()) {
+        row.rank = position;
+    }
+    chart
+}
+
+/// Returns the user ID and the tickets of every participant of the `rank`.
+///
+/// # Arguments
+/// * `rank` - The ranking of the contest
+#[must_use]
+pub fn entries(rank: &[Rank]) -> Vec<(i64, u64)> {
+    rank.iter()
+        .map(|row| (row.user.id, fairness::tickets(row.score)))
+        .collect()
+}
+
+/// Returns the description of the raffle of the contest `c`, shown in the announcement, or
+/// `None` if the winners are chosen by ranking.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+#[must_use]
+pub fn rule(ctx: &Context, c: &Contest) -> Option<String> {
+    if settings::get(ctx, c.id, settings::MODE) != "raffle" {
+        return None;
+    }
+    Some(format!(
+        "Every invited friend is a raffle ticket: {} winners are drawn at random when the \
+        contest finishes. The more tickets, the higher the chances. The draw is provably fair: \
+        its seed is committed below, and revealed with the results.",
+        winners(ctx, c.id)
+    ))
+}
@@ -0,0 +1,114 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use rusqlite::params;
+use telexide_fork::prelude::*;
+
+use crate::persistence::types::{Contest, DBKey, InvitationStatus, ReferralLevel};
+use crate::telegram::settings;
+
+/// Returns the number of levels of the referral graph credited in the `contest`, and the
+/// weight of every level relative to the previous one.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+#[must_use]
+pub fn config(ctx: &Context, contest: i64) -> (i64, f64) {
+    let levels = settings::get(ctx, contest, settings::REFERRAL_LEVELS)
+        .parse()
+        .unwrap_or(1);
+    let weight = settings::get(ctx, contest, settings::REFERRAL_WEIGHT)
+        .parse()
+        .unwrap_or(0.5);
+    (levels, weight)
+}
+
+/// Returns the recursive common table expression `tree(root, dest, level, share, credit,
+/// date)` with the referral graph of a contest: every counted invitation sent by `root`
+/// (level 1), by its invitees (level 2), and so on.
+///
+/// `share` is the product of the credits of the invitations along the path, `credit` is the
+/// share weighted by the level. The query parameters are the contest (`?1`), the number of
+/// levels (`?2`) and the weight of every level relative to the previous one (`?3`).
+#[must_use]
+pub fn graph() -> String {
+    format!(
+        "WITH RECURSIVE tree(root, dest, level, share, credit, date) AS (
+            SELECT source, dest, 1, credit, credit, date FROM invitations
+            WHERE contest = ?1 AND {counted}
+            UNION ALL
+            SELECT tree.root, i.dest, tree.level + 1, tree.share * i.credit,
+            tree.credit * i.credit * ?3, i.date
+            FROM tree INNER JOIN invitations AS i ON i.source = tree.dest
+            WHERE i.contest = ?1 AND i.{counted} AND i.dest <> tree.root AND tree.level < ?2
+        )",
+        counted = InvitationStatus::COUNTED
+    )
+}
+
+/// Returns the credit of the `user` in the contest `c`, level by level of the referral graph.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+/// * `user` - The participant
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+#[must_use]
+pub fn breakdown(ctx: &Context, c: &Contest, user: i64) -> Vec<ReferralLevel> {
+    let (levels, weight) = config(ctx, c.id);
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let mut stmt = conn
+        .prepare(&format!(
+            "{graph}
+            SELECT level, SUM(share), SUM(credit) FROM tree WHERE root = ?4
+            GROUP BY level ORDER BY level ASC",
+            graph = graph()
+        ))
+        .unwrap();
+    let breakdown = stmt
+        .query_map(params![c.id, levels, weight, user], |row| {
+            Ok(ReferralLevel {
+                level: row.get(0)?,
+                invites: row.get(1)?,
+                points: row.get(2)?,
+            })
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    breakdown
+}
+
+/// Returns the description of the multi-level referral scoring of the contest `c`, shown in
+/// the announcement, or `None` if only the direct invitations count.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+#[must_use]
+pub fn rule(ctx: &Context, c: &Contest) -> Option<String> {
+    let (levels, weight) = config(ctx, c.id);
+    if levels <= 1 {
+        return None;
+    }
+    Some(format!(
+        "The friends invited by your friends count too, up to {levels} levels: every level is \
+        worth {weight} times the previous one."
+    ))
+}
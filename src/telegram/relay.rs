@@ -0,0 +1,273 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use log::error;
+use rusqlite::params;
+use telexide_fork::{
+    api::types::{CopyMessage, SendMessage},
+    model::{InlineKeyboardButton, InlineKeyboardMarkup, Message, ReplyMarkup},
+    prelude::*,
+};
+
+use crate::persistence::types::{Channel, Contest, DBKey, RelaySession};
+use crate::telegram::messages::callback_button;
+use crate::telegram::{audit, contests};
+
+/// Returns the open session matching the SQL `condition` on the `user`, the latest first.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `condition` - SQL condition with a single parameter
+/// * `user` - The value of the parameter
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+fn find(ctx: &Context, condition: &str, user: i64) -> Option<RelaySession> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT id, contest, owner, winner FROM relay_sessions \
+            WHERE open IS TRUE AND {condition} ORDER BY id DESC LIMIT 1"
+        ))
+        .unwrap();
+    let session = stmt
+        .query_map(params![user], |row| {
+            Ok(RelaySession {
+                id: row.get(0)?,
+                contest: row.get(1)?,
+                owner: row.get(2)?,
+                winner: row.get(3)?,
+            })
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .next();
+    session
+}
+
+/// Opens (or reopens) the session between the `owner` and the `winner` of the `contest`, and
+/// makes it the one the owner is writing to.
+///
+/// # Returns
+/// True if the session was not open.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `owner` - The owner of the contest
+/// * `winner` - The winner
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the insertion fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn open(ctx: &Context, contest: i64, owner: i64, winner: i64) -> rusqlite::Result<bool> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let already_open: bool = conn
+        .query_row(
+            "SELECT COUNT(*) > 0 FROM relay_sessions \
+            WHERE contest = ? AND winner = ? AND open IS TRUE",
+            params![contest, winner],
+            |row| row.get(0),
+        )
+        .unwrap_or(false);
+    conn.execute(
+        "UPDATE relay_sessions SET owner_focus = FALSE WHERE owner = ?",
+        params![owner],
+    )?;
+    conn.execute(
+        "INSERT INTO relay_sessions(contest, owner, winner, owner_focus) VALUES(?, ?, ?, TRUE) \
+        ON CONFLICT(contest, winner) DO UPDATE SET open = TRUE, owner_focus = TRUE, \
+        closed_at = NULL",
+        params![contest, owner, winner],
+    )?;
+    Ok(!already_open)
+}
+
+/// Stops relaying the messages of the `owner` to the winners. The sessions stay open: the
+/// messages of the winners still reach the owner.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `owner` - The owner
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn leave(ctx: &Context, owner: i64) -> rusqlite::Result<()> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.execute(
+        "UPDATE relay_sessions SET owner_focus = FALSE WHERE owner = ?",
+        params![owner],
+    )?;
+    Ok(())
+}
+
+/// Closes the session between the `owner` and the `winner` of the `contest`: no more messages
+/// are relayed. The action is added to the audit log.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `owner` - The owner closing the session
+/// * `winner` - The winner
+///
+/// # Returns
+/// False if there was no open session.
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn close(ctx: &Context, contest: i64, owner: i64, winner: i64) -> rusqlite::Result<bool> {
+    let closed = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.execute(
+            "UPDATE relay_sessions SET open = FALSE, owner_focus = FALSE, \
+            closed_at = CURRENT_TIMESTAMP \
+            WHERE contest = ? AND owner = ? AND winner = ? AND open IS TRUE",
+            params![contest, owner, winner],
+        )?
+    };
+    if closed > 0 {
+        audit::log(ctx, contest, owner, "relay close", Some(winner), None)?;
+    }
+    Ok(closed > 0)
+}
+
+/// Returns the buttons of the owner to manage the session with the `winner` of the `contest`.
+///
+/// # Arguments
+/// * `chan` - The channel of the contest
+/// * `contest` - The contest ID
+/// * `winner` - The winner
+#[must_use]
+pub fn owner_keyboard(chan: i64, contest: i64, winner: i64) -> Vec<Vec<InlineKeyboardButton>> {
+    vec![vec![
+        callback_button(
+            "\u{21a9}\u{fe0f} Reply",
+            &format!("relay {chan} {contest} {winner} open"),
+        ),
+        callback_button(
+            "\u{23f8} Leave chat",
+            &format!("relay {chan} {contest} {winner} leave"),
+        ),
+        callback_button(
+            "\u{274c} Close chat",
+            &format!("relay {chan} {contest} {winner} close"),
+        ),
+    ]]
+}
+
+/// Opens the session between the owner of the `chan` and the `winner` of the contest `c`, and
+/// informs the owner. The winner is informed only when the session was not open.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chan` - The channel of the contest
+/// * `c` - The contest
+/// * `winner` - The winner
+pub async fn start(ctx: &Context, chan: &Channel, c: &Contest, winner: i64) {
+    let opened = match open(ctx, c.id, chan.registered_by, winner) {
+        Ok(opened) => opened,
+        Err(err) => {
+            error!("[relay open] {err}");
+            return;
+        }
+    };
+    let mut reply = SendMessage::new(
+        chan.registered_by,
+        &format!(
+            "\u{1f4ac} You are chatting with the winner of the {} contest. Every message you \
+            send here (text or media) is delivered to the winner through the bot, and the answers \
+            come back here.",
+            c.name
+        ),
+    );
+    reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+        inline_keyboard: owner_keyboard(chan.id, c.id, winner),
+    }));
+    if let Err(err) = ctx.api.send_message(reply).await {
+        error!("[relay owner send] {err}");
+    }
+    if !opened {
+        return;
+    }
+    let reply = SendMessage::new(
+        winner,
+        &format!(
+            "\u{1f4ac} The owner of the {} contest opened a chat with you, through the bot. \
+            Write here to answer.",
+            c.name
+        ),
+    );
+    if let Err(err) = ctx.api.send_message(reply).await {
+        error!("[relay winner send] {err}");
+    }
+}
+
+/// Relays the `message` received in a private chat to the other side of the session of the
+/// sender, if any: an owner writes to the winner of the session with the focus, a winner writes
+/// to the owner. The message is copied, hence the sender is not shown.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `message` - The message received
+///
+/// # Returns
+/// True if the message belongs to a session, hence it has been handled.
+pub async fn deliver(ctx: &Context, message: &Message) -> bool {
+    let sender = match &message.from {
+        Some(from) if from.id == message.chat.get_id() => from.id,
+        _ => return false,
+    };
+    let (session, from_owner) = match find(ctx, "owner = ? AND owner_focus IS TRUE", sender) {
+        Some(session) => (session, true),
+        None => match find(ctx, "winner = ?", sender) {
+            Some(session) => (session, false),
+            None => return false,
+        },
+    };
+    let mut copy = if from_owner {
+        CopyMessage::new(session.winner, sender, message.message_id)
+    } else {
+        CopyMessage::new(session.owner, sender, message.message_id)
+    };
+    if !from_owner {
+        let chan = contests::get(ctx, session.contest).map_or(0, |c| c.chan);
+        copy.reply_markup = Some(ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+            inline_keyboard: owner_keyboard(chan, session.contest, session.winner),
+        }));
+    }
+    if let Err(err) = ctx.api.copy_message(copy).await {
+        error!("[relay copy] {err}");
+        let reply = SendMessage::new(sender, &format!("Message not delivered: {err}"));
+        if let Err(err) = ctx.api.send_message(reply).await {
+            error!("[relay error send] {err}");
+        }
+    }
+    true
+}
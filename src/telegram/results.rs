@@ -0,0 +1,209 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use rusqlite::params;
+use std::fmt::Write;
+use telexide_fork::prelude::*;
+
+use crate::persistence::types::{Contest, ContestResult, DBKey, Rank, User};
+use crate::telegram::{contests, raffle};
+
+/// Stores the `rank` as the official results of the `contest`. The winners (the first in the
+/// chart, or the ones drawn in the raffle mode) are assigned the prize.
+/// The results are written only once: calling this function on a contest that
+/// already has results does nothing.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The finished `Contest`
+/// * `rank` - The ranking computed when the contest finished
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the insertion fails. In this case nothing is stored.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn save(ctx: &Context, contest: &Contest, rank: &[Rank]) -> rusqlite::Result<()> {
+    let winners = raffle::winners(ctx, contest.id);
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let mut conn = map.get().unwrap();
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT OR IGNORE INTO results(contest, rank, user, invites, points, indirect, \
+            adjustment, prize, last_invite) VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )?;
+        for row in rank {
+            let prize = if row.rank <= winners {
+                Some(contest.prize.clone())
+            } else {
+                None
+            };
+            stmt.execute(params![
+                contest.id,
+                row.rank,
+                row.user.id,
+                row.invites,
+                row.points,
+                row.indirect,
+                row.adjustment,
+                prize,
+                row.last_invite
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+/// Returns the official results of the contest with ID `contest`, ordered by rank.
+/// The vector is empty if the contest is not finished or no one participated.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The ID of the contest
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+#[must_use]
+pub fn get(ctx: &Context, contest: i64) -> Vec<ContestResult> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let mut stmt = conn
+        .prepare(
+            "SELECT results.rank, results.invites, COALESCE(results.points, results.invites), \
+            results.indirect, results.adjustment, \
+            COALESCE(results.points, results.invites) + results.indirect + results.adjustment, \
+            results.prize, results.last_invite, users.id, users.first_name, users.last_name, users.username \
+            FROM results INNER JOIN users ON results.user = users.id \
+            WHERE results.contest = ? ORDER BY results.rank ASC",
+        )
+        .unwrap();
+
+    let results = stmt
+        .query_map(params![contest], |row| {
+            Ok(ContestResult {
+                contest,
+                rank: row.get(0)?,
+                invites: row.get(1)?,
+                points: row.get(2)?,
+                indirect: row.get(3)?,
+                adjustment: row.get(4)?,
+                score: row.get(5)?,
+                prize: row.get(6)?,
+                last_invite: row.get(7)?,
+                user: User {
+                    id: row.get(8)?,
+                    first_name: row.get(9)?,
+                    last_name: row.get(10)?,
+                    username: row.get(11)?,
+                },
+            })
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    results
+}
+
+/// Returns the finished contests whose results are visible to `user_id`: the contests
+/// the user participated in, and the contests of the channels the user owns.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `user_id` - The user ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+#[must_use]
+pub fn visible_to(ctx: &Context, user_id: i64) -> Vec<Contest> {
+    let ids = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT id FROM contests WHERE stopped IS TRUE AND (\
+                id IN (SELECT contest FROM results WHERE user = ?) OR \
+                id IN (SELECT contest FROM invitations WHERE source = ?) OR \
+                chan IN (SELECT id FROM channels WHERE registered_by = ?)) \
+                ORDER BY end DESC",
+            )
+            .unwrap();
+        let ids = stmt
+            .query_map(params![user_id, user_id, user_id], |row| row.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect::<Vec<i64>>();
+        ids
+    };
+    ids.into_iter()
+        .filter_map(|id| contests::get(ctx, id))
+        .collect()
+}
+
+/// Returns the chart of the `results`, one row per participant, ready to be escaped and
+/// sent. The score shown is the number of invites plus the manual adjustments, and the
+/// adjusted rows are marked. When two participants have the same score, the row of the one
+/// that reached it first is marked, to make clear how the tie was broken, unless the winners
+/// have been `drawn`: the draw, not the time, decided the order.
+///
+/// # Arguments
+/// * `results` - The official results of a contest, ordered by rank
+/// * `drawn` - Whether the winners have been drawn
+#[must_use]
+pub fn chart(results: &[ContestResult], drawn: bool) -> String {
+    let mut m = String::new();
+    for (i, row) in results.iter().enumerate() {
+        let user = &row.user;
+        if row.rank == 1 {
+            m += "\u{1f947}#1!";
+        } else if row.rank <= 3 {
+            let _ = write!(m, "\u{1f3c6} #{}", row.rank);
+        } else {
+            let _ = write!(m, "#{}", row.rank);
+        }
+
+        let _ = write!(
+            m,
+            " {}{}{} - {}",
+            user.first_name,
+            match &user.last_name {
+                Some(last_name) => format!(" {last_name}"),
+                None => String::new(),
+            },
+            match &user.username {
+                Some(username) => format!(" ({username})"),
+                None => String::new(),
+            },
+            row.score
+        );
+        if row.indirect > 0.0 {
+            let _ = write!(m, " (incl. {} from referrals)", row.indirect);
+        }
+        if row.adjustment != 0 {
+            let _ = write!(m, " (incl. {:+} adjusted)", row.adjustment);
+        }
+        if !drawn
+            && results
+                .get(i + 1)
+                .is_some_and(|next| (next.score - row.score).abs() < f64::EPSILON)
+        {
+            m += " (reached first)";
+        }
+        m += "\n";
+    }
+    m
+}
@@ -0,0 +1,64 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use log::{error, info};
+use std::future::Future;
+use telexide_fork::prelude::*;
+use tokio::time::{sleep, Duration};
+
+use crate::telegram::{challenges, fulfillment, leaderboard, templates};
+
+/// Seconds between two executions of the periodic jobs.
+const PERIOD: u64 = 60;
+
+/// Executes the `job` in its own task, and waits for it: a panic of the job is logged, and
+/// doesn't stop the scheduler.
+///
+/// # Arguments
+/// * `name` - The name of the job, for the log
+/// * `job` - The job to execute
+async fn isolate<F>(name: &str, job: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    if let Err(err) = tokio::spawn(job).await {
+        error!("[scheduler {name}] {err}");
+    }
+}
+
+/// Executes forever, every `PERIOD` seconds, the jobs that don't depend on a Telegram update,
+/// like the reminders to the owners, the recurring contests, the live leaderboards and the
+/// expired challenges. Every job runs in its own task, in this order.
+///
+/// # Arguments
+/// * `ctx` - Telexide context, built from the client
+pub async fn run(ctx: Context) {
+    loop {
+        info!("scheduler begin");
+        let job = ctx.clone();
+        isolate("remind", async move { fulfillment::remind(&job).await }).await;
+        let job = ctx.clone();
+        isolate("templates", async move { templates::run(&job).await }).await;
+        let job = ctx.clone();
+        isolate(
+            "leaderboard",
+            async move { leaderboard::refresh(&job).await },
+        )
+        .await;
+        let job = ctx.clone();
+        isolate("challenges", async move { challenges::sweep(&job).await }).await;
+        info!("scheduler end");
+        sleep(Duration::from_secs(PERIOD)).await;
+    }
+}
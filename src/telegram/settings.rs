@@ -0,0 +1,572 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use log::error;
+use rusqlite::params;
+use std::fmt::Write;
+use telexide_fork::{
+    api::types::SendMessage,
+    model::{InlineKeyboardMarkup, ParseMode, ReplyMarkup},
+    prelude::*,
+};
+
+use crate::persistence::types::{Channel, Contest, DBKey};
+use crate::telegram::messages::{callback_button, escape_markdown};
+use crate::telegram::{audit, eligibility, milestones, networks, sponsors};
+
+/// An optional feature of a contest, that can assume one of the `choices`.
+pub struct Setting {
+    /// The key stored in the database
+    pub key: &'static str,
+    /// The name shown to the owner
+    pub label: &'static str,
+    /// The possible values and their description. The first one is the default.
+    /// Numeric settings have no choices: their value is written by the owner, and 0 (the
+    /// default) means unlimited.
+    pub choices: &'static [(&'static str, &'static str)],
+}
+
+/// Human-verification challenge sent to the invitees before crediting the invitation.
+pub const CHALLENGE: &str = "challenge";
+
+/// Who gets the credit when several participants invite the same user.
+pub const ATTRIBUTION: &str = "attribution";
+
+/// Exclude the owner, the admins of the channel and the bot operator from the participants.
+pub const EXCLUDE_ADMINS: &str = "exclude_admins";
+/// Allow only the users that were members of the channel before the contest started.
+pub const PRIOR_MEMBERSHIP: &str = "prior_membership";
+/// Allow only the users with a username.
+pub const REQUIRE_USERNAME: &str = "require_username";
+/// Allow only the users in the allowlist of the contest.
+pub const ALLOWLIST: &str = "allowlist";
+
+/// Invitees that are bots don't count.
+pub const INVITEE_NOT_BOT: &str = "invitee_not_bot";
+/// Invitees that have been members of the channel before don't count.
+pub const INVITEE_NEW: &str = "invitee_new";
+/// Invitees without a username don't count.
+pub const INVITEE_USERNAME: &str = "invitee_username";
+/// Only Telegram Premium invitees count.
+pub const INVITEE_PREMIUM: &str = "invitee_premium";
+
+/// Maximum number of counted invites per participant.
+pub const MAX_INVITES: &str = "max_invites";
+/// Maximum number of participants: the others are put on a waitlist.
+pub const MAX_PARTICIPANTS: &str = "max_participants";
+/// Maximum number of invites per participant per day (UTC).
+pub const MAX_DAILY_INVITES: &str = "max_daily_invites";
+
+/// How the winners are chosen: by ranking, drawn at random among the invites (raffle) or
+/// among the members that pressed Participate (giveaway), or by the messages written in the
+/// group (activity).
+pub const MODE: &str = "mode";
+/// Minimum number of characters of a message counted in the activity mode.
+pub const ACTIVITY_MIN_LENGTH: &str = "activity_min_length";
+/// Seconds between two counted messages of a member in the activity mode.
+pub const ACTIVITY_COOLDOWN: &str = "activity_cooldown";
+
+/// Number of winners drawn in the raffle and giveaway modes.
+pub const WINNERS: &str = "winners";
+
+/// Number of levels of the referral graph credited to a participant: 1 counts only the direct
+/// invitations.
+pub const REFERRAL_LEVELS: &str = "referral_levels";
+/// Weight of every level of the referral graph, relative to the previous one.
+pub const REFERRAL_WEIGHT: &str = "referral_weight";
+
+/// Days after which the owner is reminded of the prizes not delivered yet.
+pub const FULFILLMENT_REMINDER: &str = "fulfillment_reminder";
+
+/// Number of participants shown in the live leaderboard posted in the channel, or `off`.
+pub const LEADERBOARD: &str = "leaderboard";
+/// Minutes between two updates of the live leaderboard.
+pub const LEADERBOARD_INTERVAL: &str = "leaderboard_interval";
+
+/// The settings that can change while the contest runs: they don't change the rules
+/// announced when it started.
+const LIVE: &[&str] = &[FULFILLMENT_REMINDER, LEADERBOARD, LEADERBOARD_INTERVAL];
+
+/// Choices of the settings that can only be enabled or disabled.
+const TOGGLE: &[(&str, &str)] = &[("off", "disabled"), ("on", "enabled")];
+
+/// All the settings of a contest, in the order they are shown to the owner.
+pub const SETTINGS: &[Setting] = &[
+    Setting {
+        key: CHALLENGE,
+        label: "Human verification",
+        choices: &[
+            ("none", "no challenge"),
+            ("arithmetic", "write the result of a sum"),
+            ("emoji", "pick the right emoji in a grid"),
+            ("timed", "pick the result of a multiplication in 15 seconds"),
+        ],
+    },
+    Setting {
+        key: ATTRIBUTION,
+        label: "Attribution",
+        choices: &[
+            (
+                "first",
+                "the first participant who invited the user gets the credit",
+            ),
+            (
+                "last",
+                "the last participant who invited the user gets the credit",
+            ),
+            (
+                "split",
+                "the credit is split among the participants who invited the user",
+            ),
+        ],
+    },
+    Setting {
+        key: EXCLUDE_ADMINS,
+        label: "Exclude owner and admins",
+        choices: TOGGLE,
+    },
+    Setting {
+        key: PRIOR_MEMBERSHIP,
+        label: "Only members before the start",
+        choices: TOGGLE,
+    },
+    Setting {
+        key: REQUIRE_USERNAME,
+        label: "Require username",
+        choices: TOGGLE,
+    },
+    Setting {
+        key: ALLOWLIST,
+        label: "Only allowlisted users",
+        choices: TOGGLE,
+    },
+    Setting {
+        key: INVITEE_NOT_BOT,
+        label: "Invitees: no bots",
+        choices: TOGGLE,
+    },
+    Setting {
+        key: INVITEE_NEW,
+        label: "Invitees: never members before",
+        choices: TOGGLE,
+    },
+    Setting {
+        key: INVITEE_USERNAME,
+        label: "Invitees: with username",
+        choices: TOGGLE,
+    },
+    Setting {
+        key: INVITEE_PREMIUM,
+        label: "Invitees: Premium only",
+        choices: TOGGLE,
+    },
+    Setting {
+        key: MODE,
+        label: "Mode",
+        choices: &[
+            ("ranking", "who invites more friends wins"),
+            (
+                "raffle",
+                "every invite is a raffle ticket, the winners are drawn at random",
+            ),
+            (
+                "giveaway",
+                "the members press Participate, the winners are drawn at random",
+            ),
+            (
+                "activity",
+                "who writes more messages in the group wins, without invites",
+            ),
+        ],
+    },
+    Setting {
+        key: WINNERS,
+        label: "Winners drawn",
+        choices: &[
+            ("1", "one winner is drawn"),
+            ("2", "two winners are drawn"),
+            ("3", "three winners are drawn"),
+            ("5", "five winners are drawn"),
+            ("10", "ten winners are drawn"),
+        ],
+    },
+    Setting {
+        key: ACTIVITY_MIN_LENGTH,
+        label: "Activity: min length",
+        choices: &[
+            ("10", "messages of at least 10 characters count"),
+            ("20", "messages of at least 20 characters count"),
+            ("50", "messages of at least 50 characters count"),
+            ("1", "every message counts"),
+        ],
+    },
+    Setting {
+        key: ACTIVITY_COOLDOWN,
+        label: "Activity: cooldown",
+        choices: &[
+            ("60", "one counted message per minute"),
+            ("300", "one counted message every 5 minutes"),
+            ("10", "one counted message every 10 seconds"),
+            ("0", "no cooldown"),
+        ],
+    },
+    Setting {
+        key: REFERRAL_LEVELS,
+        label: "Referral levels",
+        choices: &[
+            ("1", "only the direct invitations count"),
+            ("2", "the invitations of the invitees count too"),
+            ("3", "up to the invitees of the invitees of the invitees"),
+            ("5", "up to 5 levels"),
+        ],
+    },
+    Setting {
+        key: REFERRAL_WEIGHT,
+        label: "Referral weight",
+        choices: &[
+            ("0.5", "every level is worth half of the previous one"),
+            ("0.25", "every level is worth a quarter of the previous one"),
+            ("0.1", "every level is worth a tenth of the previous one"),
+            ("1", "every level is worth as much as a direct invitation"),
+        ],
+    },
+    Setting {
+        key: FULFILLMENT_REMINDER,
+        label: "Prize reminder",
+        choices: &[
+            ("7", "after 7 days pending"),
+            ("3", "after 3 days pending"),
+            ("14", "after 14 days pending"),
+            ("off", "never"),
+        ],
+    },
+    Setting {
+        key: LEADERBOARD,
+        label: "Live leaderboard",
+        choices: &[
+            ("off", "no leaderboard in the channel"),
+            ("10", "the top 10, updated in place"),
+            ("3", "the top 3, updated in place"),
+            ("5", "the top 5, updated in place"),
+            ("20", "the top 20, updated in place"),
+        ],
+    },
+    Setting {
+        key: LEADERBOARD_INTERVAL,
+        label: "Leaderboard refresh",
+        choices: &[
+            ("10", "every 10 minutes"),
+            ("30", "every 30 minutes"),
+            ("60", "every hour"),
+            ("5", "every 5 minutes"),
+            ("1", "every minute"),
+        ],
+    },
+    Setting {
+        key: MAX_INVITES,
+        label: "Max invites per participant",
+        choices: &[],
+    },
+    Setting {
+        key: MAX_PARTICIPANTS,
+        label: "Max participants",
+        choices: &[],
+    },
+    Setting {
+        key: MAX_DAILY_INVITES,
+        label: "Max invites per day",
+        choices: &[],
+    },
+];
+
+/// Returns true if the toggle setting `key` of the `contest` is enabled.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `key` - The setting key, one of the toggle `SETTINGS`
+#[must_use]
+pub fn enabled(ctx: &Context, contest: i64, key: &str) -> bool {
+    get(ctx, contest, key) == "on"
+}
+
+/// Checks that the setting `key` of the contest `c` can change: the settings of a finished
+/// contest never change, and the rules (mode, winners, attribution, challenge, caps, filters,
+/// ...) can't change once the contest started.
+///
+/// # Arguments
+/// * `c` - The contest
+/// * `key` - The setting key, one of the `SETTINGS`
+///
+/// # Errors
+/// Returns the reason, ready to be shown to the owner.
+pub fn changeable(c: &Contest, key: &str) -> Result<(), String> {
+    if c.started_at.is_some() && !c.stopped && LIVE.contains(&key) {
+        Ok(())
+    } else {
+        rules_changeable(c)
+    }
+}
+
+/// Checks that the rules of the contest `c` outside of the settings (points model, milestones,
+/// invitee reward) can change: they can't once the contest started, like the settings.
+///
+/// # Arguments
+/// * `c` - The contest
+///
+/// # Errors
+/// Returns the reason, ready to be shown to the owner.
+pub fn rules_changeable(c: &Contest) -> Result<(), String> {
+    if c.stopped {
+        Err("The contest is finished, its settings can't change.".to_string())
+    } else if c.started_at.is_some() {
+        Err("The rules can't change once the contest started.".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the setting with the specified `key`.
+///
+/// # Panics
+/// Panics if the key is not among the `SETTINGS`.
+#[must_use]
+pub fn definition(key: &str) -> &'static Setting {
+    SETTINGS.iter().find(|s| s.key == key).expect("setting")
+}
+
+/// Returns the value of the setting `key` of the `contest`, or its default value if it has
+/// never been set.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `key` - The setting key, one of the `SETTINGS`
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the key is unknown.
+#[must_use]
+pub fn get(ctx: &Context, contest: i64, key: &str) -> String {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    conn.query_row(
+        "SELECT value FROM contest_settings WHERE contest = ? AND key = ?",
+        params![contest, key],
+        |row| row.get(0),
+    )
+    .unwrap_or_else(|_| {
+        definition(key)
+            .choices
+            .first()
+            .map_or_else(|| "0".to_string(), |(value, _)| (*value).to_string())
+    })
+}
+
+/// Returns the value of the numeric setting `key` of the `contest`: 0 means unlimited.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `key` - The setting key, one of the numeric `SETTINGS`
+#[must_use]
+pub fn number(ctx: &Context, contest: i64, key: &str) -> i64 {
+    get(ctx, contest, key).parse().unwrap_or(0)
+}
+
+/// Sets the `value` of the setting `key` of the `contest`. The change is added to the
+/// audit log.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `key` - The setting key, one of the `SETTINGS`
+/// * `value` - The new value
+/// * `actor` - The user changing the setting
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn set(
+    ctx: &Context,
+    contest: i64,
+    key: &str,
+    value: &str,
+    actor: i64,
+) -> rusqlite::Result<()> {
+    {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.execute(
+            "INSERT INTO contest_settings(contest, key, value) VALUES(?, ?, ?) \
+            ON CONFLICT(contest, key) DO UPDATE SET value = excluded.value",
+            params![contest, key, value],
+        )?;
+    }
+    audit::log(
+        ctx,
+        contest,
+        actor,
+        &format!("set {key}={value}"),
+        None,
+        None,
+    )
+}
+
+/// Sets the numeric setting `key` of the `contest` to the value written by the owner.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `key` - The setting key, one of the numeric `SETTINGS`
+/// * `text` - The value written by the owner
+/// * `actor` - The user changing the setting
+///
+/// # Errors
+/// Returns the reason of the failure, ready to be shown to the owner.
+pub fn set_number(
+    ctx: &Context,
+    contest: i64,
+    key: &str,
+    text: &str,
+    actor: i64,
+) -> Result<(), String> {
+    let value: u32 = text
+        .trim()
+        .parse()
+        .map_err(|_| "the value must be a non-negative number".to_string())?;
+    set(ctx, contest, key, &value.to_string(), actor).map_err(|err| {
+        error!("[set number] {err}");
+        err.to_string()
+    })
+}
+
+/// Changes the setting `key` of the `contest` to the next possible value.
+/// Numeric settings don't change: their value is written by the owner.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `key` - The setting key, one of the `SETTINGS`
+/// * `actor` - The user changing the setting
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the key is unknown.
+pub fn cycle(ctx: &Context, contest: i64, key: &str, actor: i64) -> rusqlite::Result<()> {
+    let choices = definition(key).choices;
+    if choices.is_empty() {
+        return Ok(());
+    }
+    let current = get(ctx, contest, key);
+    let next = choices
+        .iter()
+        .position(|(value, _)| *value == current)
+        .map_or(0, |i| (i + 1) % choices.len());
+    set(ctx, contest, key, choices[next].0, actor)
+}
+
+/// Sends to `chat_id` the settings of the `contest`, with a button per setting to change it.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chat_id` - The chat ID
+/// * `chan` - The channel of the contest
+/// * `contest` - The contest
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn display(ctx: &Context, chat_id: i64, chan: &Channel, contest: &Contest) {
+    let mut text = format!("Settings of {}\n\n", contest.name);
+    let mut inline_keyboard = vec![];
+    for setting in SETTINGS {
+        let mut value = get(ctx, contest.id, setting.key);
+        if setting.choices.is_empty() && value == "0" {
+            value = "unlimited".to_string();
+        }
+        let description = setting
+            .choices
+            .iter()
+            .find(|(choice, _)| *choice == value)
+            .map_or("write the value", |(_, description)| description);
+        let _ = writeln!(text, "{}: {value} ({description})", setting.label);
+        inline_keyboard.push(vec![callback_button(
+            &format!("{}: {value}", setting.label),
+            &format!("set {} {} {}", chan.id, contest.id, setting.key),
+        )]);
+    }
+    let allowlist = eligibility::allowlist(ctx, contest.id);
+    let _ = writeln!(text, "Allowlist: {} users", allowlist.len());
+    let milestones = milestones::get_all(ctx, contest.id);
+    let _ = writeln!(text, "Milestones: {}", milestones.len());
+    inline_keyboard.push(vec![
+        callback_button(
+            &format!("\u{1f4dd} Edit allowlist ({})", allowlist.len()),
+            &format!("allowlist {} {}", chan.id, contest.id),
+        ),
+        callback_button(
+            &format!("\u{1f3c5} Milestones ({})", milestones.len()),
+            &format!("milestones {} {}", chan.id, contest.id),
+        ),
+    ]);
+    let networked = networks::linked(ctx, contest.id);
+    let _ = writeln!(text, "Linked channels: {}", networked.len());
+    inline_keyboard.push(vec![
+        callback_button(
+            "\u{1f9ee} Points",
+            &format!("points {} {}", chan.id, contest.id),
+        ),
+        callback_button(
+            &format!("\u{1f4e1} Channels ({})", networked.len() + 1),
+            &format!("network {} {}", chan.id, contest.id),
+        ),
+    ]);
+    let partners = sponsors::get_all(ctx, contest.id);
+    let _ = writeln!(text, "Partners: {}", partners.len());
+    inline_keyboard.push(vec![callback_button(
+        &format!("\u{1f91d} Partners ({})", partners.len()),
+        &format!("sponsors {} {}", chan.id, contest.id),
+    )]);
+    inline_keyboard.push(vec![
+        callback_button(
+            "\u{1f381} Prize codes",
+            &format!("prizes {} {}", chan.id, contest.id),
+        ),
+        callback_button(
+            "\u{1f91d} Invitee reward",
+            &format!("invitee_reward {} {}", chan.id, contest.id),
+        ),
+    ]);
+    text += "\nPress a button to change the setting.";
+    inline_keyboard.push(vec![callback_button(
+        "\u{1f519} Manage",
+        &format!("manage {}", chan.id),
+    )]);
+
+    let mut reply = SendMessage::new(chat_id, &escape_markdown(&text, None));
+    reply.set_parse_mode(&ParseMode::MarkdownV2);
+    reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+        inline_keyboard,
+    }));
+    let res = ctx.api.send_message(reply).await;
+    if res.is_err() {
+        let err = res.err().unwrap();
+        error!("[settings send] {err}");
+    }
+}
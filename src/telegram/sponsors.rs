@@ -0,0 +1,301 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use log::error;
+use rusqlite::params;
+use std::fmt::Write;
+use telexide_fork::{
+    api::types::{CreateChatInviteLink, GetChat, GetChatMember, SendMessage},
+    model::{Chat, ChatMember, InlineKeyboardButton, InlineKeyboardMarkup, ParseMode, ReplyMarkup},
+    prelude::*,
+};
+
+use crate::persistence::types::{Channel, Contest, DBKey, Sponsor};
+use crate::telegram::messages::{callback_button, escape_markdown};
+use crate::telegram::{audit, channels};
+
+/// Returns the partner chats of the `contest`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+#[must_use]
+pub fn get_all(ctx: &Context, contest: i64) -> Vec<Sponsor> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let mut stmt = conn
+        .prepare("SELECT chat, name, link FROM sponsors WHERE contest = ? ORDER BY name")
+        .unwrap();
+    let sponsors = stmt
+        .query_map(params![contest], |row| {
+            Ok(Sponsor {
+                chat: row.get(0)?,
+                name: row.get(1)?,
+                link: row.get(2)?,
+            })
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    sponsors
+}
+
+/// Adds the partner chat written by the owner (its ID) to the `contest`. The bot must be an
+/// admin of the chat, otherwise the membership of the invitees can't be checked. The partners
+/// can't change once the contest started. The action is added to the audit log.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+/// * `text` - The text written by the owner
+/// * `actor` - The owner adding the partner chat
+///
+/// # Errors
+/// Returns the reason of the failure, ready to be shown to the owner.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn add(ctx: &Context, c: &Contest, text: &str, actor: i64) -> Result<Sponsor, String> {
+    if c.started_at.is_some() {
+        return Err("the partners can't change once the contest started".to_string());
+    }
+    let contest = c.id;
+    let chat_id: i64 = text
+        .trim()
+        .parse()
+        .map_err(|_| format!("{} is not a valid chat ID", text.trim()))?;
+    let chat = ctx
+        .api
+        .get_chat(GetChat { chat_id })
+        .await
+        .map_err(|err| format!("the bot can't see the chat ({err})"))?;
+    let (invite_link, username, name) = match chat {
+        Chat::Channel(c) => (c.invite_link, c.username, c.title),
+        Chat::Group(c) => (c.invite_link, c.username, c.title),
+        Chat::SuperGroup(c) => (c.invite_link, c.username, c.title),
+        Chat::Private(_) => return Err("a partner must be a channel or a group".to_string()),
+    };
+    let me = ctx.api.get_me().await.map_err(|err| err.to_string())?;
+    let member = ctx
+        .api
+        .get_chat_member(GetChatMember {
+            chat_id,
+            user_id: me.id,
+        })
+        .await
+        .map_err(|err| err.to_string())?;
+    if !matches!(member, ChatMember::Administrator(_)) {
+        return Err("the bot must be an admin of the partner chat".to_string());
+    }
+    let link = if let Some(link) = invite_link {
+        link
+    } else if let Some(username) = username {
+        format!("https://t.me/{username}")
+    } else {
+        ctx.api
+            .create_chat_invite_link(CreateChatInviteLink {
+                chat_id,
+                expire_date: None,
+                member_limit: None,
+            })
+            .await
+            .map_err(|err| format!("the bot can't create an invite link ({err})"))?
+            .invite_link
+    };
+    let sponsor = Sponsor {
+        chat: chat_id,
+        name,
+        link,
+    };
+    {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO sponsors(contest, chat, name, link) VALUES(?, ?, ?, ?)",
+            params![contest, sponsor.chat, sponsor.name, sponsor.link],
+        )
+        .map_err(|err| err.to_string())?;
+    }
+    audit::log(
+        ctx,
+        contest,
+        actor,
+        "add sponsor",
+        None,
+        Some(&sponsor.name),
+    )
+    .map_err(|err| err.to_string())?;
+    Ok(sponsor)
+}
+
+/// Removes the partner chat `chat` from the contest `c`. The partners can't change once the
+/// contest started. The action is added to the audit log.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+/// * `chat` - The partner chat ID
+/// * `actor` - The owner removing the partner chat
+///
+/// # Errors
+/// Returns the reason of the failure, ready to be shown to the owner.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn remove(ctx: &Context, c: &Contest, chat: i64, actor: i64) -> Result<(), String> {
+    if c.started_at.is_some() {
+        return Err("The partners can't change once the contest started.".to_string());
+    }
+    let contest = c.id;
+    let name: Option<String> = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.query_row(
+            "DELETE FROM sponsors WHERE contest = ? AND chat = ? RETURNING name",
+            params![contest, chat],
+            |row| row.get(0),
+        )
+        .ok()
+    };
+    match name {
+        Some(name) => {
+            audit::log(ctx, contest, actor, "remove sponsor", None, Some(&name)).map_err(|err| {
+                error!("[remove sponsor] {err}");
+                format!("Error: {err}")
+            })
+        }
+        None => Ok(()),
+    }
+}
+
+/// Returns the partner chats of the `contest` that the `user` hasn't joined.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `user` - The invitee
+pub async fn missing(ctx: &Context, contest: i64, user: i64) -> Vec<Sponsor> {
+    let mut missing = vec![];
+    for sponsor in get_all(ctx, contest) {
+        if !channels::is_member(ctx, sponsor.chat, user).await {
+            missing.push(sponsor);
+        }
+    }
+    missing
+}
+
+/// Returns a button per `missing` partner chat, to join it, and the button to check again
+/// the pending `invitation`.
+///
+/// # Arguments
+/// * `missing` - The partner chats the invitee hasn't joined
+/// * `invitation` - The pending invitation
+#[must_use]
+pub fn keyboard(missing: &[Sponsor], invitation: i64) -> Vec<Vec<InlineKeyboardButton>> {
+    missing
+        .iter()
+        .map(|sponsor| {
+            vec![InlineKeyboardButton {
+                text: format!("Join {}", sponsor.name),
+                callback_data: None,
+                callback_game: None,
+                login_url: None,
+                pay: None,
+                switch_inline_query: None,
+                switch_inline_query_current_chat: None,
+                url: Some(sponsor.link.clone()),
+            }]
+        })
+        .chain(std::iter::once(vec![callback_button(
+            "\u{1f504} I joined, check again",
+            &format!("sponsors_check {invitation}"),
+        )]))
+        .collect()
+}
+
+/// Returns the description of the partner chats of the contest `c`, shown in the
+/// announcement, or `None` if the contest has no partners.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `c` - The contest
+#[must_use]
+pub fn rule(ctx: &Context, c: &Contest) -> Option<String> {
+    let sponsors = get_all(ctx, c.id);
+    if sponsors.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "Your friends must also join our partners, until the contest finishes: {}.",
+        sponsors
+            .iter()
+            .map(|sponsor| sponsor.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))
+}
+
+/// Sends to `chat_id` the partner chats of the `contest`, and the buttons to change them.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chat_id` - The chat ID
+/// * `chan` - The channel of the contest
+/// * `contest` - The contest
+pub async fn display(ctx: &Context, chat_id: i64, chan: &Channel, contest: &Contest) {
+    let sponsors = get_all(ctx, contest.id);
+    let mut text = format!(
+        "Partners of {}\n\nThe invitees must join every partner chat, besides the channel, for \
+        their invitation to count. The memberships are checked again when the contest \
+        finishes.\n\n",
+        contest.name
+    );
+    if sponsors.is_empty() {
+        text += "No partners.\n";
+    }
+    let mut inline_keyboard = vec![];
+    for sponsor in &sponsors {
+        let _ = writeln!(text, "{} ({})", sponsor.name, sponsor.link);
+        inline_keyboard.push(vec![callback_button(
+            &format!("\u{274c} Remove {}", sponsor.name),
+            &format!("sponsor_remove {} {} {}", chan.id, contest.id, sponsor.chat),
+        )]);
+    }
+    inline_keyboard.push(vec![
+        callback_button(
+            "\u{2795} Add partner",
+            &format!("sponsor_add {} {}", chan.id, contest.id),
+        ),
+        callback_button(
+            "\u{2699}\u{fe0f} Settings",
+            &format!("settings_contest {} {}", chan.id, contest.id),
+        ),
+    ]);
+
+    let mut reply = SendMessage::new(chat_id, &escape_markdown(&text, None));
+    reply.set_parse_mode(&ParseMode::MarkdownV2);
+    reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+        inline_keyboard,
+    }));
+    let res = ctx.api.send_message(reply).await;
+    if let Err(err) = res {
+        error!("[sponsors send] {err}");
+    }
+}
@@ -0,0 +1,870 @@
+// Copyright 2021 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Timelike, Utc};
+use log::error;
+use rusqlite::{params, Transaction};
+use std::fmt::Write;
+use telexide_fork::{
+    api::types::SendMessage,
+    model::{InlineKeyboardMarkup, ParseMode, ReplyMarkup},
+    prelude::*,
+};
+
+use crate::persistence::types::{Channel, Contest, DBKey, Template};
+use crate::telegram::messages::{callback_button, escape_markdown};
+use crate::telegram::{audit, channels, contests};
+
+/// The tables containing the rules of a contest, copied to its clones, with their columns
+/// besides `contest`. The stocks of codes are not copied: every code is given once.
+const RULES: &[(&str, &str)] = &[
+    ("contest_settings", "key, value"),
+    ("allowlists", "entry"),
+    ("milestones", "threshold, reward"),
+    (
+        "point_rules",
+        "base, member_bonus, active_bonus, leave_penalty",
+    ),
+    ("invitee_rewards", "message"),
+    ("contest_channels", "chan"),
+    ("sponsors", "chat, name, link"),
+];
+
+/// Duration of the drafts cloned from a contest that never started.
+const DRAFT_DAYS: i64 = 7;
+
+/// A recurrence: the minutes, hours, days of the month, months and days of the week (0 is
+/// Sunday) of a cron expression, in UTC.
+struct Schedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    /// The day of the month is `*`
+    any_day: bool,
+    /// The day of the week is `*`
+    any_weekday: bool,
+}
+
+/// Parses a field of a cron expression: `*`, a value, a range (`1-5`), a step (`*/15`), or a
+/// comma-separated list of them.
+///
+/// # Arguments
+/// * `text` - The field
+/// * `min` - The minimum value of the field
+/// * `max` - The maximum value of the field
+///
+/// # Errors
+/// Returns the reason if the field is malformed or out of range.
+fn field(text: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let value = |text: &str| {
+        text.parse::<u32>()
+            .map_err(|_| format!("{text} is not a number"))
+    };
+    let mut set = vec![false; max as usize + 1];
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, value(step)?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(format!("{part}: the step can't be 0"));
+        }
+        let (from, to) = if range == "*" {
+            (min, max)
+        } else if let Some((from, to)) = range.split_once('-') {
+            (value(from)?, value(to)?)
+        } else {
+            let from = value(range)?;
+            (from, if step > 1 { max } else { from })
+        };
+        if from < min || to > max || from > to {
+            return Err(format!("{part} is out of the range {min}-{max}"));
+        }
+        for v in (from..=to).step_by(step as usize) {
+            set[v as usize] = true;
+        }
+    }
+    Ok(set)
+}
+
+impl Schedule {
+    /// Parses the `schedule` written by the owner: `weekly` (every Monday at 00:00 UTC),
+    /// `monthly` (every first of the month at 00:00 UTC), or a cron expression with 5 fields
+    /// (minute, hour, day of the month, month, day of the week).
+    ///
+    /// # Errors
+    /// Returns the reason if the schedule is malformed.
+    fn parse(schedule: &str) -> Result<Self, String> {
+        let expression = match schedule.trim().to_lowercase().as_str() {
+            "weekly" => "0 0 * * 1".to_string(),
+            "monthly" => "0 0 1 * *".to_string(),
+            other => other.to_string(),
+        };
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(
+                "the schedule is weekly, monthly, or a cron expression with 5 fields \
+                (minute hour day month weekday)"
+                    .to_string(),
+            );
+        }
+        let mut weekdays = field(fields[4], 0, 7)?;
+        // Both 0 and 7 are Sunday
+        weekdays[0] |= weekdays[7];
+        weekdays.truncate(7);
+        Ok(Self {
+            minutes: field(fields[0], 0, 59)?,
+            hours: field(fields[1], 0, 23)?,
+            days: field(fields[2], 1, 31)?,
+            months: field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    /// Returns true if the schedule comes on the `day`. Like cron, when both the day of the
+    /// month and the day of the week are restricted, either of them is enough.
+    fn matches(&self, day: NaiveDate) -> bool {
+        let in_month = self.days[day.day() as usize];
+        let in_week = self.weekdays[day.weekday().num_days_from_sunday() as usize];
+        self.months[day.month() as usize]
+            && match (self.any_day, self.any_weekday) {
+                (true, true) => true,
+                (true, false) => in_week,
+                (false, true) => in_month,
+                (false, false) => in_month || in_week,
+            }
+    }
+
+    /// Returns the first time the schedule comes after `after`, if it comes within 8 years
+    /// (e.g. February 30 never comes).
+    fn next(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let mut day = start.date_naive();
+        for _ in 0..366 * 8 {
+            if self.matches(day) {
+                let (first_hour, first_minute) = if day == start.date_naive() {
+                    (start.hour(), start.minute())
+                } else {
+                    (0, 0)
+                };
+                for hour in (first_hour..24).filter(|hour| self.hours[*hour as usize]) {
+                    let from = if hour == first_hour { first_minute } else { 0 };
+                    if let Some(minute) = (from..60).find(|minute| self.minutes[*minute as usize]) {
+                        return Some(day.and_hms_opt(hour, minute, 0)?.and_utc());
+                    }
+                }
+            }
+            day = day.succ_opt()?;
+        }
+        None
+    }
+}
+
+/// Parses the duration of the instances written by the owner: a number of days (`7d`) or
+/// hours (`12h`).
+///
+/// # Errors
+/// Returns the reason if the duration is malformed.
+fn duration(text: &str) -> Result<i64, String> {
+    let text = text.trim().to_lowercase();
+    let seconds = if let Some(days) = text.strip_suffix('d') {
+        days.trim().parse::<i64>().map(|days| days * 86400)
+    } else if let Some(hours) = text.strip_suffix('h') {
+        hours.trim().parse::<i64>().map(|hours| hours * 3600)
+    } else {
+        return Err(format!(
+            "{text}: the duration is a number of days (7d) or hours (12h)"
+        ));
+    }
+    .map_err(|err| format!("{text}: {err}"))?;
+    if seconds <= 0 {
+        return Err("the duration must be positive".to_string());
+    }
+    Ok(seconds)
+}
+
+/// Returns the name of the instance `n` of a recurring contest, started at `start`, replacing
+/// the placeholders of the `pattern`.
+///
+/// # Arguments
+/// * `pattern` - The name of the template
+/// * `n` - The number of the instance, starting from 1
+/// * `start` - When the instance starts
+fn render(pattern: &str, n: i64, start: DateTime<Utc>) -> String {
+    pattern
+        .replace("{n}", &n.to_string())
+        .replace("{date}", &start.format("%Y-%m-%d").to_string())
+        .replace("{week}", &start.iso_week().week().to_string())
+        .replace("{month}", &start.format("%B %Y").to_string())
+}
+
+/// Returns the active templates whose rules come from the `contest`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+///
+/// # Panics
+/// Panics if the connection to the DB fails, or if the returned data is corrupt.
+#[must_use]
+pub fn get_all(ctx: &Context, contest: i64) -> Vec<Template> {
+    query(
+        ctx,
+        "WHERE contest = ? AND active ORDER BY next_run",
+        params![contest],
+    )
+}
+
+/// Returns the active templates whose next instance is due.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+fn due(ctx: &Context) -> Vec<Template> {
+    query(ctx, "WHERE active AND next_run <= ?", params![Utc::now()])
+}
+
+/// Returns the templates satisfying the `filter`.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `filter` - The WHERE (and ORDER BY) clause
+/// * `args` - The arguments of the filter
+fn query(ctx: &Context, filter: &str, args: &[&dyn rusqlite::ToSql]) -> Vec<Template> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let conn = map.get().unwrap();
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT id, contest, name, prize, duration, schedule, next_run, runs \
+            FROM contest_templates {filter}"
+        ))
+        .unwrap();
+    let templates = stmt
+        .query_map(args, |row| {
+            Ok(Template {
+                id: row.get(0)?,
+                contest: row.get(1)?,
+                name: row.get(2)?,
+                prize: row.get(3)?,
+                duration: row.get(4)?,
+                schedule: row.get(5)?,
+                next_run: row.get(6)?,
+                runs: row.get(7)?,
+            })
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    templates
+}
+
+/// Adds a template, written by the owner, that repeats the `contest` with its rules. The text
+/// has four lines: the name (with the optional placeholders `{n}`, `{date}`, `{week}`,
+/// `{month}`), the prize, the duration of every instance, and the schedule. The action is
+/// added to the audit log.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest whose rules are repeated
+/// * `text` - The text written by the owner
+/// * `actor` - The owner
+///
+/// # Errors
+/// Returns the reason if the text is malformed or the insertion fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn add(ctx: &Context, contest: i64, text: &str, actor: i64) -> Result<Template, String> {
+    let rows = text
+        .lines()
+        .map(str::trim)
+        .filter(|row| !row.is_empty())
+        .collect::<Vec<_>>();
+    if rows.len() != 4 {
+        return Err(format!(
+            "expected 4 lines (name, prize, duration, schedule), got {}",
+            rows.len()
+        ));
+    }
+    let duration = duration(rows[2])?;
+    let next_run = Schedule::parse(rows[3])?
+        .next(Utc::now())
+        .ok_or_else(|| format!("the schedule {} never comes", rows[3]))?;
+    let id = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.execute(
+            "INSERT INTO contest_templates(contest, name, prize, duration, schedule, next_run) \
+            VALUES(?, ?, ?, ?, ?, ?)",
+            params![contest, rows[0], rows[1], duration, rows[3], next_run],
+        )
+        .map_err(|err| err.to_string())?;
+        conn.last_insert_rowid()
+    };
+    audit::log(ctx, contest, actor, "add template", Some(id), Some(rows[0]))
+        .map_err(|err| err.to_string())?;
+    Ok(Template {
+        id,
+        contest,
+        name: rows[0].to_string(),
+        prize: rows[1].to_string(),
+        duration,
+        schedule: rows[3].to_string(),
+        next_run,
+        runs: 0,
+    })
+}
+
+/// Removes the `template` of the `contest`: no more instances are created, the running ones
+/// are finished anyway. The action is added to the audit log.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `contest` - The contest ID
+/// * `template` - The template ID
+/// * `actor` - The owner
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the update fails.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn remove(ctx: &Context, contest: i64, template: i64, actor: i64) -> rusqlite::Result<()> {
+    let removed = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        conn.execute(
+            "UPDATE contest_templates SET active = FALSE WHERE id = ? AND contest = ? AND active",
+            params![template, contest],
+        )?
+    };
+    if removed > 0 {
+        audit::log(ctx, contest, actor, "remove template", Some(template), None)?;
+    }
+    Ok(())
+}
+
+/// Copies the `source` contest, with its rules, as a new contest of the same channel that
+/// hasn't started yet. A number is added to the `name` if another contest of the channel
+/// has it.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `source` - The contest to copy
+/// * `name` - The name of the copy
+/// * `prize` - The prize of the copy
+/// * `end` - The end date of the copy
+/// * `template` - The template the copy is an instance of, if any
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the copy fails: nothing is copied.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+fn copy(
+    ctx: &Context,
+    source: &Contest,
+    name: &str,
+    prize: &str,
+    end: DateTime<Utc>,
+    template: Option<i64>,
+) -> rusqlite::Result<Contest> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let mut conn = map.get().unwrap();
+    let tx = conn.transaction()?;
+    let copy = copy_tx(&tx, source, name, prize, end, template)?;
+    tx.commit()?;
+    Ok(copy)
+}
+
+/// Same as `copy`, as part of the transaction `tx` of the caller.
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the copy fails.
+fn copy_tx(
+    tx: &Transaction,
+    source: &Contest,
+    name: &str,
+    prize: &str,
+    end: DateTime<Utc>,
+    template: Option<i64>,
+) -> rusqlite::Result<Contest> {
+    let mut unique = name.to_string();
+    let mut n = 1;
+    while tx.query_row(
+        "SELECT EXISTS(SELECT 1 FROM contests WHERE chan = ? AND name = ?)",
+        params![source.chan, unique],
+        |row| row.get::<_, bool>(0),
+    )? {
+        n += 1;
+        unique = format!("{name} ({n})");
+    }
+    tx.execute(
+        "INSERT INTO contests(name, end, prize, chan) VALUES(?, ?, ?, ?)",
+        params![unique, end, prize, source.chan],
+    )?;
+    let id = tx.last_insert_rowid();
+    for (table, columns) in RULES {
+        tx.execute(
+            &format!(
+                "INSERT INTO {table}(contest, {columns}) \
+                SELECT ?, {columns} FROM {table} WHERE contest = ?"
+            ),
+            params![id, source.id],
+        )?;
+    }
+    if let Some(template) = template {
+        tx.execute(
+            "INSERT INTO template_instances(contest, template) VALUES(?, ?)",
+            params![id, template],
+        )?;
+    }
+    Ok(Contest {
+        id,
+        name: unique,
+        prize: prize.to_string(),
+        end,
+        chan: source.chan,
+        started_at: None,
+        stopped: false,
+    })
+}
+
+/// Clones the `source` contest, with its rules, as a draft the owner can start: the draft
+/// lasts as long as the source. The action is added to the audit log.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `source` - The contest to clone
+/// * `actor` - The owner
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the copy fails: nothing is copied.
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub fn clone(ctx: &Context, source: &Contest, actor: i64) -> rusqlite::Result<Contest> {
+    let guard = ctx.data.read();
+    let map = guard.get::<DBKey>().expect("db");
+    let mut conn = map.get().unwrap();
+    let tx = conn.transaction()?;
+    let draft = clone_tx(&tx, source, actor, Utc::now())?;
+    tx.commit()?;
+    Ok(draft)
+}
+
+/// Same as `clone`, as part of the transaction `tx` of the caller, at the time `now`.
+///
+/// # Errors
+/// Returns the `rusqlite::Error` if the copy fails.
+fn clone_tx(
+    tx: &Transaction,
+    source: &Contest,
+    actor: i64,
+    now: DateTime<Utc>,
+) -> rusqlite::Result<Contest> {
+    let length = source
+        .started_at
+        .map_or(Duration::days(DRAFT_DAYS), |start| source.end - start);
+    let length = if length > Duration::zero() {
+        length
+    } else {
+        Duration::days(DRAFT_DAYS)
+    };
+    let name = format!("{} (copy)", source.name);
+    let draft = copy_tx(tx, source, &name, &source.prize, now + length, None)?;
+    audit::log_tx(
+        tx,
+        draft.id,
+        actor,
+        "clone contest",
+        Some(source.id),
+        Some(&source.name),
+    )?;
+    Ok(draft)
+}
+
+/// Creates and starts the instances of the templates that are due, and finishes the
+/// instances whose end date passed. Invoked periodically by the scheduler.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+///
+/// # Panics
+/// Panics if the connection to the DB fails.
+pub async fn run(ctx: &Context) {
+    let expired: Vec<i64> = {
+        let guard = ctx.data.read();
+        let map = guard.get::<DBKey>().expect("db");
+        let conn = map.get().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT c.id FROM template_instances AS t INNER JOIN contests AS c \
+                ON c.id = t.contest \
+                WHERE c.started_at IS NOT NULL AND NOT c.stopped AND c.end <= ?",
+            )
+            .unwrap();
+        let ids = stmt
+            .query_map(params![Utc::now()], |row| row.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        ids
+    };
+    for id in expired {
+        let c = contests::get(ctx, id);
+        let chan = c.as_ref().and_then(|c| channels::get(ctx, c.chan));
+        if let (Some(c), Some(chan)) = (c, chan) {
+            contests::stop(ctx, &chan, &c, chan.registered_by).await;
+        }
+    }
+
+    for template in due(ctx) {
+        let source = contests::get(ctx, template.contest);
+        let chan = source.as_ref().and_then(|c| channels::get(ctx, c.chan));
+        let (Some(source), Some(chan)) = (source, chan) else {
+            continue;
+        };
+        // The next run is decided before creating the instance: a failure doesn't repeat it
+        let now = Utc::now();
+        let next_run = Schedule::parse(&template.schedule)
+            .ok()
+            .and_then(|schedule| schedule.next(now));
+        let res = {
+            let guard = ctx.data.read();
+            let map = guard.get::<DBKey>().expect("db");
+            let conn = map.get().unwrap();
+            conn.execute(
+                "UPDATE contest_templates SET next_run = ?, runs = runs + 1, active = ? \
+                WHERE id = ?",
+                params![next_run.unwrap_or(now), next_run.is_some(), template.id],
+            )
+        };
+        if let Err(err) = res {
+            error!("[advance template] {err}");
+            continue;
+        }
+
+        let name = render(&template.name, template.runs + 1, now);
+        let end = now + Duration::seconds(template.duration);
+        match copy(ctx, &source, &name, &template.prize, end, Some(template.id)) {
+            Ok(instance) => {
+                contests::start(ctx, &chan, instance.id, chan.registered_by).await;
+            }
+            Err(err) => {
+                error!("[template instance] {err}");
+                let text = format!("The recurring contest {name} can't be created: {err}");
+                let res = ctx
+                    .api
+                    .send_message(SendMessage::new(chan.registered_by, &text))
+                    .await;
+                if let Err(err) = res {
+                    error!("[template send] {err}");
+                }
+            }
+        }
+    }
+}
+
+/// Shows to the owner the recurring contests that repeat the `contest`, with the buttons to
+/// add and remove them and to clone the contest as a draft.
+///
+/// # Arguments
+/// * `ctx` - Telexide context
+/// * `chat_id` - The chat of the owner
+/// * `chan` - The channel of the contest
+/// * `contest` - The contest
+pub async fn display(ctx: &Context, chat_id: i64, chan: &Channel, contest: &Contest) {
+    let templates = get_all(ctx, contest.id);
+    let mut text = format!(
+        "Recurring contests of {}\n\nEvery instance copies the rules of this contest \
+        (settings, milestones, points, channels, partners, invitee reward) and is created, \
+        started and finished automatically.\n\n",
+        contest.name
+    );
+    if templates.is_empty() {
+        text += "No recurring contests.\n";
+    }
+    let mut inline_keyboard = vec![];
+    for template in &templates {
+        let _ = writeln!(
+            text,
+            "{} - {} - {}, lasting {}h, {} instances so far. Next: {}",
+            template.name,
+            template.prize,
+            template.schedule,
+            template.duration / 3600,
+            template.runs,
+            template.next_run.format("%Y-%m-%d %H:%M UTC")
+        );
+        inline_keyboard.push(vec![callback_button(
+            &format!("\u{274c} Remove {}", template.name),
+            &format!("template_remove {} {} {}", chan.id, contest.id, template.id),
+        )]);
+    }
+    inline_keyboard.push(vec![
+        callback_button(
+            "\u{2795} Add recurrence",
+            &format!("template_add {} {}", chan.id, contest.id),
+        ),
+        callback_button(
+            "\u{1f4d1} Clone as draft",
+            &format!("template_clone {} {}", chan.id, contest.id),
+        ),
+    ]);
+    inline_keyboard.push(vec![callback_button(
+        "\u{1f519} Manage",
+        &format!("manage {}", chan.id),
+    )]);
+
+    let mut reply = SendMessage::new(chat_id, &escape_markdown(&text, None));
+    reply.set_parse_mode(&ParseMode::MarkdownV2);
+    reply.set_reply_markup(&ReplyMarkup::InlineKeyboardMarkup(InlineKeyboardMarkup {
+        inline_keyboard,
+    }));
+    let res = ctx.api.send_message(reply).await;
+    if let Err(err) = res {
+        error!("[templates send] {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::db;
+    use chrono::TimeZone;
+    use rusqlite::Connection;
+
+    /// Returns the values set in a parsed cron field.
+    fn values(set: &[bool]) -> Vec<usize> {
+        set.iter()
+            .enumerate()
+            .filter_map(|(value, set)| set.then_some(value))
+            .collect()
+    }
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn field_any() {
+        assert_eq!(
+            values(&field("*", 0, 59).unwrap()),
+            (0..=59).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            values(&field("*", 1, 12).unwrap()),
+            (1..=12).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn field_step() {
+        assert_eq!(values(&field("*/15", 0, 59).unwrap()), vec![0, 15, 30, 45]);
+        assert_eq!(values(&field("5/20", 0, 59).unwrap()), vec![5, 25, 45]);
+        assert_eq!(values(&field("10-20/5", 0, 59).unwrap()), vec![10, 15, 20]);
+    }
+
+    #[test]
+    fn field_range_and_list() {
+        assert_eq!(values(&field("1-5", 0, 6).unwrap()), vec![1, 2, 3, 4, 5]);
+        assert_eq!(values(&field("7", 0, 23).unwrap()), vec![7]);
+        assert_eq!(
+            values(&field("1,3,10-12", 1, 31).unwrap()),
+            vec![1, 3, 10, 11, 12]
+        );
+    }
+
+    #[test]
+    fn field_malformed() {
+        assert!(field("*/0", 0, 59).is_err());
+        assert!(field("60", 0, 59).is_err());
+        assert!(field("0", 1, 31).is_err());
+        assert!(field("5-1", 0, 59).is_err());
+        assert!(field("a", 0, 59).is_err());
+        assert!(field("1,", 0, 59).is_err());
+    }
+
+    #[test]
+    fn parse_schedule() {
+        assert!(Schedule::parse("weekly").is_ok());
+        assert!(Schedule::parse("Monthly").is_ok());
+        assert!(Schedule::parse("0 0 * *").is_err());
+        assert!(Schedule::parse("0 0 * * * *").is_err());
+        assert!(Schedule::parse("0 24 * * *").is_err());
+        // Both 0 and 7 are Sunday
+        let sunday = Schedule::parse("0 0 * * 7").unwrap();
+        assert_eq!(values(&sunday.weekdays), vec![0]);
+        assert!(!sunday.any_weekday);
+    }
+
+    #[test]
+    fn next_weekly_and_monthly() {
+        let weekly = Schedule::parse("weekly").unwrap();
+        // Wednesday -> next Monday
+        assert_eq!(
+            weekly.next(at(2026, 10, 14, 12, 0)),
+            Some(at(2026, 10, 19, 0, 0))
+        );
+        let monthly = Schedule::parse("monthly").unwrap();
+        assert_eq!(
+            monthly.next(at(2026, 12, 15, 8, 30)),
+            Some(at(2027, 1, 1, 0, 0))
+        );
+    }
+
+    #[test]
+    fn next_is_strictly_after() {
+        let daily = Schedule::parse("30 9 * * *").unwrap();
+        assert_eq!(
+            daily.next(at(2026, 10, 14, 9, 10)),
+            Some(at(2026, 10, 14, 9, 30))
+        );
+        assert_eq!(
+            daily.next(at(2026, 10, 14, 9, 30)),
+            Some(at(2026, 10, 15, 9, 30))
+        );
+    }
+
+    #[test]
+    fn next_day_of_month_or_day_of_week() {
+        // Only the day of the month: November 13
+        let dom = Schedule::parse("0 0 13 * *").unwrap();
+        assert_eq!(
+            dom.next(at(2026, 10, 14, 12, 0)),
+            Some(at(2026, 11, 13, 0, 0))
+        );
+        // Only the day of the week: Friday, October 16
+        let dow = Schedule::parse("0 0 * * 5").unwrap();
+        assert_eq!(
+            dow.next(at(2026, 10, 14, 12, 0)),
+            Some(at(2026, 10, 16, 0, 0))
+        );
+        // Both restricted: either of them is enough, like cron
+        let both = Schedule::parse("0 0 13 * 5").unwrap();
+        assert_eq!(
+            both.next(at(2026, 10, 14, 12, 0)),
+            Some(at(2026, 10, 16, 0, 0))
+        );
+        let both = Schedule::parse("0 0 19 * 5").unwrap();
+        assert_eq!(
+            both.next(at(2026, 10, 17, 12, 0)),
+            Some(at(2026, 10, 19, 0, 0))
+        );
+    }
+
+    #[test]
+    fn next_never() {
+        let never = Schedule::parse("0 0 30 2 *").unwrap();
+        assert_eq!(never.next(at(2026, 10, 14, 12, 0)), None);
+    }
+
+    #[test]
+    fn parse_duration() {
+        assert_eq!(duration("7d"), Ok(7 * 86400));
+        assert_eq!(duration(" 12H "), Ok(12 * 3600));
+        assert!(duration("0d").is_err());
+        assert!(duration("-1h").is_err());
+        assert!(duration("7w").is_err());
+        assert!(duration("xd").is_err());
+    }
+
+    #[test]
+    fn render_name() {
+        assert_eq!(
+            render("Contest #{n} - {date}", 3, at(2026, 10, 19, 0, 0)),
+            "Contest #3 - 2026-10-19"
+        );
+        assert_eq!(
+            render("Week {week}, {month}", 1, at(2026, 10, 19, 0, 0)),
+            "Week 43, October 2026"
+        );
+        assert_eq!(render("Plain", 1, at(2026, 10, 19, 0, 0)), "Plain");
+    }
+
+    #[test]
+    fn delete_clone() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&conn).unwrap();
+        let source = Contest {
+            id: 5,
+            name: "contest".to_string(),
+            prize: "prize".to_string(),
+            end: at(2026, 1, 31, 12, 0),
+            chan: -10,
+            started_at: Some(at(2026, 1, 1, 12, 0)),
+            stopped: true,
+        };
+        conn.execute_batch(
+            "INSERT INTO users(id, first_name) VALUES(1, 'owner');
+            INSERT INTO channels(id, registered_by, link, name) VALUES(-10, 1, 'link', 'chan');",
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO contests(id, name, prize, end, chan, started_at, stopped) \
+            VALUES(?, ?, ?, ?, ?, ?, ?)",
+            params![
+                source.id,
+                source.name,
+                source.prize,
+                source.end,
+                source.chan,
+                source.started_at,
+                source.stopped
+            ],
+        )
+        .unwrap();
+        conn.execute_batch(
+            "INSERT INTO contest_settings(contest, key, value) VALUES(5, 'mode', 'raffle');
+            INSERT INTO milestones(contest, threshold, reward) VALUES(5, 3, 'sticker');
+            INSERT INTO sponsors(contest, chat, name, link) VALUES(5, -20, 'partner', 'link');",
+        )
+        .unwrap();
+        let count = |conn: &Connection, table: &str, contest: i64| {
+            conn.query_row(
+                &format!("SELECT COUNT(*) FROM {table} WHERE contest = ?"),
+                params![contest],
+                |row| row.get::<_, i64>(0),
+            )
+            .unwrap()
+        };
+
+        let tx = conn.transaction().unwrap();
+        let draft = clone_tx(&tx, &source, 1, at(2026, 2, 1, 12, 0)).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(draft.name, "contest (copy)");
+        assert_eq!(draft.end, at(2026, 3, 3, 12, 0));
+        for table in ["contest_settings", "milestones", "sponsors", "audit_log"] {
+            assert_eq!(count(&conn, table, draft.id), 1, "{table}");
+        }
+
+        let tx = conn.transaction().unwrap();
+        contests::delete_tx(&tx, draft.id, source.chan).unwrap();
+        tx.commit().unwrap();
+        for table in ["contest_settings", "milestones", "sponsors", "audit_log"] {
+            assert_eq!(count(&conn, table, draft.id), 0, "{table}");
+        }
+        assert!(contests::delete_tx(&conn.transaction().unwrap(), draft.id, source.chan).is_err());
+        // The rules of the source are untouched
+        assert_eq!(count(&conn, "contest_settings", source.id), 1);
+    }
+}
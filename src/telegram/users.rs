@@ -86,3 +86,24 @@ pub fn owners(ctx: &Context) -> Vec<User> {
         .collect();
     users
 }
+
+/// Returns the name to show for the `user`: first name, last name (if any) and username
+/// (if any).
+///
+/// # Arguments
+/// * `user` - The user to show
+#[must_use]
+pub fn display_name(user: &User) -> String {
+    format!(
+        "{}{}{}",
+        user.first_name,
+        match &user.last_name {
+            Some(last_name) => format!(" {last_name}"),
+            None => String::new(),
+        },
+        match &user.username {
+            Some(username) => format!(" (@{username})"),
+            None => String::new(),
+        },
+    )
+}